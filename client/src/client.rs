@@ -1 +1,21 @@
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub mod batch;
+pub mod connection;
+pub mod firmware;
+pub mod fragment;
+pub mod keepalive;
+#[cfg(feature = "embedded-nal")]
+pub mod nal;
+#[cfg(feature = "embedded-nal-async")]
+pub mod nal_async;
+pub mod offline;
+mod random;
+pub mod reconnect;
+pub mod rtt;
 pub mod session;
+#[cfg(test)]
+mod test_server;
+pub mod time;
+pub mod upload;
+pub mod window;
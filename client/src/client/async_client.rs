@@ -0,0 +1,427 @@
+extern crate std;
+
+use std::{boxed::Box, collections::HashMap, collections::VecDeque, io, vec::Vec};
+
+use shared_lib::{
+    close::CloseReason,
+    command::{Payload, PACKET_SIZE},
+    serialize,
+};
+use thiserror::Error;
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use super::{
+    connection::{Connection, Event, Handle},
+    session::{Error, OutputVec, Session},
+    time::Instant,
+};
+
+/// Commands waiting for the connection task, senders wait when it is full.
+const COMMAND_QUEUE_SIZE: usize = 32;
+/// Server messages kept until the application takes them, newer ones are dropped.
+const MESSAGE_QUEUE_SIZE: usize = 32;
+
+type Build = Box<dyn FnOnce(&mut Session) -> Result<OutputVec, Error> + Send>;
+type Reply = oneshot::Sender<Result<Option<Message>, ClientError>>;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("Session error: {0}")]
+    Session(#[from] Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Message is not acknowledged after all retries")]
+    Lost,
+    #[error("Handshake attempts are exhausted")]
+    HandshakeFailed,
+    #[error("Connection is closed")]
+    Closed,
+}
+
+/// Payload received from the server, owned to pass it between tasks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message(Vec<u8>);
+
+impl Message {
+    pub fn payload(&self) -> Option<Payload<'_>> {
+        serialize::parse_payload(&self.0).ok()
+    }
+}
+
+/// Messages sent by the server on its own, in the order they arrived.
+pub struct Messages {
+    receiver: mpsc::Receiver<Message>,
+}
+
+impl Messages {
+    /// Next message, `None` once the connection task is finished.
+    pub async fn next(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+
+    /// Poll the next message, e.g. to implement a `Stream`.
+    pub fn poll_next(
+        &mut self,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+enum Command {
+    Send {
+        build: Build,
+        reply: Reply,
+    },
+    Close {
+        reason: CloseReason,
+        reply: oneshot::Sender<Result<(), ClientError>>,
+    },
+}
+
+/// Async client of a [Connection] over a tokio UDP socket.
+///
+/// A task spawned on the runtime owns the socket and the connection: it retransmits the
+/// messages with tokio timers and reconnects when the session is lost. The client is a
+/// cheap handle to the task, clones of it send messages concurrently.
+#[derive(Clone)]
+pub struct Client {
+    commands: mpsc::Sender<Command>,
+}
+
+impl Client {
+    /// Open the session over a socket connected to the server and spawn the connection task.
+    /// The task is finished when every client is dropped or the session is closed.
+    pub async fn connect<const N: usize>(
+        socket: UdpSocket,
+        connection: Connection<N>,
+    ) -> Result<(Client, Messages), ClientError> {
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (messages, messages_receiver) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+        let (connected, connected_receiver) = oneshot::channel();
+
+        let task = Task {
+            socket,
+            connection,
+            started: time::Instant::now(),
+            messages,
+            requests: HashMap::new(),
+            waiting: VecDeque::new(),
+            closing: None,
+            connected: Some(connected),
+        };
+        tokio::spawn(task.run(receiver));
+
+        connected_receiver
+            .await
+            .map_err(|_| ClientError::Closed)??;
+        Ok((
+            Client { commands },
+            Messages {
+                receiver: messages_receiver,
+            },
+        ))
+    }
+
+    /// Send a message built by the session and wait for the acknowledgement.
+    /// Returns the message if the server answered with data.
+    ///
+    /// Messages are sent when the windows allow it, the others wait in the command queue,
+    /// the call waits for a free slot when the queue is full.
+    pub async fn send(
+        &self,
+        build: impl FnOnce(&mut Session) -> Result<OutputVec, Error> + Send + 'static,
+    ) -> Result<Option<Message>, ClientError> {
+        let (reply, answer) = oneshot::channel();
+        self.command(Command::Send {
+            build: Box::new(build),
+            reply,
+        })
+        .await?;
+        answer.await.map_err(|_| ClientError::Closed)?
+    }
+
+    /// Close the session and wait until the server acknowledges it.
+    pub async fn close(&self, reason: CloseReason) -> Result<(), ClientError> {
+        let (reply, answer) = oneshot::channel();
+        self.command(Command::Close { reason, reply }).await?;
+        answer.await.map_err(|_| ClientError::Closed)?
+    }
+
+    async fn command(&self, command: Command) -> Result<(), ClientError> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| ClientError::Closed)
+    }
+}
+
+struct Task<const N: usize> {
+    socket: UdpSocket,
+    connection: Connection<N>,
+    started: time::Instant,
+    messages: mpsc::Sender<Message>,
+    requests: HashMap<Handle, Reply>,
+    // sends which do not fit into the windows yet, no more commands are taken while it is not empty
+    waiting: VecDeque<(Build, Reply)>,
+    closing: Option<(Handle, oneshot::Sender<Result<(), ClientError>>)>,
+    connected: Option<oneshot::Sender<Result<(), ClientError>>>,
+}
+
+impl<const N: usize> Task<N> {
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        if let Err(error) = self.connection.connect(self.now()) {
+            self.finish(error.into());
+            return;
+        }
+
+        let mut buf = [0u8; PACKET_SIZE];
+        loop {
+            if let Err(error) = self.flush().await {
+                self.finish(error.into());
+                return;
+            }
+            if !self.dispatch_events() {
+                return;
+            }
+            self.send_waiting();
+            if let Err(error) = self.flush().await {
+                self.finish(error.into());
+                return;
+            }
+
+            let deadline = self.connection.poll_timeout().map(|deadline| {
+                self.started + core::time::Duration::from_millis(deadline.as_millis())
+            });
+            // commands wait in the bounded queue while the windows are full, so the senders
+            // feel the backpressure and the waiting sends do not grow without a limit
+            let accepting = self.waiting.is_empty() || self.connection.can_send();
+            tokio::select! {
+                command = commands.recv(), if accepting => match command {
+                    Some(command) => self.handle_command(command),
+                    None => {
+                        log::info!("Client is dropped, forgetting the session");
+                        self.connection.abort();
+                        return;
+                    }
+                },
+                received = self.socket.recv(&mut buf) => match received {
+                    Ok(size) => {
+                        let now = self.now();
+                        self.connection.handle_datagram(&buf[..size], now);
+                    }
+                    // the server might not be reachable yet, the timers retry
+                    Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {}
+                    Err(error) => {
+                        self.finish(error.into());
+                        return;
+                    }
+                },
+                _ = time::sleep_until(deadline.unwrap_or(self.started)), if deadline.is_some() => {}
+            }
+            let now = self.now();
+            self.connection.handle_timeout(now);
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Send { build, reply } => self.waiting.push_back((build, reply)),
+            Command::Close { reason, reply } => {
+                if self.closing.is_some() {
+                    let _ = reply.send(Err(Error::IncorrectState.into()));
+                    return;
+                }
+                match self.connection.close(reason, self.now()) {
+                    Ok(handle) => self.closing = Some((handle, reply)),
+                    Err(error) => {
+                        let _ = reply.send(Err(error.into()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send the waiting messages while the windows allow it.
+    fn send_waiting(&mut self) {
+        while self.connection.can_send() {
+            let Some((build, reply)) = self.waiting.pop_front() else {
+                return;
+            };
+            let now = self.now();
+            match self.connection.send(now, build) {
+                Ok(handle) => {
+                    self.requests.insert(handle, reply);
+                }
+                Err(error) => {
+                    let _ = reply.send(Err(error.into()));
+                }
+            }
+        }
+    }
+
+    /// Answer the requests with the events, `false` when the task is finished.
+    fn dispatch_events(&mut self) -> bool {
+        while let Some(event) = self.connection.poll_event() {
+            match event {
+                Event::Connected => {
+                    if let Some(connected) = self.connected.take() {
+                        let _ = connected.send(Ok(()));
+                    }
+                }
+                Event::Reconnecting => log::warn!("Session is lost, reconnecting"),
+                Event::Delivered(handle) => self.answer(handle, Ok(None)),
+                Event::Response(handle) => {
+                    let message = Message(self.connection.raw_payload().to_vec());
+                    self.answer(handle, Ok(Some(message)));
+                }
+                Event::Rejected(handle, code) => {
+                    self.answer(handle, Err(Error::Server(code).into()))
+                }
+                Event::Lost(handle) => self.answer(handle, Err(ClientError::Lost)),
+                Event::Message => {
+                    let message = Message(self.connection.raw_payload().to_vec());
+                    if let Err(mpsc::error::TrySendError::Full(_)) = self.messages.try_send(message)
+                    {
+                        log::warn!("Server message is not taken, dropped");
+                    }
+                }
+                Event::Closed(reason) => {
+                    log::info!("Session closed: {reason:?}");
+                    if let Some((_, reply)) = self.closing.take() {
+                        let _ = reply.send(Ok(()));
+                    }
+                    self.finish(ClientError::Closed);
+                    return false;
+                }
+                Event::Failed => {
+                    self.finish(ClientError::HandshakeFailed);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn answer(&mut self, handle: Handle, result: Result<Option<Message>, ClientError>) {
+        if let Some(reply) = self.requests.remove(&handle) {
+            let _ = reply.send(result);
+            return;
+        }
+        if matches!(&self.closing, Some((closing, _)) if *closing == handle) {
+            if let (Some((_, reply)), Err(error)) = (self.closing.take(), result) {
+                let _ = reply.send(Err(error));
+            }
+        }
+    }
+
+    /// Fail everything which is not answered yet, the task is finished.
+    fn finish(&mut self, error: ClientError) {
+        log::info!("Connection is finished: {error}");
+        if let Some(connected) = self.connected.take() {
+            let _ = connected.send(Err(error));
+        }
+        for (_, reply) in self.requests.drain() {
+            let _ = reply.send(Err(ClientError::Closed));
+        }
+        for (_, reply) in self.waiting.drain(..) {
+            let _ = reply.send(Err(ClientError::Closed));
+        }
+        if let Some((_, reply)) = self.closing.take() {
+            let _ = reply.send(Err(ClientError::Closed));
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.connection.poll_transmit() {
+            match self.socket.send(&packet).await {
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                    log::warn!("Server is not reachable");
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_millis(self.started.elapsed().as_millis() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{
+        reconnect::Reconnect, reconnect::ReconnectConfig, rtt::RttEstimator,
+        test_server::TestServer,
+    };
+
+    #[tokio::test]
+    async fn concurrent_sends() {
+        // the server answers the handshake and three pings
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote = server.local_addr().unwrap();
+        let answering = tokio::spawn(async move {
+            let mut test_server = TestServer::new(1);
+            let mut buf = [0u8; PACKET_SIZE];
+            for _ in 0..4 {
+                let (size, client) = server.recv_from(&mut buf).await.unwrap();
+                let answer = test_server.answer(&buf[..size]).unwrap();
+                server.send_to(&answer, client).await.unwrap();
+            }
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(remote).await.unwrap();
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let connection = Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let (client, _messages) = Client::connect(socket, connection).await.unwrap();
+
+        let sends: Vec<_> = (1..=3)
+            .map(|id| {
+                let client = client.clone();
+                tokio::spawn(async move { client.send(move |session| session.send_ping(id)).await })
+            })
+            .collect();
+        for send in sends {
+            assert!(matches!(send.await.unwrap(), Ok(None)));
+        }
+        answering.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_taking_commands_while_window_is_full() {
+        // the server answers the handshake only, the first ping fills the window
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let remote = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut test_server = TestServer::new(1);
+            let mut buf = [0u8; PACKET_SIZE];
+            let (size, client) = server.recv_from(&mut buf).await.unwrap();
+            let answer = test_server.answer(&buf[..size]).unwrap();
+            server.send_to(&answer, client).await.unwrap();
+            while server.recv_from(&mut buf).await.is_ok() {}
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(remote).await.unwrap();
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let connection = Connection::<1>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let (client, _messages) = Client::connect(socket, connection).await.unwrap();
+
+        // one ping is in flight and one waits, the others stay in the command queue
+        let count = COMMAND_QUEUE_SIZE + 4;
+        for id in 0..count as u32 {
+            let client = client.clone();
+            tokio::spawn(async move { client.send(move |session| session.send_ping(id)).await });
+        }
+        time::sleep(core::time::Duration::from_millis(200)).await;
+        assert_eq!(client.commands.capacity(), 0);
+    }
+}
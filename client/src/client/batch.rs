@@ -0,0 +1,147 @@
+use core::time::Duration;
+
+use heapless::Vec;
+use shared_lib::{command::PACKET_SIZE, packet::PacketView, telemetry::Reading};
+
+use super::{
+    session::{OutputVec, Result, Session, TAG_SIZE},
+    time::Instant,
+};
+
+/// Telemetry envelope overhead inside the plaintext: payload kind and schema version.
+const TELEMETRY_OVERHEAD: usize = 2;
+
+/// The biggest batch plaintext which can be encrypted in place in a [PACKET_SIZE] buffer.
+pub const MAX_BATCH_SIZE: usize = (PACKET_SIZE - PacketView::PAYLOAD_OFFSET - TAG_SIZE) / 2;
+
+/// Collects readings into a single encrypted packet.
+///
+/// A batch should be flushed when it is full by count (`N` readings), by size
+/// (the next reading might not fit into the packet) or when the oldest reading
+/// waited longer than the configured delay.
+pub struct Batcher<const N: usize> {
+    readings: Vec<Reading, N>,
+    encoded_size: usize,
+    max_size: usize,
+    max_delay: Duration,
+    first_at: Option<Instant>,
+}
+
+impl<const N: usize> Batcher<N> {
+    /// Create a batcher which keeps readings no longer than `max_delay`.
+    pub fn new(max_delay: Duration) -> Self {
+        Batcher {
+            readings: Vec::new(),
+            encoded_size: TELEMETRY_OVERHEAD,
+            max_size: MAX_BATCH_SIZE,
+            max_delay,
+            first_at: None,
+        }
+    }
+
+    /// Limit the batch plaintext size, e.g. for links with a smaller MTU.
+    /// The limit is capped by [MAX_BATCH_SIZE].
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size.min(MAX_BATCH_SIZE);
+        self
+    }
+
+    /// Add a reading to the batch.
+    /// Returns the reading back if the batch is full, it has to be flushed first.
+    pub fn push(&mut self, reading: Reading, now: Instant) -> core::result::Result<(), Reading> {
+        if self.encoded_size + reading.encoded_size() > self.max_size {
+            return Err(reading);
+        }
+
+        self.readings.push(reading)?;
+        self.encoded_size += reading.encoded_size();
+        self.first_at.get_or_insert(now);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readings.is_empty()
+    }
+
+    pub fn readings(&self) -> &[Reading] {
+        &self.readings
+    }
+
+    /// Time when the batch has to be flushed because of the delay.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.first_at.map(|first_at| first_at + self.max_delay)
+    }
+
+    /// Check if the batch is full or the oldest reading waited long enough.
+    pub fn should_flush(&self, now: Instant) -> bool {
+        self.readings.is_full()
+            || self.encoded_size + Reading::MAX_SIZE > self.max_size
+            || self.deadline().is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Encrypt all collected readings into one packet and clear the batch.
+    /// Returns `None` if there is nothing to send.
+    pub fn flush(&mut self, session: &mut Session) -> Result<Option<OutputVec>> {
+        if self.readings.is_empty() {
+            return Ok(None);
+        }
+
+        let packet = session.send_readings(&self.readings)?;
+        self.clear();
+        Ok(Some(packet))
+    }
+
+    /// Same as [Batcher::flush], but encrypts in place in `buf`. Returns the packet size.
+    pub fn flush_in_place(
+        &mut self,
+        session: &mut Session,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>> {
+        if self.readings.is_empty() {
+            return Ok(None);
+        }
+
+        let size = session.encode_readings(&self.readings, buf)?;
+        self.clear();
+        Ok(Some(size))
+    }
+
+    fn clear(&mut self) {
+        self.readings.clear();
+        self.encoded_size = TELEMETRY_OVERHEAD;
+        self.first_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_lib::telemetry::Information;
+
+    #[test]
+    fn flush_on_count_size_and_deadline() {
+        let reading = Reading::new(Information::Humidity(40.0));
+
+        let mut by_count = Batcher::<2>::new(Duration::from_secs(10));
+        by_count.push(reading, Instant::from_millis(0)).unwrap();
+        assert!(!by_count.should_flush(Instant::from_millis(1)));
+        by_count.push(reading, Instant::from_millis(1)).unwrap();
+        assert!(by_count.should_flush(Instant::from_millis(1)));
+        assert!(by_count.push(reading, Instant::from_millis(2)).is_err());
+
+        let mut by_size = Batcher::<16>::new(Duration::from_secs(10)).with_max_size(25);
+        by_size.push(reading, Instant::from_millis(0)).unwrap();
+        assert!(by_size.should_flush(Instant::from_millis(0)));
+        by_size.push(reading, Instant::from_millis(0)).unwrap_err();
+
+        let mut by_deadline = Batcher::<16>::new(Duration::from_millis(100));
+        assert_eq!(by_deadline.deadline(), None);
+        by_deadline.push(reading, Instant::from_millis(50)).unwrap();
+        assert!(!by_deadline.should_flush(Instant::from_millis(149)));
+        assert!(by_deadline.should_flush(Instant::from_millis(150)));
+    }
+}
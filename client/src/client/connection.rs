@@ -0,0 +1,894 @@
+use heapless::{Deque, Vec};
+use shared_lib::{
+    close::CloseReason,
+    command::{Payload, COMMAND_SIZE, PACKET_SIZE},
+    error::ErrorCode,
+    network::MessageType,
+    packet::PacketView,
+    serialize,
+};
+
+use super::{
+    reconnect::Reconnect,
+    rtt::RttEstimator,
+    session::{Error, OutputVec, Result, Session},
+    time::Instant,
+    window::{Delivery, SendWindow, Stats, Timeout},
+};
+
+/// Events kept until the driver polls them, a few more than one acknowledgement might report.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+/// Handle of a message sent with [Connection::send], it is kept when the message
+/// is sent again in a new session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle(u32);
+
+/// Something the application should know about, see [Connection::poll_event].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The handshake is complete, messages might be sent.
+    Connected,
+    /// The session is lost, a new one is being opened.
+    /// The messages which are not acknowledged are sent again when it is open.
+    Reconnecting,
+    /// The server acknowledged the message.
+    Delivered(Handle),
+    /// The server acknowledged the message and answered with data, see [Connection::payload].
+    Response(Handle),
+    /// The server answered the message with an error.
+    Rejected(Handle, ErrorCode),
+    /// The message was not acknowledged after all retries.
+    Lost(Handle),
+    /// The server sent a message on its own, see [Connection::payload].
+    Message,
+    /// The session was closed with [Connection::close].
+    Closed(CloseReason),
+    /// The handshake attempts are exhausted, [Connection::connect] starts them again.
+    Failed,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum State {
+    Idle,
+    Handshaking {
+        packet: OutputVec,
+        sent_at: Instant,
+        deadline: Instant,
+        retries: u8,
+    },
+    Backoff {
+        until: Instant,
+    },
+    Open,
+}
+
+/// Message which is not acknowledged yet, its plaintext is encrypted again in a new session.
+struct Unacked {
+    handle: Handle,
+    sequence: u16,
+    plaintext: Vec<u8, COMMAND_SIZE>,
+}
+
+/// Client protocol as a state machine without any I/O (sans-IO), up to `N` messages in flight.
+///
+/// The connection opens the session, retransmits messages, reconnects when the session is
+/// lost and replays the messages which are not acknowledged, but it never touches a socket
+/// or a clock. A driver moves the datagrams and the time in and out of it:
+/// - [Connection::handle_datagram] for every received datagram;
+/// - [Connection::poll_transmit] until it returns `None`, sending every packet;
+/// - [Connection::poll_timeout] for the time to wake up at, then [Connection::handle_timeout];
+/// - [Connection::poll_event] until it returns `None`, after every call above.
+///
+/// Packets waiting for [Connection::poll_transmit] are limited to `N`, more packets are
+/// dropped like lost datagrams and retransmitted later.
+pub struct Connection<const N: usize> {
+    session: Session,
+    window: SendWindow<N>,
+    reconnect: Reconnect,
+    max_retries: u8,
+    state: State,
+    unacked: Vec<Unacked, N>,
+    next_handle: u32,
+    transmits: Deque<OutputVec, N>,
+    events: Deque<Event, EVENT_QUEUE_SIZE>,
+    // payload of the last Response or Message event
+    payload: Vec<u8, COMMAND_SIZE>,
+}
+
+impl<const N: usize> Connection<N> {
+    /// Create a connection for the session, `max_retries` applies to the handshake and every message.
+    pub fn new(session: Session, rtt: RttEstimator, reconnect: Reconnect, max_retries: u8) -> Self {
+        Connection {
+            session,
+            window: SendWindow::new(rtt, max_retries),
+            reconnect,
+            max_retries,
+            state: State::Idle,
+            unacked: Vec::new(),
+            next_handle: 0,
+            transmits: Deque::new(),
+            events: Deque::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// Session to configure it, e.g. the keepalive interval before [Connection::connect].
+    pub fn session(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    /// Check if the session is open and messages might be sent.
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, State::Open)
+    }
+
+    /// Check if a new message might be sent now, see [SendWindow::can_send].
+    pub fn can_send(&self) -> bool {
+        self.is_open() && self.window.can_send() && !self.unacked.is_full()
+    }
+
+    /// Check if messages are waiting for an acknowledgement.
+    pub fn has_unacked(&self) -> bool {
+        !self.unacked.is_empty()
+    }
+
+    /// Sender statistics with the current RTT estimation.
+    pub fn stats(&self) -> Stats {
+        self.window.stats()
+    }
+
+    /// Start the handshake, [Event::Connected] reports the open session.
+    pub fn connect(&mut self, now: Instant) -> Result<()> {
+        if self.is_open() {
+            return Err(Error::IncorrectState);
+        }
+        self.start_handshake(now)
+    }
+
+    /// Send a message built by the session, e.g. with [Session::send_payload].
+    /// The message is retransmitted until acknowledged and reported with its handle.
+    pub fn send(
+        &mut self,
+        now: Instant,
+        build: impl FnOnce(&mut Session) -> Result<OutputVec>,
+    ) -> Result<Handle> {
+        if !self.is_open() {
+            return Err(Error::IncorrectState);
+        }
+        if !self.can_send() {
+            return Err(Error::WindowFull);
+        }
+
+        let packet = self.build(now, build)?;
+        let sequence = self.session.sequence_id();
+        let plaintext =
+            Vec::from_slice(self.session.pending_plaintext()).map_err(|_| Error::IncorrectState)?;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let handle = Handle(self.next_handle);
+        let _ = self.unacked.push(Unacked {
+            handle,
+            sequence,
+            plaintext,
+        });
+
+        self.transmit(&packet);
+        let _ = self.window.push(sequence, packet, now);
+        Ok(handle)
+    }
+
+    /// Send a message which is not acknowledged, e.g. built with [crate::Qos::AtMostOnce].
+    pub fn send_unreliable(
+        &mut self,
+        now: Instant,
+        build: impl FnOnce(&mut Session) -> Result<OutputVec>,
+    ) -> Result<()> {
+        if !self.is_open() {
+            return Err(Error::IncorrectState);
+        }
+        let packet = self.build(now, build)?;
+        self.transmit(&packet);
+        Ok(())
+    }
+
+    /// Close the session, [Event::Closed] reports the close acknowledged by the server.
+    pub fn close(&mut self, reason: CloseReason, now: Instant) -> Result<Handle> {
+        self.send(now, |session| session.send_close(reason))
+    }
+
+    /// Forget the session without telling the server, e.g. when the close is not acknowledged.
+    pub fn abort(&mut self) {
+        self.session.close();
+        self.window.clear();
+        self.unacked.clear();
+        self.state = State::Idle;
+    }
+
+    /// Next packet to send, `None` if there is nothing to send.
+    pub fn poll_transmit(&mut self) -> Option<OutputVec> {
+        self.transmits.pop_front()
+    }
+
+    /// Time when [Connection::handle_timeout] should be called, `None` if no timer is running.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match self.state {
+            State::Idle => None,
+            State::Handshaking { deadline, .. } => Some(deadline),
+            State::Backoff { until } => Some(until),
+            State::Open => self.window.deadline(),
+        }
+    }
+
+    /// Next event, `None` if nothing happened.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Payload of the last [Event::Response] or [Event::Message].
+    ///
+    /// Only one payload is kept: if another one arrives before the event is polled,
+    /// the older [Event::Response] becomes [Event::Delivered] and the older
+    /// [Event::Message] is dropped. Poll the events after every received datagram.
+    pub fn payload(&self) -> Option<Payload<'_>> {
+        if self.payload.is_empty() {
+            return None;
+        }
+        serialize::parse_payload(&self.payload).ok()
+    }
+
+    /// Serialized payload of the last [Event::Response] or [Event::Message].
+    #[cfg(feature = "tokio")]
+    pub(crate) fn raw_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Handle expired timers: retransmit the handshake or messages, report lost messages
+    /// and start the handshake after the reconnect backoff.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        match self.state {
+            State::Idle => {}
+            State::Handshaking {
+                deadline, retries, ..
+            } if deadline <= now => {
+                if retries >= self.max_retries {
+                    log::warn!("Handshake is not answered");
+                    self.handshake_failed(now);
+                    return;
+                }
+                let retries = retries + 1;
+                let timeout = self.window.rtt_mut().timeout(retries);
+                if let State::Handshaking {
+                    packet,
+                    deadline,
+                    retries: state_retries,
+                    ..
+                } = &mut self.state
+                {
+                    *deadline = now + timeout;
+                    *state_retries = retries;
+                    let _ = self.transmits.push_back(packet.clone());
+                }
+            }
+            State::Handshaking { .. } => {}
+            State::Backoff { until } if until <= now => {
+                if self.start_handshake(now).is_err() {
+                    self.handshake_failed(now);
+                }
+            }
+            State::Backoff { .. } => {}
+            State::Open => {
+                while let Some(timeout) = self.window.poll_timeout(now) {
+                    match timeout {
+                        Timeout::Retransmit(packet) => {
+                            if let Ok(packet) = OutputVec::from_slice(packet) {
+                                let _ = self.transmits.push_back(packet);
+                            }
+                        }
+                        Timeout::Lost(sequence) => {
+                            if self.reconnect.on_lost() {
+                                // the message is kept, it is sent again in the new session
+                                self.session_lost(now);
+                                return;
+                            }
+                            if let Some(handle) = self.remove_unacked(sequence) {
+                                self.push_event(Event::Lost(handle));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a datagram received from the server.
+    /// Datagrams which do not belong to the session are ignored.
+    pub fn handle_datagram(&mut self, datagram: &[u8], now: Instant) {
+        let mut buf = [0u8; PACKET_SIZE];
+        let Some(buf) = buf.get_mut(..datagram.len()) else {
+            log::warn!("Datagram is too big, ignored");
+            return;
+        };
+        buf.copy_from_slice(datagram);
+        let Ok(packet) = PacketView::parse(buf) else {
+            log::warn!("Failed to parse datagram, ignored");
+            return;
+        };
+        if !self.session.accepts(packet.header()) {
+            log::warn!("Received message of another session, ignored");
+            return;
+        }
+
+        match self.state {
+            State::Handshaking {
+                sent_at, retries, ..
+            } => match self.session.receive_handshake(&packet) {
+                Ok(()) => {
+                    if retries == 0 {
+                        self.window
+                            .rtt_mut()
+                            .on_sample(now.saturating_duration_since(sent_at));
+                    }
+                    self.connected(now);
+                }
+                Err(Error::Server(code)) => {
+                    log::warn!("Handshake is rejected: {code}");
+                    self.handshake_failed(now);
+                }
+                Err(error) => log::warn!("Failed to process handshake response: {error}"),
+            },
+            State::Open => self.handle_packet(packet, now),
+            State::Idle | State::Backoff { .. } => {}
+        }
+    }
+
+    fn handle_packet(&mut self, packet: PacketView<'_>, now: Instant) {
+        match packet.header().message_type {
+            MessageType::Timeout => {
+                if self.session.receive_timeout(packet.header()).is_ok() {
+                    self.session_lost(now);
+                }
+                return;
+            }
+            MessageType::Error => {
+                // the error is not authenticated, it only answers a message in flight,
+                // the server answers forged messages with DecryptFailed as well
+                let code = ErrorCode::parse(packet.payload());
+                let answered = packet.header().sequence;
+                if code == ErrorCode::DecryptFailed || !self.window.contains(answered) {
+                    return;
+                }
+                if Reconnect::is_session_error(code) {
+                    self.session_lost(now);
+                } else if self.window.remove(answered) {
+                    if let Some(handle) = self.remove_unacked(answered) {
+                        self.push_event(Event::Rejected(handle, code));
+                    }
+                }
+                return;
+            }
+            MessageType::EncryptedMessage => {
+                let (session_id, sequence) = (packet.header().session_id, packet.header().sequence);
+                match self.session.receive_message(packet) {
+                    Ok(Payload::Close(reason)) => {
+                        log::warn!("Session closed by the server: {reason:?}");
+                        // the server sends the close again until it is acknowledged
+                        if let Ok(ack) = self.session.acknowledge_close(session_id, sequence) {
+                            self.transmit(&ack);
+                        }
+                        self.session_lost(now);
+                    }
+                    Ok(payload) => {
+                        if self.set_payload(&payload) {
+                            self.push_event(Event::Message);
+                        }
+                    }
+                    Err(error) => log::warn!("Failed to process server message: {error}"),
+                }
+                return;
+            }
+            MessageType::Ack => {}
+            _ => return,
+        }
+
+        // only authenticated ack fields remove messages from the window
+        let header = packet.header().clone();
+        let payload = match self.session.receive_ack(packet) {
+            Ok(payload) => payload,
+            Err(error) => {
+                log::warn!("Failed to process ack: {error}");
+                return;
+            }
+        };
+
+        let mut delivered: Vec<u16, N> = Vec::new();
+        self.window.on_ack(&header, now, |delivery| {
+            if let Delivery::Delivered(sequence) = delivery {
+                let _ = delivered.push(sequence);
+            }
+        });
+        if delivered.is_empty() {
+            // acknowledgement of messages delivered before
+            return;
+        }
+        self.reconnect.on_delivered();
+
+        // the server echoes the sequence it answers, other messages are only delivered
+        let answered = header.sequence;
+        let closed = match payload {
+            Some(Payload::Close(reason)) => Some(reason),
+            _ => None,
+        };
+        let response = payload.is_some_and(|payload| self.set_payload(&payload));
+        for sequence in delivered {
+            let Some(handle) = self.remove_unacked(sequence) else {
+                continue;
+            };
+            let event = if response && sequence == answered {
+                Event::Response(handle)
+            } else {
+                Event::Delivered(handle)
+            };
+            self.push_event(event);
+        }
+
+        if let Some(reason) = closed {
+            // messages sent after the close are not delivered anymore
+            self.window.clear();
+            while let Some(unacked) = self.unacked.pop() {
+                self.push_event(Event::Lost(unacked.handle));
+            }
+            self.state = State::Idle;
+            self.push_event(Event::Closed(reason));
+        }
+    }
+
+    /// Build a message, a session without free sequences is replaced with a new one.
+    /// The message is not sent, it might be sent again once [Event::Connected] is reported.
+    fn build(
+        &mut self,
+        now: Instant,
+        build: impl FnOnce(&mut Session) -> Result<OutputVec>,
+    ) -> Result<OutputVec> {
+        let result = build(&mut self.session);
+        if let Err(Error::SequenceExhausted) = result {
+            self.session_lost(now);
+        }
+        result
+    }
+
+    fn start_handshake(&mut self, now: Instant) -> Result<()> {
+        let packet = self.session.initiate_handshake()?;
+        let deadline = now + self.window.rtt_mut().timeout(0);
+        let _ = self.transmits.push_back(packet.clone());
+        self.state = State::Handshaking {
+            packet,
+            sent_at: now,
+            deadline,
+            retries: 0,
+        };
+        Ok(())
+    }
+
+    /// Wait for the reconnect backoff or give up.
+    fn handshake_failed(&mut self, now: Instant) {
+        self.session.close();
+        match self.reconnect.next_delay() {
+            Some(delay) => {
+                log::warn!("Handshake failed, next attempt in {delay:?}");
+                self.state = State::Backoff { until: now + delay };
+            }
+            None => {
+                self.state = State::Idle;
+                self.push_event(Event::Failed);
+            }
+        }
+    }
+
+    /// Open the session and send the messages which are not acknowledged again.
+    fn connected(&mut self, now: Instant) {
+        self.state = State::Open;
+        self.reconnect.on_connected();
+        self.push_event(Event::Connected);
+
+        for index in 0..self.unacked.len() {
+            let Ok(packet) = self.session.send_plaintext(&self.unacked[index].plaintext) else {
+                continue;
+            };
+            let sequence = self.session.sequence_id();
+            self.unacked[index].sequence = sequence;
+            self.transmit(&packet);
+            let _ = self.window.push(sequence, packet, now);
+        }
+    }
+
+    /// The server lost the session: open a new one, the messages are replayed after it.
+    fn session_lost(&mut self, now: Instant) {
+        log::warn!("Session is lost, reconnecting");
+        self.session.close();
+        self.window.clear();
+        self.push_event(Event::Reconnecting);
+        if self.start_handshake(now).is_err() {
+            self.handshake_failed(now);
+        }
+    }
+
+    fn transmit(&mut self, packet: &OutputVec) {
+        if self.transmits.push_back(packet.clone()).is_err() {
+            log::warn!("Transmit queue is full, packet dropped");
+        }
+    }
+
+    fn remove_unacked(&mut self, sequence: u16) -> Option<Handle> {
+        let index = self
+            .unacked
+            .iter()
+            .position(|unacked| unacked.sequence == sequence)?;
+        Some(self.unacked.remove(index).handle)
+    }
+
+    /// Keep the payload for [Connection::payload], the events of the previous one are updated.
+    fn set_payload(&mut self, payload: &Payload) -> bool {
+        let _ = self.payload.resize_default(COMMAND_SIZE);
+        let Ok(size) = serialize::write_payload(payload, &mut self.payload) else {
+            self.payload.clear();
+            return false;
+        };
+        self.payload.truncate(size);
+
+        let mut events = Deque::new();
+        while let Some(event) = self.events.pop_front() {
+            let event = match event {
+                Event::Response(handle) => Event::Delivered(handle),
+                Event::Message => {
+                    log::warn!("Server message is not polled, dropped");
+                    continue;
+                }
+                event => event,
+            };
+            let _ = events.push_back(event);
+        }
+        self.events = events;
+        true
+    }
+
+    fn push_event(&mut self, event: Event) {
+        if self.events.push_back(event).is_err() {
+            log::warn!("Event queue is full, {event:?} dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{reconnect::ReconnectConfig, test_server::TestServer};
+    use core::time::Duration;
+    use shared_lib::{command::EncodedCommand, network::PackedHeader, qos::Qos, write_command};
+
+    /// Deliver all packets to the server and its answers back.
+    fn exchange(connection: &mut Connection<4>, server: &mut TestServer, now: Instant) {
+        while let Some(packet) = connection.poll_transmit() {
+            if let Some(answer) = server.answer(&packet) {
+                connection.handle_datagram(&answer, now);
+            }
+        }
+    }
+
+    #[test]
+    fn retry_handshake_and_give_up() {
+        let second = Duration::from_secs(1);
+        let rtt = RttEstimator::new(7).with_bounds(second, second);
+        let reconnect = Reconnect::new(
+            ReconnectConfig {
+                initial_delay: second,
+                max_delay: second,
+                max_attempts: Some(1),
+                max_lost: 2,
+            },
+            7,
+        );
+        let mut connection = Connection::<4>::new(Session::new(7), rtt, reconnect, 1);
+        assert!(matches!(
+            connection.send(Instant::from_millis(0), |session| session.send_ping(1)),
+            Err(Error::IncorrectState)
+        ));
+
+        // the handshake is sent and retransmitted once
+        connection.connect(Instant::from_millis(0)).unwrap();
+        let mut attempts = 0;
+        while let Some(deadline) = connection.poll_timeout() {
+            while let Some(mut packet) = connection.poll_transmit() {
+                let header = PacketView::parse(&mut packet)
+                    .unwrap()
+                    .header()
+                    .message_type;
+                assert_eq!(header, MessageType::HandshakeRequest);
+                attempts += 1;
+            }
+            assert_eq!(connection.poll_event(), None);
+            connection.handle_timeout(deadline);
+        }
+
+        // two handshakes with a backoff between them
+        assert_eq!(attempts, 4);
+        assert_eq!(connection.poll_event(), Some(Event::Failed));
+        assert!(!connection.is_open());
+    }
+
+    #[test]
+    fn replay_after_server_restart() {
+        let now = Instant::from_millis(0);
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection =
+            Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut server = TestServer::new(1);
+
+        connection.connect(now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+        let ping = connection
+            .send(now, |session| session.send_ping(1))
+            .unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(ping)));
+
+        // the restarted server does not know the session, the message is sent again in a new one
+        let mut server = TestServer::new(2);
+        let ping = connection
+            .send(now, |session| session.send_ping(2))
+            .unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Reconnecting));
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(ping)));
+        assert_eq!(connection.poll_event(), None);
+        assert!(!connection.has_unacked());
+    }
+
+    #[test]
+    fn reconnect_only_on_session_error_of_message_in_flight() {
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection =
+            Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut server = TestServer::new(1);
+        let now = Instant::from_millis(0);
+        connection.connect(now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+
+        let ping = connection
+            .send(now, |session| session.send_ping(1))
+            .unwrap();
+        let mut packet = connection.poll_transmit().unwrap();
+        let sequence = PacketView::parse(&mut packet).unwrap().header().sequence;
+        let error = |sequence, code: ErrorCode| {
+            let mut buf = [0u8; PACKET_SIZE];
+            let header = PackedHeader::new(MessageType::Error, 7, 1, sequence, 0);
+            let size =
+                write_command(&header, &EncodedCommand::new(&[code.into()]), &mut buf).unwrap();
+            buf[..size].to_vec()
+        };
+
+        // errors for other sequences and errors of forged messages keep the session
+        for (sequence, code) in [
+            (sequence.wrapping_add(1), ErrorCode::BadState),
+            (sequence, ErrorCode::DecryptFailed),
+        ] {
+            connection.handle_datagram(&error(sequence, code), now);
+            assert_eq!(connection.poll_event(), None);
+            assert!(connection.is_open());
+        }
+
+        // the server lost the session, the message is sent again in a new one
+        connection.handle_datagram(&error(sequence, ErrorCode::BadState), now);
+        assert_eq!(connection.poll_event(), Some(Event::Reconnecting));
+        let mut server = TestServer::new(2);
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(ping)));
+    }
+
+    #[test]
+    fn ignore_forged_acks() {
+        let second = Duration::from_secs(1);
+        let rtt = RttEstimator::new(7).with_bounds(second, second);
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection = Connection::<4>::new(Session::new(7), rtt, reconnect, 3);
+        let mut server = TestServer::new(1);
+        let now = Instant::from_millis(0);
+        connection.connect(now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+        let ping = connection
+            .send(now, |session| session.send_ping(1))
+            .unwrap();
+        let ack = server.answer(&connection.poll_transmit().unwrap()).unwrap();
+        connection.handle_datagram(&ack, now);
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(ping)));
+
+        let event = connection
+            .send(now, |session| {
+                session.send_with_qos(Qos::AtLeastOnce, &Payload::Application(b"event"))
+            })
+            .unwrap();
+        let mut lost = connection.poll_transmit().unwrap();
+        let sequence = PacketView::parse(&mut lost).unwrap().header().sequence;
+
+        // a bare ack header and the ack of the ping with its ack fields changed
+        // acknowledge the message selectively, both are ignored
+        let mut bare = [0u8; PACKET_SIZE];
+        let header =
+            PackedHeader::new(MessageType::Ack, 7, 1, sequence, sequence).with_ack_bits(u32::MAX);
+        let size = write_command(&header, &EncodedCommand::new(&[]), &mut bare).unwrap();
+        let mut replayed = ack.clone();
+        replayed[12..14].copy_from_slice(&sequence.wrapping_add(1).to_be_bytes());
+        replayed[14..18].copy_from_slice(&u32::MAX.to_be_bytes());
+        for datagram in [&bare[..size], &replayed] {
+            connection.handle_datagram(datagram, now);
+            assert_eq!(connection.poll_event(), None);
+        }
+
+        // the message is still retransmitted and delivered with the authentic ack
+        let deadline = connection.poll_timeout().unwrap();
+        connection.handle_timeout(deadline);
+        let retransmitted = connection.poll_transmit().unwrap();
+        let answer = server.answer(&retransmitted).unwrap();
+        connection.handle_datagram(&answer, deadline);
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(event)));
+        assert!(!connection.has_unacked());
+    }
+
+    #[test]
+    fn ignore_truncated_and_garbage_datagrams() {
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection =
+            Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut server = TestServer::new(1);
+        let now = Instant::from_millis(0);
+
+        // a forged packet with garbage instead of the encrypted payload, truncated as well
+        let feed = |connection: &mut Connection<4>, message_type| {
+            let mut buf = [0u8; PACKET_SIZE];
+            let header = PackedHeader::new(message_type, 7, 1, 1, 1);
+            let size = write_command(&header, &EncodedCommand::new(&[0xa5; 40]), &mut buf).unwrap();
+            for datagram in [&buf[..size], &buf[..PackedHeader::SIZE], &buf[..3], &[]] {
+                connection.handle_datagram(datagram, now);
+            }
+        };
+
+        connection.connect(now).unwrap();
+        feed(&mut connection, MessageType::HandshakeResponse);
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+
+        let ping = connection
+            .send(now, |session| session.send_ping(1))
+            .unwrap();
+        for message_type in [
+            MessageType::Ack,
+            MessageType::EncryptedMessage,
+            MessageType::Error,
+        ] {
+            feed(&mut connection, message_type);
+        }
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(ping)));
+        assert_eq!(connection.poll_event(), None);
+    }
+
+    #[test]
+    fn match_answers_out_of_order() {
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection =
+            Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut server = TestServer::new(1);
+        let now = Instant::from_millis(0);
+        connection.connect(now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+        // one delivered message opens the congestion window for two
+        let ping = connection
+            .send(now, |session| session.send_ping(1))
+            .unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(ping)));
+
+        // the server receives the second message first, the answer of the first one
+        // acknowledges the newer sequence
+        let first = connection
+            .send(now, |session| session.send_application(b"first"))
+            .unwrap();
+        let second = connection
+            .send(now, |session| session.send_application(b"second"))
+            .unwrap();
+        let first_packet = connection.poll_transmit().unwrap();
+        let second_packet = connection.poll_transmit().unwrap();
+        for (packet, handle, data) in [
+            (second_packet, second, &b"second"[..]),
+            (first_packet, first, &b"first"[..]),
+        ] {
+            let answer = server.answer(&packet).unwrap();
+            connection.handle_datagram(&answer, now);
+            assert_eq!(connection.poll_event(), Some(Event::Response(handle)));
+            assert_eq!(connection.payload(), Some(Payload::Application(data)));
+        }
+        assert_eq!(connection.poll_event(), None);
+    }
+
+    #[test]
+    fn retransmit_by_qos() {
+        let second = Duration::from_secs(1);
+        let rtt = RttEstimator::new(7).with_bounds(second, second);
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection = Connection::<4>::new(Session::new(7), rtt, reconnect, 3);
+        let mut server = TestServer::new(1);
+        let now = Instant::from_millis(0);
+        connection.connect(now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+
+        // an at-most-once message is sent once and no timer waits for its ack
+        connection
+            .send_unreliable(now, |session| {
+                session.send_with_qos(Qos::AtMostOnce, &Payload::Application(b"status"))
+            })
+            .unwrap();
+        assert!(connection.poll_transmit().is_some());
+        assert_eq!(connection.poll_timeout(), None);
+        connection.handle_timeout(now + second * 10);
+        assert!(connection.poll_transmit().is_none());
+
+        // an at-least-once message is retransmitted until it is acknowledged
+        let event = connection
+            .send(now, |session| {
+                session.send_with_qos(Qos::AtLeastOnce, &Payload::Application(b"event"))
+            })
+            .unwrap();
+        let lost = connection.poll_transmit().unwrap();
+        let deadline = connection.poll_timeout().unwrap();
+        connection.handle_timeout(deadline);
+        let retransmitted = connection.poll_transmit().unwrap();
+        assert_eq!(retransmitted, lost);
+        let answer = server.answer(&retransmitted).unwrap();
+        connection.handle_datagram(&answer, deadline);
+        assert_eq!(connection.poll_event(), Some(Event::Delivered(event)));
+        assert!(!connection.has_unacked());
+    }
+
+    #[test]
+    fn close_in_both_directions() {
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection =
+            Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut server = TestServer::new(1);
+        let now = Instant::from_millis(0);
+        connection.connect(now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+
+        // the server closes the session: the close is acknowledged and a new session is opened
+        let (sequence, close) = server.close(7, CloseReason::Shutdown);
+        connection.handle_datagram(&close, now);
+        assert_eq!(connection.poll_event(), Some(Event::Reconnecting));
+        let mut ack = connection.poll_transmit().unwrap();
+        let ack = PacketView::parse(&mut ack).unwrap();
+        assert_eq!(ack.header().message_type, MessageType::Ack);
+        assert_eq!((ack.header().session_id, ack.header().ack), (1, sequence));
+        assert!(ack.payload().is_empty());
+
+        let mut server = TestServer::new(2);
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Connected));
+
+        // the device closes the session, the server echoes the close
+        let handle = connection.close(CloseReason::Normal, now).unwrap();
+        exchange(&mut connection, &mut server, now);
+        assert_eq!(connection.poll_event(), Some(Event::Response(handle)));
+        assert_eq!(
+            connection.poll_event(),
+            Some(Event::Closed(CloseReason::Normal))
+        );
+        assert!(!connection.is_open());
+        assert_eq!(connection.poll_timeout(), None);
+    }
+}
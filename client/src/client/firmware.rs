@@ -0,0 +1,331 @@
+use blake2::{Blake2s256, Digest};
+use shared_lib::{
+    command::Payload,
+    firmware::{FirmwareInfo, FirmwareQuery, FirmwareRequest, MAX_FIRMWARE_BLOCK_SIZE},
+    transfer::Block,
+};
+use thiserror::Error;
+
+/// Size of the chunks the image is read back in for the hash verification.
+const VERIFY_CHUNK_SIZE: usize = 64;
+
+/// Download progress, persisted after every stored block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Image being downloaded, a different image restarts the download.
+    pub info: FirmwareInfo,
+    /// The next block to request.
+    pub next_block: u32,
+}
+
+/// Storage of the firmware image and the download progress, e.g. an update flash slot.
+///
+/// The progress must survive a power loss, so the download continues from the last
+/// stored block. Blocks are written before the progress is saved.
+pub trait FirmwareStorage {
+    type Error: core::fmt::Debug;
+
+    /// Write image data at the offset.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Read image data at the offset, used to verify the stored image.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Load the saved progress, `None` if there is no download in progress.
+    fn load_progress(&mut self) -> Result<Option<Progress>, Self::Error>;
+
+    /// Save the progress.
+    fn save_progress(&mut self, progress: &Progress) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError<E: core::fmt::Debug> {
+    #[error("Storage error: {0:?}")]
+    Storage(E),
+    #[error("Image description is not received yet")]
+    NoInfo,
+    #[error("Image is too big or has a different version")]
+    InvalidInfo,
+    #[error("Unexpected block {0}")]
+    UnexpectedBlock(u32),
+    #[error("Image hash does not match")]
+    HashMismatch,
+}
+
+/// Firmware image download over the secure session.
+///
+/// The download starts with [Download::query], the server answers with [FirmwareInfo]
+/// which is passed to [Download::on_info]. After that blocks are requested one by one
+/// with [Download::next_request] and stored with [Download::on_block]. Once the last
+/// block is stored, the image is read back and verified against the hash.
+pub struct Download<'a, S: FirmwareStorage> {
+    name: &'a str,
+    version: u32,
+    block_size: u16,
+    max_size: u32,
+    storage: S,
+    progress: Option<Progress>,
+    verified: bool,
+}
+
+impl<'a, S: FirmwareStorage> Download<'a, S> {
+    /// Download the image `name` of `version`, no bigger than `max_size` bytes.
+    pub fn new(name: &'a str, version: u32, block_size: u16, max_size: u32, storage: S) -> Self {
+        Download {
+            name,
+            version,
+            block_size: block_size.clamp(1, MAX_FIRMWARE_BLOCK_SIZE),
+            max_size,
+            storage,
+            progress: None,
+            verified: false,
+        }
+    }
+
+    /// Request for the image description.
+    pub fn query(&self) -> Payload<'a> {
+        Payload::FirmwareQuery(FirmwareQuery {
+            name: self.name,
+            version: self.version,
+        })
+    }
+
+    /// Accept the image description. A download of the same image is resumed
+    /// from the saved progress, otherwise it starts from the first block.
+    pub fn on_info(&mut self, info: FirmwareInfo) -> Result<(), DownloadError<S::Error>> {
+        if info.version != self.version || info.size > self.max_size {
+            return Err(DownloadError::InvalidInfo);
+        }
+
+        let progress = match self
+            .storage
+            .load_progress()
+            .map_err(DownloadError::Storage)?
+        {
+            Some(progress) if progress.info == info => {
+                log::info!(
+                    "Resuming firmware download from block {}",
+                    progress.next_block
+                );
+                progress
+            }
+            _ => {
+                let progress = Progress {
+                    info,
+                    next_block: 0,
+                };
+                self.storage
+                    .save_progress(&progress)
+                    .map_err(DownloadError::Storage)?;
+                progress
+            }
+        };
+
+        self.progress = Some(progress);
+        if progress.next_block >= self.block_count(&info) {
+            // power loss after the last block, only the verification is left
+            self.verify()?;
+        }
+        Ok(())
+    }
+
+    /// Request for the next block, `None` if all blocks are stored.
+    pub fn next_request(&self) -> Option<Payload<'a>> {
+        let progress = self.progress?;
+        if progress.next_block >= self.block_count(&progress.info) {
+            return None;
+        }
+
+        Some(Payload::FirmwareRequest(FirmwareRequest {
+            name: self.name,
+            version: self.version,
+            index: progress.next_block,
+            block_size: self.block_size,
+        }))
+    }
+
+    /// Store the received block. The image is verified after the last block.
+    /// Returns `true` when the image is downloaded and verified.
+    pub fn on_block(&mut self, block: &Block) -> Result<bool, DownloadError<S::Error>> {
+        let Some(mut progress) = self.progress else {
+            return Err(DownloadError::NoInfo);
+        };
+
+        let offset = u64::from(block.index) * u64::from(self.block_size);
+        let expected_size =
+            (u64::from(progress.info.size).saturating_sub(offset)).min(u64::from(self.block_size));
+        if block.name != self.name
+            || block.index != progress.next_block
+            || block.block_size != self.block_size
+            || block.data.len() as u64 != expected_size
+        {
+            return Err(DownloadError::UnexpectedBlock(block.index));
+        }
+
+        self.storage
+            .write(offset as u32, block.data)
+            .map_err(DownloadError::Storage)?;
+        progress.next_block += 1;
+        self.storage
+            .save_progress(&progress)
+            .map_err(DownloadError::Storage)?;
+        self.progress = Some(progress);
+
+        if progress.next_block < self.block_count(&progress.info) {
+            return Ok(false);
+        }
+        self.verify()?;
+        Ok(true)
+    }
+
+    /// Image is downloaded and verified.
+    pub fn is_complete(&self) -> bool {
+        self.verified
+    }
+
+    /// Current progress, `None` before the image description is received.
+    pub fn progress(&self) -> Option<Progress> {
+        self.progress
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    fn block_count(&self, info: &FirmwareInfo) -> u32 {
+        info.block_count(self.block_size)
+    }
+
+    /// Read the stored image back and compare its hash.
+    /// A corrupted image restarts the download.
+    fn verify(&mut self) -> Result<(), DownloadError<S::Error>> {
+        let Some(progress) = self.progress else {
+            return Err(DownloadError::NoInfo);
+        };
+
+        let mut hasher = Blake2s256::new();
+        let mut chunk = [0u8; VERIFY_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < progress.info.size {
+            let size = (progress.info.size - offset).min(VERIFY_CHUNK_SIZE as u32) as usize;
+            self.storage
+                .read(offset, &mut chunk[..size])
+                .map_err(DownloadError::Storage)?;
+            hasher.update(&chunk[..size]);
+            offset += size as u32;
+        }
+
+        if hasher.finalize().as_slice() != progress.info.hash {
+            let restart = Progress {
+                info: progress.info,
+                next_block: 0,
+            };
+            self.storage
+                .save_progress(&restart)
+                .map_err(DownloadError::Storage)?;
+            self.progress = Some(restart);
+            return Err(DownloadError::HashMismatch);
+        }
+
+        self.verified = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MemoryStorage {
+        image: [u8; 256],
+        progress: Option<Progress>,
+    }
+
+    impl FirmwareStorage for MemoryStorage {
+        type Error = ();
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            self.image[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.image[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn load_progress(&mut self) -> Result<Option<Progress>, ()> {
+            Ok(self.progress)
+        }
+
+        fn save_progress(&mut self, progress: &Progress) -> Result<(), ()> {
+            self.progress = Some(*progress);
+            Ok(())
+        }
+    }
+
+    fn block<'a>(image: &'a [u8], index: u32) -> Block<'a> {
+        let start = index as usize * 64;
+        let end = (start + 64).min(image.len());
+        Block {
+            name: "sensor",
+            index,
+            block_size: 64,
+            last: end == image.len(),
+            data: &image[start..end],
+        }
+    }
+
+    #[test]
+    fn resume_and_verify() {
+        let mut image = [0u8; 200];
+        for (i, byte) in image.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        let info = FirmwareInfo {
+            version: 3,
+            size: image.len() as u32,
+            hash: Blake2s256::digest(image).into(),
+        };
+        let storage = MemoryStorage {
+            image: [0u8; 256],
+            progress: None,
+        };
+
+        // download two blocks and lose power
+        let mut download = Download::new("sensor", 3, 64, 256, storage);
+        download.on_info(info).unwrap();
+        assert!(!download.on_block(&block(&image, 0)).unwrap());
+        assert!(!download.on_block(&block(&image, 1)).unwrap());
+        let storage = download.into_storage();
+
+        // resume from the third block
+        let mut download = Download::new("sensor", 3, 64, 256, storage);
+        download.on_info(info).unwrap();
+        let Some(Payload::FirmwareRequest(request)) = download.next_request() else {
+            panic!("Expected firmware request");
+        };
+        assert_eq!(request.index, 2);
+        assert!(matches!(
+            download.on_block(&block(&image, 1)),
+            Err(DownloadError::UnexpectedBlock(1))
+        ));
+
+        assert!(!download.on_block(&block(&image, 2)).unwrap());
+        assert!(download.on_block(&block(&image, 3)).unwrap());
+        assert!(download.is_complete());
+        assert!(download.next_request().is_none());
+
+        // corrupted image restarts the download
+        let mut storage = download.into_storage();
+        storage.image[0] ^= 1;
+        let mut download = Download::new("sensor", 3, 64, 256, storage);
+        assert!(matches!(
+            download.on_info(info),
+            Err(DownloadError::HashMismatch)
+        ));
+        assert_eq!(download.progress().unwrap().next_block, 0);
+    }
+}
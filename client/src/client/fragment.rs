@@ -0,0 +1,45 @@
+use core::time::Duration;
+
+use shared_lib::{command::Payload, fragment::Fragment, fragment::Reassembly, parse_payload_body};
+
+use super::{session::Result, time::Instant};
+
+/// Reassembles fragmented server messages in a fixed-size buffer of `N` bytes.
+/// An incomplete message is dropped after the timeout or when another message starts.
+pub struct Reassembler<const N: usize> {
+    reassembly: Reassembly<N>,
+    started: Option<Instant>,
+    timeout: Duration,
+}
+
+impl<const N: usize> Reassembler<N> {
+    pub const fn new(timeout: Duration) -> Self {
+        Reassembler {
+            reassembly: Reassembly::new(),
+            started: None,
+            timeout,
+        }
+    }
+
+    /// Add a received fragment. Returns the payload once the message is complete.
+    pub fn push(&mut self, fragment: &Fragment, now: Instant) -> Result<Option<Payload<'_>>> {
+        let expired = self
+            .started
+            .is_some_and(|started| now >= started + self.timeout);
+        if expired || self.reassembly.message_id() != Some(fragment.message_id) {
+            if expired {
+                log::warn!("Reassembly timed out, message dropped");
+            }
+            self.reassembly.reset();
+            self.started = Some(now);
+        }
+
+        match self.reassembly.push(fragment)? {
+            Some((kind, message)) => {
+                self.started = None;
+                Ok(Some(parse_payload_body(kind, message)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
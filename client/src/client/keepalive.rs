@@ -0,0 +1,50 @@
+use super::{
+    session::{OutputVec, Result, Session},
+    time::Instant,
+};
+
+/// Schedules keepalive pings while the device has nothing else to send.
+///
+/// Any message sent to the server keeps the session alive, so the caller reports sent
+/// messages with [Keepalive::on_sent] and a ping is due only after a full interval of silence.
+/// The interval is negotiated in the handshake, see [Session::keepalive_interval].
+pub struct Keepalive {
+    last_sent: Instant,
+    next_token: u32,
+}
+
+impl Keepalive {
+    pub fn new(now: Instant) -> Self {
+        Keepalive {
+            last_sent: now,
+            next_token: 0,
+        }
+    }
+
+    /// A message was sent to the server.
+    pub fn on_sent(&mut self, now: Instant) {
+        self.last_sent = now;
+    }
+
+    /// Time when the next ping is due, `None` if the interval is unknown yet.
+    pub fn deadline(&self, session: &Session) -> Option<Instant> {
+        let interval = session.keepalive_interval();
+        (!interval.is_zero()).then(|| self.last_sent + interval)
+    }
+
+    /// Build the ping packet if it is due. The server answers with the returned token.
+    pub fn poll(
+        &mut self,
+        session: &mut Session,
+        now: Instant,
+    ) -> Result<Option<(u32, OutputVec)>> {
+        if self.deadline(session).is_none_or(|deadline| deadline > now) {
+            return Ok(None);
+        }
+
+        self.next_token = self.next_token.wrapping_add(1);
+        let packet = session.send_ping(self.next_token)?;
+        self.last_sent = now;
+        Ok(Some((self.next_token, packet)))
+    }
+}
@@ -0,0 +1,183 @@
+use core::net::SocketAddr;
+
+use embedded_nal::UdpClientStack;
+use shared_lib::command::PACKET_SIZE;
+
+use super::{connection::Connection, time::Instant};
+
+/// Moves the datagrams of a [Connection] over an [UdpClientStack], e.g. a smoltcp-based stack.
+///
+/// The transport never blocks: [NalTransport::poll] sends the waiting packets, handles
+/// the received datagrams and the expired timers and returns the time it should be called
+/// again at. The firmware sleeps until then or until the stack receives a datagram.
+pub struct NalTransport<S: UdpClientStack, const N: usize> {
+    stack: S,
+    socket: S::UdpSocket,
+    connection: Connection<N>,
+}
+
+impl<S: UdpClientStack, const N: usize> NalTransport<S, N> {
+    /// Open a socket of the stack connected to the server.
+    pub fn new(
+        mut stack: S,
+        remote: SocketAddr,
+        connection: Connection<N>,
+    ) -> Result<Self, S::Error> {
+        let mut socket = stack.socket()?;
+        stack.connect(&mut socket, remote)?;
+        Ok(NalTransport {
+            stack,
+            socket,
+            connection,
+        })
+    }
+
+    /// Connection to send messages and poll events with.
+    pub fn connection(&mut self) -> &mut Connection<N> {
+        &mut self.connection
+    }
+
+    /// Send the waiting packets, handle the received datagrams and the expired timers.
+    /// Returns the time of the next timer, `None` if the connection waits only for datagrams.
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Instant>, S::Error> {
+        self.flush()?;
+
+        let mut buf = [0u8; PACKET_SIZE];
+        loop {
+            match self.stack.receive(&mut self.socket, &mut buf) {
+                Ok((size, _)) => self.connection.handle_datagram(&buf[..size], now),
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(error)) => return Err(error),
+            }
+        }
+        self.connection.handle_timeout(now);
+
+        self.flush()?;
+        Ok(self.connection.poll_timeout())
+    }
+
+    /// Close the socket and return the stack.
+    pub fn close(mut self) -> Result<S, S::Error> {
+        self.stack.close(self.socket)?;
+        Ok(self.stack)
+    }
+
+    fn flush(&mut self) -> Result<(), S::Error> {
+        while let Some(packet) = self.connection.poll_transmit() {
+            match self.stack.send(&mut self.socket, &packet) {
+                Ok(()) => {}
+                // no room in the stack buffers, the packet is retransmitted like a lost one
+                Err(nb::Error::WouldBlock) => log::warn!("Stack is busy, packet dropped"),
+                Err(nb::Error::Other(error)) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::client::{
+        connection::Event, reconnect::Reconnect, reconnect::ReconnectConfig, rtt::RttEstimator,
+        session::Session, test_server,
+    };
+    use std::{io::ErrorKind, net::UdpSocket};
+
+    /// Std-backed stack, the same traits as the firmware stack on Linux.
+    struct StdStack;
+
+    impl UdpClientStack for StdStack {
+        type UdpSocket = Option<UdpSocket>;
+        type Error = std::io::Error;
+
+        fn socket(&mut self) -> Result<Self::UdpSocket, Self::Error> {
+            Ok(None)
+        }
+
+        fn connect(
+            &mut self,
+            socket: &mut Self::UdpSocket,
+            remote: SocketAddr,
+        ) -> Result<(), Self::Error> {
+            let udp = UdpSocket::bind("127.0.0.1:0")?;
+            udp.connect(remote)?;
+            udp.set_nonblocking(true)?;
+            *socket = Some(udp);
+            Ok(())
+        }
+
+        fn send(
+            &mut self,
+            socket: &mut Self::UdpSocket,
+            buffer: &[u8],
+        ) -> nb::Result<(), Self::Error> {
+            connected(socket)?
+                .send(buffer)
+                .map(|_| ())
+                .map_err(nb_error)
+        }
+
+        fn receive(
+            &mut self,
+            socket: &mut Self::UdpSocket,
+            buffer: &mut [u8],
+        ) -> nb::Result<(usize, SocketAddr), Self::Error> {
+            connected(socket)?.recv_from(buffer).map_err(nb_error)
+        }
+
+        fn close(&mut self, _socket: Self::UdpSocket) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn connected(socket: &Option<UdpSocket>) -> nb::Result<&UdpSocket, std::io::Error> {
+        socket
+            .as_ref()
+            .ok_or_else(|| nb::Error::Other(ErrorKind::NotConnected.into()))
+    }
+
+    fn nb_error(error: std::io::Error) -> nb::Error<std::io::Error> {
+        match error.kind() {
+            ErrorKind::WouldBlock => nb::Error::WouldBlock,
+            _ => nb::Error::Other(error),
+        }
+    }
+
+    #[test]
+    fn deliver_over_std_stack() {
+        // the server answers the handshake and the ping in its own thread
+        let (remote, answering) = test_server::spawn_udp(2);
+
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let connection = Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut transport = NalTransport::new(StdStack, remote, connection).unwrap();
+        let started = std::time::Instant::now();
+        let now = || Instant::from_millis(started.elapsed().as_millis() as u64);
+
+        transport.connection().connect(now()).unwrap();
+        let mut ping = None;
+        let delivered = loop {
+            assert!(started.elapsed().as_secs() < 5, "Not delivered in time");
+            transport.poll(now()).unwrap();
+            match transport.connection().poll_event() {
+                Some(Event::Connected) => {
+                    let handle = transport
+                        .connection()
+                        .send(now(), |session| session.send_ping(1))
+                        .unwrap();
+                    ping = Some(handle);
+                }
+                Some(Event::Delivered(handle)) => break handle,
+                Some(event) => panic!("Unexpected event {event:?}"),
+                None => std::thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        };
+
+        assert_eq!(Some(delivered), ping);
+        answering.join().unwrap();
+        transport.close().unwrap();
+    }
+}
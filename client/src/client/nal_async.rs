@@ -0,0 +1,188 @@
+use core::future::Future;
+
+use embedded_nal_async::ConnectedUdp;
+use shared_lib::command::PACKET_SIZE;
+
+use super::{
+    connection::{Connection, Event},
+    time::Instant,
+};
+
+/// Time source and timer of the async runtime, e.g. embassy-time.
+#[allow(async_fn_in_trait)]
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Run the future until the deadline, `None` if the deadline passed first.
+    async fn with_deadline<F: Future>(&self, deadline: Instant, future: F) -> Option<F::Output>;
+}
+
+/// Moves the datagrams of a [Connection] over a [ConnectedUdp] socket of an async stack.
+pub struct AsyncTransport<U: ConnectedUdp, C: Clock, const N: usize> {
+    udp: U,
+    clock: C,
+    connection: Connection<N>,
+}
+
+impl<U: ConnectedUdp, C: Clock, const N: usize> AsyncTransport<U, C, N> {
+    pub fn new(udp: U, clock: C, connection: Connection<N>) -> Self {
+        AsyncTransport {
+            udp,
+            clock,
+            connection,
+        }
+    }
+
+    /// Connection to send messages and poll events with.
+    pub fn connection(&mut self) -> &mut Connection<N> {
+        &mut self.connection
+    }
+
+    /// Drive the connection until `on_event` returns a value: send the packets, receive
+    /// the datagrams and handle the timers. `on_event` might send new messages.
+    pub async fn run_until<T>(
+        &mut self,
+        mut on_event: impl FnMut(&mut Connection<N>, Event) -> Option<T>,
+    ) -> Result<T, U::Error> {
+        let mut buf = [0u8; PACKET_SIZE];
+        loop {
+            self.flush().await?;
+            while let Some(event) = self.connection.poll_event() {
+                if let Some(result) = on_event(&mut self.connection, event) {
+                    self.flush().await?;
+                    return Ok(result);
+                }
+            }
+            // messages sent by the handler
+            self.flush().await?;
+
+            let received = match self.connection.poll_timeout() {
+                Some(deadline) => {
+                    self.clock
+                        .with_deadline(deadline, self.udp.receive_into(&mut buf))
+                        .await
+                }
+                None => Some(self.udp.receive_into(&mut buf).await),
+            };
+            let now = self.clock.now();
+            if let Some(size) = received.transpose()? {
+                self.connection.handle_datagram(&buf[..size], now);
+            }
+            self.connection.handle_timeout(now);
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), U::Error> {
+        while let Some(packet) = self.connection.poll_transmit() {
+            self.udp.send(&packet).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::client::{
+        reconnect::Reconnect, reconnect::ReconnectConfig, rtt::RttEstimator, session::Session,
+        test_server,
+    };
+    use core::{
+        convert::Infallible,
+        future::poll_fn,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{io::ErrorKind, net::UdpSocket};
+
+    /// Std-backed socket, the futures are polled until the socket is ready.
+    struct StdUdp(UdpSocket);
+
+    impl ConnectedUdp for StdUdp {
+        type Error = Infallible;
+
+        async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+            self.0.send(data).unwrap();
+            Ok(())
+        }
+
+        async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+            poll_fn(|cx| match self.0.recv(buffer) {
+                Ok(size) => Poll::Ready(Ok(size)),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                Err(error) => panic!("Failed to receive: {error}"),
+            })
+            .await
+        }
+    }
+
+    struct StdClock(std::time::Instant);
+
+    impl Clock for StdClock {
+        fn now(&self) -> Instant {
+            Instant::from_millis(self.0.elapsed().as_millis() as u64)
+        }
+
+        async fn with_deadline<F: Future>(
+            &self,
+            deadline: Instant,
+            future: F,
+        ) -> Option<F::Output> {
+            let mut future = pin!(future);
+            poll_fn(|cx| match future.as_mut().poll(cx) {
+                Poll::Ready(output) => Poll::Ready(Some(output)),
+                Poll::Pending if self.now() >= deadline => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            })
+            .await
+        }
+    }
+
+    /// Poll the future until it is ready.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn deliver_over_std_udp() {
+        // the server answers the handshake and the ping in its own thread
+        let (remote, answering) = test_server::spawn_udp(2);
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.connect(remote).unwrap();
+        udp.set_nonblocking(true).unwrap();
+
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let connection = Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let started = std::time::Instant::now();
+        let clock = StdClock(started);
+        let now = clock.now();
+        let mut transport = AsyncTransport::new(StdUdp(udp), clock, connection);
+
+        transport.connection().connect(now).unwrap();
+        let mut ping = None;
+        let delivered = block_on(transport.run_until(|connection, event| match event {
+            Event::Connected => {
+                ping = connection.send(now, |session| session.send_ping(1)).ok();
+                None
+            }
+            Event::Delivered(handle) => Some(handle),
+            event => panic!("Unexpected event {event:?}"),
+        }))
+        .unwrap();
+
+        assert_eq!(Some(delivered), ping);
+        answering.join().unwrap();
+    }
+}
@@ -0,0 +1,431 @@
+use core::time::Duration;
+
+use heapless::{Deque, Vec};
+use shared_lib::telemetry::{self, Reading, Telemetry};
+use thiserror::Error;
+
+use super::{
+    batch::Batcher,
+    connection::{Connection, Event, Handle},
+    session::Error,
+    time::Instant,
+};
+
+/// Size of a stored record: sequence 4 bytes, priority 1 byte, length 1 byte
+/// and the reading with the telemetry envelope.
+pub const RECORD_SIZE: usize = 6 + Telemetry::HEADER_SIZE + Reading::MAX_SIZE;
+
+/// Persistent slots of the [OfflineQueue], e.g. EEPROM pages or a flash sector.
+///
+/// Every slot keeps one record and is written independently, so a power loss damages
+/// one reading at most. The queue order is restored from the sequence in the records.
+pub trait QueueStorage {
+    type Error: core::fmt::Debug;
+
+    /// Read the record of the slot, `false` if the slot is empty.
+    fn read(&mut self, slot: usize, record: &mut [u8; RECORD_SIZE]) -> Result<bool, Self::Error>;
+
+    /// Write the record of the slot.
+    fn write(&mut self, slot: usize, record: &[u8; RECORD_SIZE]) -> Result<(), Self::Error>;
+
+    /// Mark the slot empty.
+    fn erase(&mut self, slot: usize) -> Result<(), Self::Error>;
+}
+
+/// Storage which keeps nothing, the readings are lost on a restart.
+pub struct MemoryStorage;
+
+impl QueueStorage for MemoryStorage {
+    type Error = core::convert::Infallible;
+
+    fn read(&mut self, _slot: usize, _record: &mut [u8; RECORD_SIZE]) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn write(&mut self, _slot: usize, _record: &[u8; RECORD_SIZE]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn erase(&mut self, _slot: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Which reading gives way to a new one when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// The oldest reading is dropped.
+    OldestFirst,
+    /// The oldest reading with the lowest priority is dropped. A new reading with
+    /// a lower priority than all stored ones is dropped itself.
+    LowestPriority,
+}
+
+#[derive(Debug, Error)]
+pub enum QueueError<E: core::fmt::Debug> {
+    #[error("Storage error: {0:?}")]
+    Storage(E),
+    #[error("Session error: {0}")]
+    Session(#[from] Error),
+}
+
+/// Reading kept while the server is not reachable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    /// The reading with the time it was taken.
+    pub reading: Reading,
+    pub priority: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Stored {
+    entry: Entry,
+    sequence: u32,
+    slot: usize,
+}
+
+/// Readings in flight, the first `count` entries of the queue.
+struct InFlight {
+    handle: Handle,
+    count: usize,
+}
+
+/// Bounded store-and-forward queue of readings, up to `N` readings.
+///
+/// Readings produced while the server is not reachable are pushed to the queue and
+/// persisted in the storage. [OfflineQueue::poll_send] drains them in batches, oldest
+/// first, whenever the connection can send, e.g. once the session is reestablished.
+/// The readings are removed when the server acknowledges them.
+pub struct OfflineQueue<S: QueueStorage, const N: usize> {
+    storage: S,
+    eviction: Eviction,
+    entries: Deque<Stored, N>,
+    next_sequence: u32,
+    in_flight: Option<InFlight>,
+}
+
+impl<S: QueueStorage, const N: usize> OfflineQueue<S, N> {
+    /// Restore the readings kept in the storage, damaged records are dropped.
+    pub fn open(mut storage: S, eviction: Eviction) -> Result<Self, QueueError<S::Error>> {
+        let mut stored: Vec<Stored, N> = Vec::new();
+        let mut record = [0u8; RECORD_SIZE];
+        for slot in 0..N {
+            if !storage
+                .read(slot, &mut record)
+                .map_err(QueueError::Storage)?
+            {
+                continue;
+            }
+            match parse_record(&record) {
+                Some((sequence, entry)) => {
+                    let _ = stored.push(Stored {
+                        entry,
+                        sequence,
+                        slot,
+                    });
+                }
+                None => {
+                    log::warn!("Stored reading {slot} is damaged, dropped");
+                    storage.erase(slot).map_err(QueueError::Storage)?;
+                }
+            }
+        }
+        stored.sort_unstable_by_key(|stored| stored.sequence);
+
+        let next_sequence = stored
+            .last()
+            .map_or(0, |last| last.sequence.wrapping_add(1));
+        let mut entries = Deque::new();
+        for stored in stored {
+            let _ = entries.push_back(stored);
+        }
+        Ok(OfflineQueue {
+            storage,
+            eviction,
+            entries,
+            next_sequence,
+            in_flight: None,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Readings in the queue, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().map(|stored| &stored.entry)
+    }
+
+    /// Store a reading until it is delivered.
+    /// Returns the reading dropped to make room for it, it might be the new one.
+    pub fn push(
+        &mut self,
+        reading: Reading,
+        priority: u8,
+    ) -> Result<Option<Entry>, QueueError<S::Error>> {
+        let entry = Entry { reading, priority };
+        let mut evicted = None;
+        if self.entries.is_full() {
+            let victim = match self.eviction {
+                Eviction::OldestFirst => 0,
+                Eviction::LowestPriority => {
+                    let (index, lowest) = self
+                        .entries
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(index, stored)| (stored.entry.priority, *index))
+                        .map(|(index, stored)| (index, stored.entry.priority))
+                        .unwrap_or_default();
+                    if priority < lowest {
+                        return Ok(Some(entry));
+                    }
+                    index
+                }
+            };
+            evicted = Some(self.remove(victim)?);
+        }
+
+        let slot = (0..N)
+            .find(|slot| self.entries.iter().all(|stored| stored.slot != *slot))
+            .unwrap_or_default();
+        let sequence = self.next_sequence;
+        let mut record = [0u8; RECORD_SIZE];
+        write_record(sequence, &entry, &mut record)?;
+        self.storage
+            .write(slot, &record)
+            .map_err(QueueError::Storage)?;
+
+        self.next_sequence = sequence.wrapping_add(1);
+        let _ = self.entries.push_back(Stored {
+            entry,
+            sequence,
+            slot,
+        });
+        Ok(evicted)
+    }
+
+    /// Send the oldest readings in one batch if the connection can send and
+    /// no batch is in flight. Call it whenever the driver polls the connection.
+    pub fn poll_send<const M: usize>(
+        &mut self,
+        connection: &mut Connection<M>,
+        now: Instant,
+    ) -> Result<(), QueueError<S::Error>> {
+        if self.in_flight.is_some() || self.entries.is_empty() || !connection.can_send() {
+            return Ok(());
+        }
+
+        let mut batcher = Batcher::<N>::new(Duration::ZERO);
+        let count = self
+            .entries
+            .iter()
+            .take_while(|stored| batcher.push(stored.entry.reading, now).is_ok())
+            .count();
+        let handle = connection.send(now, |session| {
+            batcher
+                .flush(session)
+                .and_then(|batch| batch.ok_or(Error::IncorrectState))
+        })?;
+        log::info!("Sending {count} stored readings");
+        self.in_flight = Some(InFlight { handle, count });
+        Ok(())
+    }
+
+    /// Handle an event of the connection.
+    /// Returns `true` if the event reported a batch of the queue.
+    pub fn handle_event(&mut self, event: &Event) -> Result<bool, QueueError<S::Error>> {
+        let Some(in_flight) = &self.in_flight else {
+            return Ok(false);
+        };
+        match *event {
+            Event::Delivered(handle) | Event::Response(handle) if handle == in_flight.handle => {
+                self.remove_in_flight()?;
+            }
+            Event::Rejected(handle, code) if handle == in_flight.handle => {
+                // the server would reject them again
+                log::warn!("Stored readings are rejected: {code}, dropped");
+                self.remove_in_flight()?;
+            }
+            // the readings are kept and sent again
+            Event::Lost(handle) if handle == in_flight.handle => self.in_flight = None,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn remove_in_flight(&mut self) -> Result<(), QueueError<S::Error>> {
+        if let Some(in_flight) = self.in_flight.take() {
+            for _ in 0..in_flight.count {
+                self.remove(0)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, index: usize) -> Result<Entry, QueueError<S::Error>> {
+        // the deque keeps the order, rotate the entries around the removed one
+        let mut removed = None;
+        for position in 0..self.entries.len() {
+            let Some(stored) = self.entries.pop_front() else {
+                break;
+            };
+            if position == index {
+                removed = Some(stored);
+            } else {
+                let _ = self.entries.push_back(stored);
+            }
+        }
+        let removed = removed.ok_or(Error::IncorrectState)?;
+        self.storage
+            .erase(removed.slot)
+            .map_err(QueueError::Storage)?;
+
+        // an evicted reading in flight is still delivered, it is not removed twice
+        if let Some(in_flight) = &mut self.in_flight {
+            if index < in_flight.count {
+                in_flight.count -= 1;
+            }
+        }
+        Ok(removed.entry)
+    }
+}
+
+fn write_record(sequence: u32, entry: &Entry, record: &mut [u8; RECORD_SIZE]) -> Result<(), Error> {
+    record[..4].copy_from_slice(&sequence.to_be_bytes());
+    record[4] = entry.priority;
+    let size = telemetry::write_readings(&[entry.reading], &mut record[6..])?;
+    record[5] = size as u8;
+    Ok(())
+}
+
+fn parse_record(record: &[u8; RECORD_SIZE]) -> Option<(u32, Entry)> {
+    let size = usize::from(record[5]);
+    let readings = record.get(6..6 + size)?;
+    let reading = Telemetry::parse(readings).ok()?.readings().next()?.ok()?;
+    Some((
+        u32::from_be_bytes(record[..4].try_into().ok()?),
+        Entry {
+            reading,
+            priority: record[4],
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{
+        reconnect::Reconnect, reconnect::ReconnectConfig, rtt::RttEstimator, session::Session,
+        test_server::TestServer,
+    };
+    use shared_lib::telemetry::Information;
+
+    /// Slots in memory which survive the queue, like a flash sector survives a restart.
+    #[derive(Default)]
+    struct Slots([Option<[u8; RECORD_SIZE]>; 4]);
+
+    impl QueueStorage for &mut Slots {
+        type Error = core::convert::Infallible;
+
+        fn read(
+            &mut self,
+            slot: usize,
+            record: &mut [u8; RECORD_SIZE],
+        ) -> Result<bool, Self::Error> {
+            if let Some(stored) = self.0[slot] {
+                *record = stored;
+            }
+            Ok(self.0[slot].is_some())
+        }
+
+        fn write(&mut self, slot: usize, record: &[u8; RECORD_SIZE]) -> Result<(), Self::Error> {
+            self.0[slot] = Some(*record);
+            Ok(())
+        }
+
+        fn erase(&mut self, slot: usize) -> Result<(), Self::Error> {
+            self.0[slot] = None;
+            Ok(())
+        }
+    }
+
+    fn counter(value: u32) -> Reading {
+        Reading::new(Information::Counter(value))
+    }
+
+    fn counters<S: QueueStorage, const N: usize>(queue: &OfflineQueue<S, N>) -> Vec<u32, N> {
+        queue
+            .entries()
+            .filter_map(|entry| match entry.reading.value {
+                Information::Counter(value) => Some(value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn evict_and_restore() {
+        let mut slots = Slots::default();
+        let mut queue = OfflineQueue::<_, 4>::open(&mut slots, Eviction::OldestFirst).unwrap();
+        for value in 1..=5 {
+            queue.push(counter(value), 0).unwrap();
+        }
+        assert_eq!(counters(&queue), [2, 3, 4, 5]);
+
+        let mut queue = OfflineQueue::<_, 4>::open(&mut slots, Eviction::LowestPriority).unwrap();
+        assert_eq!(counters(&queue), [2, 3, 4, 5]);
+        let evicted = queue.push(counter(6), 1).unwrap();
+        assert_eq!(evicted.map(|entry| entry.reading), Some(counter(2)));
+        let evicted = queue.push(counter(7), 0).unwrap();
+        assert_eq!(evicted.map(|entry| entry.reading), Some(counter(3)));
+        queue.push(counter(8), 2).unwrap();
+        queue.push(counter(9), 2).unwrap();
+        queue.push(counter(10), 2).unwrap();
+        // lower than everything stored
+        let evicted = queue.push(counter(11), 0).unwrap();
+        assert_eq!(evicted.map(|entry| entry.reading), Some(counter(11)));
+        assert_eq!(counters(&queue), [6, 8, 9, 10]);
+
+        let queue = OfflineQueue::<_, 4>::open(&mut slots, Eviction::OldestFirst).unwrap();
+        assert_eq!(counters(&queue), [6, 8, 9, 10]);
+    }
+
+    #[test]
+    fn drain_once_connected() {
+        let mut slots = Slots::default();
+        let mut queue = OfflineQueue::<_, 4>::open(&mut slots, Eviction::OldestFirst).unwrap();
+        for value in 1..=3 {
+            queue.push(counter(value), 0).unwrap();
+        }
+
+        let now = Instant::from_millis(0);
+        let reconnect = Reconnect::new(ReconnectConfig::default(), 7);
+        let mut connection =
+            Connection::<4>::new(Session::new(7), RttEstimator::new(7), reconnect, 3);
+        let mut server = TestServer::new(1);
+        // nothing is sent until the session is open
+        queue.poll_send(&mut connection, now).unwrap();
+        connection.connect(now).unwrap();
+
+        let mut batches = 0;
+        while !queue.is_empty() {
+            queue.poll_send(&mut connection, now).unwrap();
+            while let Some(packet) = connection.poll_transmit() {
+                batches += usize::from(connection.is_open());
+                if let Some(answer) = server.answer(&packet) {
+                    connection.handle_datagram(&answer, now);
+                }
+            }
+            while let Some(event) = connection.poll_event() {
+                queue.handle_event(&event).unwrap();
+            }
+        }
+        assert_eq!(batches, 1);
+        assert!(slots.0.iter().all(Option::is_none));
+    }
+}
@@ -0,0 +1,20 @@
+/// Xorshift generator for timer jitter, not suitable for cryptography.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct XorShift(u32);
+
+impl XorShift {
+    /// Create a generator, `seed` should differ between devices, e.g. the device id.
+    pub(crate) fn new(seed: u32) -> Self {
+        // xorshift must not start from zero
+        XorShift(seed | 1)
+    }
+
+    pub(crate) fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
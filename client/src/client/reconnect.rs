@@ -0,0 +1,141 @@
+use core::time::Duration;
+
+use shared_lib::error::ErrorCode;
+
+use super::random::XorShift;
+
+/// Reconnect backoff settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    /// Delay before the first handshake attempt after the session was lost.
+    pub initial_delay: Duration,
+    /// The longest delay between handshake attempts.
+    pub max_delay: Duration,
+    /// Failed handshakes before the driver gives up, `None` to try forever.
+    pub max_attempts: Option<u8>,
+    /// Messages lost in a row after which the session is considered lost.
+    pub max_lost: u8,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+            max_lost: 2,
+        }
+    }
+}
+
+/// Detects a lost session and schedules new handshakes with a backoff.
+///
+/// The session is lost when the server answers with a Timeout, with an error code of
+/// [Reconnect::is_session_error] or when [ReconnectConfig::max_lost] messages in a row
+/// are not acknowledged. The driver then closes the session, waits [Reconnect::next_delay]
+/// and starts a new handshake, the unacknowledged messages are sent again in the new session.
+/// The delay doubles with every failed handshake, with up to a half of random jitter,
+/// so devices do not reconnect in sync after a server restart.
+pub struct Reconnect {
+    config: ReconnectConfig,
+    attempts: u8,
+    lost_in_row: u8,
+    rng: XorShift,
+}
+
+impl Reconnect {
+    /// Create the policy, `seed` randomizes the jitter, e.g. the device id.
+    pub fn new(config: ReconnectConfig, seed: u32) -> Self {
+        Reconnect {
+            config,
+            attempts: 0,
+            lost_in_row: 0,
+            rng: XorShift::new(seed),
+        }
+    }
+
+    /// Check if the server error means that it does not have the session state,
+    /// e.g. it was restarted and got a session id of another session.
+    /// [ErrorCode::DecryptFailed] is not one: the server answers any forged message with it.
+    pub fn is_session_error(code: ErrorCode) -> bool {
+        matches!(code, ErrorCode::BadState)
+    }
+
+    /// A message was acknowledged, the session works.
+    pub fn on_delivered(&mut self) {
+        self.lost_in_row = 0;
+    }
+
+    /// A message was not acknowledged after all retries.
+    /// Returns `true` if too many messages are lost and the session should be reestablished.
+    pub fn on_lost(&mut self) -> bool {
+        self.lost_in_row = self.lost_in_row.saturating_add(1);
+        self.lost_in_row >= self.config.max_lost
+    }
+
+    /// Delay before the next handshake attempt, `None` if the attempts are exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .config
+            .max_attempts
+            .is_some_and(|max_attempts| self.attempts >= max_attempts)
+        {
+            return None;
+        }
+
+        let backoff = u32::from(self.attempts).min(16);
+        self.attempts = self.attempts.saturating_add(1);
+        let delay = self
+            .config
+            .initial_delay
+            .saturating_mul(1 << backoff)
+            .min(self.config.max_delay);
+
+        // wait at least a half of the delay
+        let half = u64::try_from(delay.as_millis() / 2).unwrap_or(u64::MAX);
+        let jitter = u64::from(self.rng.next_u32()) % (half + 1);
+        Some(delay - Duration::from_millis(jitter))
+    }
+
+    /// The handshake succeeded, the backoff starts again from the initial delay.
+    pub fn on_connected(&mut self) {
+        self.attempts = 0;
+        self.lost_in_row = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn back_off_and_give_up() {
+        let second = Duration::from_secs(1);
+        let mut reconnect = Reconnect::new(
+            ReconnectConfig {
+                initial_delay: second,
+                max_delay: 3 * second,
+                max_attempts: Some(3),
+                max_lost: 2,
+            },
+            42,
+        );
+
+        // a single lost message is not enough
+        assert!(!reconnect.on_lost());
+        reconnect.on_delivered();
+        assert!(!reconnect.on_lost());
+        assert!(reconnect.on_lost());
+
+        // the delay doubles up to the limit, jitter takes up to a half of it
+        let delays = [second, 2 * second, 3 * second];
+        for delay in delays {
+            let next = reconnect.next_delay().unwrap();
+            assert!(next <= delay && next >= delay / 2, "{next:?} for {delay:?}");
+        }
+        assert_eq!(reconnect.next_delay(), None);
+
+        reconnect.on_connected();
+        assert!(reconnect.next_delay().unwrap() <= second);
+    }
+}
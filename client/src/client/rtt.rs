@@ -0,0 +1,122 @@
+use core::time::Duration;
+
+use super::random::XorShift;
+
+/// Retransmission timeout estimator (RFC 6298).
+///
+/// Keeps the smoothed round-trip time (SRTT) and its variation (RTTVAR) and derives the
+/// retransmission timeout (RTO) from them. Every retransmission of a message doubles
+/// its timeout. Samples of retransmitted messages must not be taken (Karn's algorithm),
+/// because it is unknown which transmission was acknowledged.
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
+    // timer jitter
+    rng: XorShift,
+}
+
+impl RttEstimator {
+    /// RTO before the first sample.
+    pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+    pub const MIN_RTO: Duration = Duration::from_millis(200);
+    pub const MAX_RTO: Duration = Duration::from_secs(60);
+    /// Clock granularity.
+    const GRANULARITY: Duration = Duration::from_millis(1);
+    /// Maximal number of RTO doublings.
+    const MAX_BACKOFF: u32 = 6;
+
+    /// Create an estimator, `seed` randomizes the jitter, e.g. the device id.
+    pub fn new(seed: u32) -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: Self::INITIAL_RTO,
+            min_rto: Self::MIN_RTO,
+            max_rto: Self::MAX_RTO,
+            rng: XorShift::new(seed),
+        }
+    }
+
+    /// Limit the RTO, e.g. for links with a known latency.
+    pub fn with_bounds(mut self, min_rto: Duration, max_rto: Duration) -> Self {
+        self.min_rto = min_rto;
+        self.max_rto = max_rto.max(min_rto);
+        self.rto = self.rto.clamp(self.min_rto, self.max_rto);
+        self
+    }
+
+    /// Add a round-trip sample of a message which was sent exactly once.
+    pub fn on_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+
+        let srtt = self.srtt.unwrap_or(rtt);
+        self.rto =
+            (srtt + (self.rttvar * 4).max(Self::GRANULARITY)).clamp(self.min_rto, self.max_rto);
+    }
+
+    /// Current RTO without backoff.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Timeout for a message sent `retries` times before: the RTO doubled for every
+    /// retransmission, with up to 25% of random jitter, so devices which lost packets
+    /// at the same time do not retry in sync.
+    pub fn timeout(&mut self, retries: u8) -> Duration {
+        let backoff = u32::from(retries).min(Self::MAX_BACKOFF);
+        let rto = (self.rto * (1 << backoff)).min(self.max_rto);
+        let jitter_range = u64::try_from(rto.as_millis() / 4).unwrap_or(u64::MAX);
+        let jitter = u64::from(self.rng.next_u32()) % (jitter_range + 1);
+        rto + Duration::from_millis(jitter)
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_and_back_off() {
+        let mut rtt = RttEstimator::new(42);
+        assert_eq!(rtt.rto(), RttEstimator::INITIAL_RTO);
+
+        // first sample: srtt = r, rttvar = r / 2, rto = r + 4 * rttvar
+        rtt.on_sample(Duration::from_millis(100));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.rto(), Duration::from_millis(300));
+
+        rtt.on_sample(Duration::from_millis(200));
+        assert_eq!(rtt.srtt(), Some(Duration::from_micros(112_500)));
+        assert_eq!(rtt.rttvar(), Duration::from_micros(62_500));
+
+        assert_eq!(rtt.rto(), Duration::from_micros(362_500));
+
+        // the second retransmission waits four times longer
+        let backoff = Duration::from_millis(1_450);
+        let timeout = rtt.timeout(2);
+        assert!(timeout >= backoff && timeout <= backoff + backoff / 4);
+        assert!(rtt.timeout(u8::MAX) <= RttEstimator::MAX_RTO + RttEstimator::MAX_RTO / 4);
+    }
+}
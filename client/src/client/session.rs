@@ -1,15 +1,31 @@
+use core::time::Duration;
+
 use heapless::Vec;
 use shared_lib::{
-    command::{EncodedCommand, Information, COMMAND_SIZE, PACKET_SIZE},
-    error::SerializeError,
+    ack::sequence_newer,
+    close::CloseReason,
+    command::{Buffer, Payload, COMMAND_SIZE, PACKET_SIZE},
+    error::{ErrorCode, SerializeError},
+    fragment::{Fragment, Fragmenter, FRAGMENT_DATA_SIZE},
+    handshake::HandshakeParams,
     network::{MessageType, PackedHeader},
-    parse_command, serialize, write_command,
+    packet::PacketView,
+    qos::Qos,
+    serialize,
+    telemetry::{Information, Reading},
+    write_command_header,
 };
 use thiserror::Error;
 
-const ENC_PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+pub(crate) const ENC_PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+
+/// Size of the authentication tag appended by ChaCha20-Poly1305.
+pub const TAG_SIZE: usize = 16;
+
+/// Maximal size of the handshake payload sent by the server.
+const HANDSHAKE_PAYLOAD_SIZE: usize = 64;
 
-type OutputVec = Vec<u8, PACKET_SIZE>;
+pub type OutputVec = Vec<u8, PACKET_SIZE>;
 pub type Result<T> = core::result::Result<T, Error>;
 
 pub struct Session {
@@ -17,7 +33,16 @@ pub struct Session {
     session_id: u16,
     last_server_message_id: u16,
     sequence_id: u16,
+    message_id: u16,
+    // id of the last message with a delivery guarantee, not reset by a new handshake
+    qos_message_id: u32,
+    // requested before the handshake, accepted by the server after it
+    keepalive_secs: u16,
     snow_state: Noise,
+    // scratch buffer for decryption, snow does not decrypt in place
+    read_buf: Buffer,
+    // plaintext of the last sent message, encrypted again after the session expired
+    pending: Vec<u8, COMMAND_SIZE>,
 }
 
 /// The current state of the session.
@@ -36,6 +61,20 @@ pub enum Error {
     Serialization(#[from] SerializeError),
     #[error("Incorrect session state")]
     IncorrectState,
+    #[error("Message does not belong to the session")]
+    ForeignMessage,
+    #[error("Unexpected message type: {0:?}")]
+    UnexpectedMessage(MessageType),
+    #[error("Message does not acknowledge the request")]
+    UnexpectedAck,
+    #[error("Acknowledgement fields are not authenticated")]
+    ForgedAck,
+    #[error("Server error: {0}")]
+    Server(ErrorCode),
+    #[error("Send window is full")]
+    WindowFull,
+    #[error("Sequence numbers of the session are exhausted, a new handshake is needed")]
+    SequenceExhausted,
 }
 
 impl Session {
@@ -45,27 +84,84 @@ impl Session {
             session_id: 0,
             last_server_message_id: 0,
             sequence_id: 0,
+            message_id: 0,
+            qos_message_id: 0,
+            keepalive_secs: 0,
             snow_state: Noise::None,
+            read_buf: [0u8; COMMAND_SIZE],
+            pending: Vec::new(),
         }
     }
 
+    /// Sequence number of the last sent message.
+    pub fn sequence_id(&self) -> u16 {
+        self.sequence_id
+    }
+
+    /// Ask for the keepalive interval in the next handshake, the server might adjust it.
+    /// Without it the server default is used.
+    pub fn set_keepalive_interval(&mut self, interval: Duration) {
+        self.keepalive_secs = u16::try_from(interval.as_secs()).unwrap_or(u16::MAX);
+    }
+
+    /// Check if the handshake is complete and the session is not closed.
+    pub fn is_open(&self) -> bool {
+        matches!(self.snow_state, Noise::TransportState(_))
+    }
+
+    /// Forget the session keys, e.g. when the close is not acknowledged.
+    /// The next handshake opens a new session.
+    pub fn close(&mut self) {
+        self.session_id = 0;
+        self.last_server_message_id = 0;
+        self.snow_state = Noise::None;
+    }
+
+    /// Keepalive interval accepted by the server, or the requested one before the handshake.
+    /// Zero if the server default is not known yet.
+    pub fn keepalive_interval(&self) -> Duration {
+        Duration::from_secs(u64::from(self.keepalive_secs))
+    }
+
     pub fn initiate_handshake(&mut self) -> Result<OutputVec> {
-        self.sequence_id += 1;
+        let mut output_vec = OutputVec::new();
+        let _ = output_vec.resize_default(PACKET_SIZE);
+
+        let handshake_size = self.initiate_handshake_in_place(output_vec.as_mut_slice())?;
 
-        let mut initiator = snow::Builder::new(ENC_PATTERN.parse().unwrap()).build_initiator()?;
+        output_vec.truncate(handshake_size);
+        Ok(output_vec)
+    }
+
+    /// Write the handshake request directly into `buf`. Returns the packet size.
+    ///
+    /// The noise message is written at the payload offset, no intermediate buffers are used.
+    pub fn initiate_handshake_in_place(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < PacketView::PAYLOAD_OFFSET {
+            return Err(Error::Serialization(SerializeError::TooBig));
+        }
 
-        // create new handshake
-        let mut handshake_buf = [0u8; COMMAND_SIZE];
-        let handshake_buf_size = initiator.write_message(&[], &mut handshake_buf)?;
+        // a new session has new keys, so the sequence might start over
+        self.sequence_id = self.sequence_id.checked_add(1).unwrap_or(1);
 
-        let handshake_command = EncodedCommand {
-            size: handshake_buf_size,
-            buf: handshake_buf,
+        // the handshake state is built in the session, not in a local variable
+        self.snow_state = Noise::HandshakeState(
+            snow::Builder::new(ENC_PATTERN.parse().unwrap()).build_initiator()?,
+        );
+        let Noise::HandshakeState(ref mut initiator) = self.snow_state else {
+            return Err(Error::IncorrectState);
         };
 
+        // create new handshake with the requested session parameters
+        let mut params = [0u8; HandshakeParams::SIZE];
+        HandshakeParams {
+            keepalive_secs: self.keepalive_secs,
+        }
+        .serialize(&mut params)?;
+        let handshake_size =
+            initiator.write_message(&params, &mut buf[PacketView::PAYLOAD_OFFSET..])?;
+
         // serialize that into message
-        let mut output_vec = OutputVec::new();
-        let _ = output_vec.resize_default(PACKET_SIZE);
         let handshake_header = PackedHeader::new(
             MessageType::HandshakeRequest,
             self.device_id,
@@ -73,91 +169,405 @@ impl Session {
             self.sequence_id,
             0,
         );
-
-        let handshake_size = write_command(
+        Ok(write_command_header(
             &handshake_header,
-            &handshake_command,
-            output_vec.as_mut_slice(),
-        )?;
-
-        self.snow_state = Noise::HandshakeState(initiator);
-
-        output_vec.truncate(handshake_size);
-        Ok(output_vec)
+            handshake_size,
+            buf,
+        )?)
     }
 
-    pub fn receive_handshake(&mut self, hrh: PackedHeader, server_body: &[u8]) -> Result<()> {
+    /// Process the server handshake response and open the session.
+    ///
+    /// Responses of another device or to another handshake are rejected with an error
+    /// and the handshake is still pending, so the caller might wait for the right one.
+    pub fn receive_handshake(&mut self, packet: &PacketView) -> Result<()> {
+        let hrh = packet.header();
         log::info!("Handshake response header: {:?}", hrh);
-        let server_body = parse_command(server_body)?;
+        let server_body = packet.command();
         log::info!("Handshake response body: {:?}", server_body);
 
+        if hrh.device_id != self.device_id {
+            return Err(Error::ForeignMessage);
+        }
+        match hrh.message_type {
+            MessageType::HandshakeResponse => {}
+            MessageType::Error => return Err(Error::Server(ErrorCode::parse(packet.payload()))),
+            other => return Err(Error::UnexpectedMessage(other)),
+        }
+        if hrh.session_id == 0 {
+            return Err(Error::ForeignMessage);
+        }
+        if !hrh.acknowledges(self.sequence_id) {
+            return Err(Error::UnexpectedAck);
+        }
+        let Noise::HandshakeState(ref mut initiator) = self.snow_state else {
+            return Err(Error::IncorrectState);
+        };
+
+        // read handshake message and finish handshake, a forged response keeps it pending
+        let mut read_buf = [0u8; HANDSHAKE_PAYLOAD_SIZE];
+        let read_size = initiator.read_message(server_body.buf, &mut read_buf)?;
+        let accepted = HandshakeParams::parse(&read_buf[..read_size])?;
+        let Noise::HandshakeState(initiator) = self.snow_state.take() else {
+            return Err(Error::IncorrectState);
+        };
+        self.keepalive_secs = accepted.keepalive_secs;
+        self.snow_state = Noise::TransportState(initiator.into_stateless_transport_mode()?);
         self.session_id = hrh.session_id;
         self.last_server_message_id = hrh.sequence;
+        Ok(())
+    }
 
-        assert_eq!(hrh.message_type, MessageType::HandshakeResponse);
-        assert_eq!(hrh.device_id, self.device_id);
-        assert_ne!(hrh.session_id, 0);
-        assert_eq!(hrh.ack, 1);
-
-        let mut read_buf = [0u8; COMMAND_SIZE];
-        let noise_state = self.snow_state.take();
-        // read handshake message and finish handshake
-        if let Noise::HandshakeState(mut initiator) = noise_state {
-            initiator.read_message(&server_body.buf[..server_body.size], &mut read_buf)?;
-            self.snow_state = Noise::TransportState(initiator.into_stateless_transport_mode()?);
-            Ok(())
-        } else {
-            Err(Error::IncorrectState)
+    /// Build an encrypted packet with the information from the default sensor, ready to be sent.
+    pub fn send_information(&mut self, information: &Information) -> Result<OutputVec> {
+        self.send_reading(&Reading::new(*information))
+    }
+
+    /// Build an encrypted packet with the reading, ready to be sent.
+    pub fn send_reading(&mut self, reading: &Reading) -> Result<OutputVec> {
+        self.send_readings(core::slice::from_ref(reading))
+    }
+
+    /// Build an encrypted packet with all readings, ready to be sent.
+    pub fn send_readings(&mut self, readings: &[Reading]) -> Result<OutputVec> {
+        self.send_with(|buf| serialize::write_readings_payload(readings, buf))
+    }
+
+    /// Build an encrypted packet with opaque application-defined data, ready to be sent.
+    pub fn send_application(&mut self, data: &[u8]) -> Result<OutputVec> {
+        self.send_with(|buf| serialize::write_payload(&Payload::Application(data), buf))
+    }
+
+    /// Build an encrypted packet with any payload, ready to be sent.
+    pub fn send_payload(&mut self, payload: &Payload) -> Result<OutputVec> {
+        self.send_with(|buf| serialize::write_payload(payload, buf))
+    }
+
+    /// Build an encrypted keepalive packet, the server answers with [Payload::Pong] and the same token.
+    pub fn send_ping(&mut self, token: u32) -> Result<OutputVec> {
+        self.send_payload(&Payload::Ping(token))
+    }
+
+    /// Build an encrypted close packet, the server answers with the same [Payload::Close]
+    /// and removes the session. The session is closed when the answer is received.
+    pub fn send_close(&mut self, reason: CloseReason) -> Result<OutputVec> {
+        self.send_payload(&Payload::Close(reason))
+    }
+
+    /// Acknowledge the close the server sent with `sequence` in the session `session_id`,
+    /// so the server stops sending it again. The session keys are gone after the close,
+    /// so the acknowledgement is a bare [MessageType::Ack] header without payload.
+    pub fn acknowledge_close(&self, session_id: u16, sequence: u16) -> Result<OutputVec> {
+        let header = PackedHeader::new(
+            MessageType::Ack,
+            self.device_id,
+            session_id,
+            self.sequence_id,
+            sequence,
+        );
+        let mut output_vec = OutputVec::new();
+        let _ = output_vec.resize_default(PacketView::PAYLOAD_OFFSET);
+        write_command_header(&header, 0, &mut output_vec)?;
+        Ok(output_vec)
+    }
+
+    /// Encrypt the last sent message again for the current session, e.g. after the server
+    /// answered it with [MessageType::Timeout] and a new handshake was made.
+    /// The message gets a new sequence, its content including the message id of
+    /// [Qos::ExactlyOnce] messages is kept.
+    pub fn resend_pending(&mut self) -> Result<OutputVec> {
+        if self.pending.is_empty() {
+            return Err(Error::IncorrectState);
+        }
+
+        let pending = core::mem::take(&mut self.pending);
+        let result = self.send_plaintext(&pending);
+        if result.is_err() {
+            self.pending = pending;
         }
+        result
     }
 
-    pub fn temperature_message(&mut self) -> Result<OutputVec> {
-        if let Noise::TransportState(ref mut noise) = self.snow_state {
-            self.sequence_id += 1;
-            // prepare temperature information
-            let mut tmp_buf = [0u8; COMMAND_SIZE];
-            let information = Information::Temparature(25f32);
-            let inf_size = usize::from(serialize::write_non_encrypted(&information, &mut tmp_buf)?);
+    /// Serialized payload of the last sent message, e.g. to keep it until it is
+    /// acknowledged and replay it with [Session::send_plaintext] in a new session.
+    pub fn pending_plaintext(&self) -> &[u8] {
+        &self.pending
+    }
 
-            let header = PackedHeader::new(
-                MessageType::EncryptedMessage,
-                self.device_id,
-                self.session_id,
-                self.sequence_id,
-                self.last_server_message_id,
-            );
+    /// Build an encrypted packet with an already serialized payload, ready to be sent.
+    pub fn send_plaintext(&mut self, plaintext: &[u8]) -> Result<OutputVec> {
+        self.send_with(|buf| {
+            buf.get_mut(..plaintext.len())
+                .ok_or(SerializeError::TooBig)?
+                .copy_from_slice(plaintext);
+            Ok(plaintext.len())
+        })
+    }
 
-            // encrypt message
-            let mut enc_buf = [0u8; COMMAND_SIZE];
-            let enc_size =
-                noise.write_message(header.nonce(), &tmp_buf[..inf_size], &mut enc_buf)?;
+    /// Build an encrypted packet with the payload and its delivery guarantee, ready to be sent.
+    ///
+    /// [Qos::AtMostOnce] packets are sent once, the server does not acknowledge them.
+    /// Other packets are retransmitted until acknowledged, [Qos::ExactlyOnce] retransmissions
+    /// must reuse the packet, so the server recognizes the message id.
+    pub fn send_with_qos(&mut self, qos: Qos, payload: &Payload) -> Result<OutputVec> {
+        self.qos_message_id = self.qos_message_id.wrapping_add(1);
+        let message_id = self.qos_message_id;
+        self.send_with(|buf| serialize::write_qos_payload(qos, message_id, payload, buf))
+    }
 
-            // send temperature information
-            let temp_command = EncodedCommand {
-                size: enc_size,
-                buf: enc_buf,
-            };
+    /// Id of the last message sent with [Session::send_with_qos].
+    pub fn qos_message_id(&self) -> u32 {
+        self.qos_message_id
+    }
+
+    /// Continue message ids after a restart, e.g. from a value saved in flash.
+    /// The server drops exactly-once messages with recently used ids as duplicates.
+    pub fn set_qos_message_id(&mut self, message_id: u32) {
+        self.qos_message_id = message_id;
+    }
 
-            let mut output_vec = OutputVec::new();
-            let _ = output_vec.resize_default(PACKET_SIZE);
+    /// Split application data which does not fit into one datagram into fragments.
+    /// Each fragment is sent with [Session::send_fragment] and acknowledged separately.
+    pub fn fragment_application<'a>(&mut self, data: &'a [u8]) -> Result<Fragmenter<'a>> {
+        self.message_id = self.message_id.wrapping_add(1);
+        Ok(Fragmenter::new(
+            self.message_id,
+            Payload::APPLICATION,
+            data,
+            FRAGMENT_DATA_SIZE,
+        )?)
+    }
 
-            let temperature_size =
-                write_command(&header, &temp_command, output_vec.as_mut_slice())?;
+    /// Build an encrypted packet with the fragment, ready to be sent.
+    pub fn send_fragment(&mut self, fragment: &Fragment) -> Result<OutputVec> {
+        self.send_with(|buf| serialize::write_payload(&Payload::Fragment(*fragment), buf))
+    }
 
-            output_vec.truncate(temperature_size);
-            Ok(output_vec)
-        } else {
-            Err(Error::IncorrectState)
+    fn send_with(
+        &mut self,
+        write_plaintext: impl FnOnce(&mut [u8]) -> core::result::Result<usize, SerializeError>,
+    ) -> Result<OutputVec> {
+        let mut output_vec = OutputVec::new();
+        let _ = output_vec.resize_default(PACKET_SIZE);
+
+        let packet_size = self.encode_with(write_plaintext, output_vec.as_mut_slice())?;
+
+        output_vec.truncate(packet_size);
+        Ok(output_vec)
+    }
+
+    /// Serialize, encrypt and frame the payload in place in `buf`. Returns the packet size.
+    ///
+    /// The plaintext is serialized at the payload offset, moved to the end of `buf` and then
+    /// encrypted back into the payload region, so `buf` must hold at least
+    /// [in_place_buffer_size] of the serialized payload. [PACKET_SIZE] is always enough
+    /// for a few dozens of readings.
+    ///
+    /// **Memory use**
+    ///
+    /// The packet is built in `buf` only, this function keeps no packet-sized buffer on
+    /// the stack, just the header, the nonce and the cipher state of snow. The session
+    /// itself is not small, keep it in a static rather than in a task stack:
+    /// - [Session] is 3688 bytes: `read_buf` for decryption and `pending` for
+    ///   [Session::resend_pending] are [COMMAND_SIZE] (1400 bytes) each;
+    /// - [Session::initiate_handshake_in_place] builds the snow `HandshakeState`
+    ///   (856 bytes) in the session, [Session::receive_handshake] moves it through the
+    ///   stack once to turn it into the transport state;
+    /// - the `send_*` functions return an [OutputVec] (1512 bytes) by value, use this
+    ///   function and [Session::encode_readings] on small stacks instead.
+    ///
+    /// The sizes are `core::mem::size_of` values on x86_64. No stack budget is guaranteed:
+    /// the stack frames of this function, of snow and of the cipher are not measured. The crate
+    /// does not build for thumbv7 yet (the default resolver of snow needs `getrandom` with std),
+    /// so measure the stack on the target once it does, e.g. with `-Z emit-stack-sizes`.
+    pub fn encode_message(&mut self, payload: &Payload, buf: &mut [u8]) -> Result<usize> {
+        self.encode_with(|plain| serialize::write_payload(payload, plain), buf)
+    }
+
+    /// Same as [Session::encode_message] for telemetry readings.
+    pub fn encode_readings(&mut self, readings: &[Reading], buf: &mut [u8]) -> Result<usize> {
+        self.encode_with(
+            |plain| serialize::write_readings_payload(readings, plain),
+            buf,
+        )
+    }
+
+    fn encode_with(
+        &mut self,
+        write_plaintext: impl FnOnce(&mut [u8]) -> core::result::Result<usize, SerializeError>,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let Noise::TransportState(ref noise) = self.snow_state else {
+            return Err(Error::IncorrectState);
+        };
+
+        if buf.len() < PacketView::PAYLOAD_OFFSET {
+            return Err(Error::Serialization(SerializeError::TooBig));
+        }
+        // the sequence is a part of the nonce, it must never repeat within the session
+        let sequence_id = self
+            .sequence_id
+            .checked_add(1)
+            .ok_or(Error::SequenceExhausted)?;
+
+        // serialize the plaintext and move it to the end of the buffer
+        let plain_size = write_plaintext(&mut buf[PacketView::PAYLOAD_OFFSET..])?;
+        if buf.len() < in_place_buffer_size(plain_size) {
+            return Err(Error::Serialization(SerializeError::TooBig));
         }
+        let plaintext = &buf[PacketView::PAYLOAD_OFFSET..PacketView::PAYLOAD_OFFSET + plain_size];
+        self.pending.clear();
+        self.pending
+            .extend_from_slice(plaintext)
+            .map_err(|_| Error::Serialization(SerializeError::TooBig))?;
+        let plain_offset = buf.len() - plain_size;
+        buf.copy_within(
+            PacketView::PAYLOAD_OFFSET..PacketView::PAYLOAD_OFFSET + plain_size,
+            plain_offset,
+        );
+
+        self.sequence_id = sequence_id;
+        let header = PackedHeader::new(
+            MessageType::EncryptedMessage,
+            self.device_id,
+            self.session_id,
+            self.sequence_id,
+            self.last_server_message_id,
+        );
+
+        // encrypt message into the payload region, the regions do not overlap
+        let (packet, plaintext) = buf.split_at_mut(plain_offset);
+        let enc_size = noise.write_message(
+            header.nonce(),
+            plaintext,
+            &mut packet[PacketView::PAYLOAD_OFFSET..],
+        )?;
+
+        Ok(write_command_header(&header, enc_size, buf)?)
     }
 
-    pub fn receive_ack(&mut self, hrh: PackedHeader, _: &[u8]) -> Result<()> {
-        assert_eq!(hrh.message_type, MessageType::Ack);
-        assert_eq!(hrh.device_id, self.device_id);
-        assert_eq!(hrh.session_id, self.session_id);
-        assert_eq!(hrh.ack, self.sequence_id);
+    /// Decrypt the server message in place and parse its payload.
+    pub fn decrypt_payload<'p>(&mut self, packet: &'p mut PacketView) -> Result<Payload<'p>> {
+        self.decrypt_in_place(packet)?;
+        Ok(serialize::parse_payload(packet.payload())?)
+    }
 
-        self.last_server_message_id = hrh.sequence;
+    /// Check if the header belongs to the session: to the device and the current session,
+    /// or to the pending handshake. Drivers use it to skip stale or foreign datagrams.
+    pub fn accepts(&self, header: &PackedHeader) -> bool {
+        if header.device_id != self.device_id {
+            return false;
+        }
+
+        match (&self.snow_state, header.message_type) {
+            // the session id is assigned by the response
+            (Noise::HandshakeState(_), MessageType::HandshakeResponse | MessageType::Error) => {
+                header.session_id != 0
+            }
+            (
+                Noise::TransportState(_),
+                MessageType::Ack
+                | MessageType::Error
+                | MessageType::Timeout
+                | MessageType::EncryptedMessage,
+            ) => header.session_id == self.session_id,
+            _ => false,
+        }
+    }
+
+    /// Process the server acknowledgement.
+    /// Returns the decrypted payload if the server answered with data, e.g. a [Payload::BlockAck].
+    /// The session is closed if the server acknowledged [Payload::Close].
+    /// An Error message of the server is returned as [Error::Server] with its code.
+    ///
+    /// The ack fields of the header are trusted only if the encrypted payload starts
+    /// with the same fields, otherwise the ack is rejected with [Error::ForgedAck].
+    ///
+    /// With several messages in flight the ack might be for any of them,
+    /// use [crate::SendWindow] to match acknowledgements to messages.
+    pub fn receive_ack<'p>(&mut self, mut packet: PacketView<'p>) -> Result<Option<Payload<'p>>> {
+        let hrh = packet.header();
+        if !self.is_open() {
+            return Err(Error::IncorrectState);
+        }
+        if hrh.device_id != self.device_id || hrh.session_id != self.session_id {
+            return Err(Error::ForeignMessage);
+        }
+        match hrh.message_type {
+            MessageType::Ack => {}
+            MessageType::Error => return Err(Error::Server(ErrorCode::parse(packet.payload()))),
+            other => return Err(Error::UnexpectedMessage(other)),
+        }
+
+        let (sequence, ack_fields) = (hrh.sequence, hrh.ack_fields());
+
+        self.decrypt_in_place(&mut packet)?;
+        let Some(plaintext) = packet.into_payload().strip_prefix(&ack_fields[..]) else {
+            return Err(Error::ForgedAck);
+        };
+        if sequence_newer(sequence, self.last_server_message_id) {
+            self.last_server_message_id = sequence;
+        }
+        if plaintext.is_empty() {
+            return Ok(None);
+        }
+
+        let payload = serialize::parse_payload(plaintext)?;
+        if let Payload::Close(_) = payload {
+            self.close();
+        }
+        Ok(Some(payload))
+    }
+
+    /// Process the server answer to a message for an unknown or expired session.
+    /// The session is closed, the last message is sent again with [Session::resend_pending]
+    /// after a new handshake.
+    ///
+    /// The answer is not authenticated, so it is accepted only for the current session
+    /// and the messages which were sent already.
+    pub fn receive_timeout(&mut self, header: &PackedHeader) -> Result<()> {
+        if header.message_type != MessageType::Timeout
+            || header.device_id != self.device_id
+            || header.session_id != self.session_id
+            || sequence_newer(header.ack, self.sequence_id)
+        {
+            return Err(Error::ForeignMessage);
+        }
+
+        self.close();
+        Ok(())
+    }
+
+    /// Process a message sent by the server on its own, not as an acknowledgement.
+    /// Returns the decrypted payload, the session is closed if it is [Payload::Close].
+    pub fn receive_message<'p>(&mut self, mut packet: PacketView<'p>) -> Result<Payload<'p>> {
+        let header = packet.header();
+        if header.message_type != MessageType::EncryptedMessage
+            || header.device_id != self.device_id
+            || header.session_id != self.session_id
+        {
+            return Err(Error::ForeignMessage);
+        }
+
+        self.decrypt_in_place(&mut packet)?;
+        let payload = serialize::parse_payload(packet.into_payload())?;
+        if let Payload::Close(_) = payload {
+            self.close();
+        }
+        Ok(payload)
+    }
+
+    fn decrypt_in_place(&mut self, packet: &mut PacketView) -> Result<()> {
+        let Noise::TransportState(ref noise) = self.snow_state else {
+            return Err(Error::IncorrectState);
+        };
+
+        let read_buf = &mut self.read_buf;
+        packet.decrypt_in_place(|nonce, payload| {
+            let size = noise.read_message(nonce, payload, read_buf)?;
+            payload[..size].copy_from_slice(&read_buf[..size]);
+            Ok::<_, Error>(size)
+        })?;
         Ok(())
     }
 }
@@ -168,21 +578,210 @@ impl Noise {
     }
 }
 
-/// Parse a buffer to header and command. Buffer expected size should be at least PackedHeader::SIZE.
-///
-/// Returns a tuple with the header and the command buffer.
-///
-/// **Arguments**
-/// - `buf`: The buffer to parse.
-pub fn parse_request(buf: &[u8]) -> Result<(PackedHeader, OutputVec)> {
-    let header: PackedHeader = PackedHeader::try_deserialize(buf)?;
-    let mut buffer = OutputVec::new();
-    if buffer
-        .extend_from_slice(&buf[PackedHeader::SIZE..buf.len()])
-        .is_ok()
-    {
-        Ok((header, buffer))
-    } else {
-        Err(Error::Serialization(SerializeError::TooBig))
+/// Buffer size required to encrypt a plaintext of `plain_size` bytes in place:
+/// header, length prefix, encrypted payload with the tag and the plaintext itself.
+pub const fn in_place_buffer_size(plain_size: usize) -> usize {
+    PacketView::PAYLOAD_OFFSET + plain_size + TAG_SIZE + plain_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::test_server::TestServer;
+    use shared_lib::{command::EncodedCommand, write_command};
+
+    fn packet(header: &PackedHeader, body: &[u8], buf: &mut [u8; PACKET_SIZE]) -> usize {
+        write_command(header, &EncodedCommand::new(body), buf).unwrap()
+    }
+
+    #[test]
+    fn reject_mismatching_messages() {
+        let mut session = Session::new(7);
+        session.initiate_handshake().unwrap();
+        let mut buf = [0u8; PACKET_SIZE];
+
+        // another device, another type and a stale response are rejected
+        let cases = [
+            (
+                PackedHeader::new(MessageType::HandshakeResponse, 8, 1, 1, 1),
+                Error::ForeignMessage,
+            ),
+            (
+                PackedHeader::new(MessageType::Ack, 7, 1, 1, 1),
+                Error::UnexpectedMessage(MessageType::Ack),
+            ),
+            (
+                PackedHeader::new(MessageType::HandshakeResponse, 7, 1, 1, 0),
+                Error::UnexpectedAck,
+            ),
+        ];
+        for (header, expected) in cases {
+            let size = packet(&header, &[], &mut buf);
+            let error = session
+                .receive_handshake(&PacketView::parse(&mut buf[..size]).unwrap())
+                .unwrap_err();
+            assert_eq!(
+                core::mem::discriminant(&error),
+                core::mem::discriminant(&expected)
+            );
+        }
+
+        // the handshake is still pending and the server error is reported with its code
+        let header = PackedHeader::new(MessageType::Error, 7, 1, 1, 1);
+        assert!(session.accepts(&header));
+        let size = packet(&header, &[u8::from(ErrorCode::BadState)], &mut buf);
+        assert!(matches!(
+            session.receive_handshake(&PacketView::parse(&mut buf[..size]).unwrap()),
+            Err(Error::Server(ErrorCode::BadState))
+        ));
+
+        // acknowledgements are not accepted before the session is open
+        let header = PackedHeader::new(MessageType::Ack, 7, 0, 1, 1);
+        assert!(!session.accepts(&header));
+        let size = packet(&header, &[], &mut buf);
+        assert!(matches!(
+            session.receive_ack(PacketView::parse(&mut buf[..size]).unwrap()),
+            Err(Error::IncorrectState)
+        ));
+    }
+
+    #[test]
+    fn ignore_truncated_and_garbage_datagrams() {
+        let mut session = Session::new(7);
+        let request = session.initiate_handshake().unwrap();
+        let mut server = TestServer::new(1);
+        let mut buf = [0u8; PACKET_SIZE];
+
+        // a response without the length prefix does not reach the session
+        let header = PackedHeader::new(MessageType::HandshakeResponse, 7, 1, 1, 1);
+        packet(&header, &[], &mut buf);
+        assert!(PacketView::parse(&mut buf[..PackedHeader::SIZE]).is_err());
+
+        // a forged response with garbage instead of the noise message keeps the handshake
+        for body in [&[][..], &[0xa5; 7], &[0xa5; 48]] {
+            let size = packet(&header, body, &mut buf);
+            let forged = PacketView::parse(&mut buf[..size]).unwrap();
+            assert!(session.accepts(forged.header()));
+            assert!(session.receive_handshake(&forged).is_err());
+        }
+        let mut response = server.answer(&request).unwrap();
+        session
+            .receive_handshake(&PacketView::parse(&mut response).unwrap())
+            .unwrap();
+        assert!(session.is_open());
+
+        // garbage in the open session is rejected, the session stays open
+        for message_type in [MessageType::Ack, MessageType::EncryptedMessage] {
+            let header = PackedHeader::new(message_type, 7, 1, 2, 1);
+            for body in [&[][..], &[0xa5; 1], &[0xa5; 40]] {
+                let size = packet(&header, body, &mut buf);
+                let forged = PacketView::parse(&mut buf[..size]).unwrap();
+                let result = match message_type {
+                    MessageType::Ack => session.receive_ack(forged).map(|_| ()),
+                    _ => session.receive_message(forged).map(|_| ()),
+                };
+                assert!(result.is_err());
+            }
+        }
+        assert!(session.is_open());
+    }
+
+    #[test]
+    fn keep_qos_message_id_on_resend() {
+        let mut session = Session::new(7);
+        let request = session.initiate_handshake().unwrap();
+        let mut response = TestServer::new(1).answer(&request).unwrap();
+        session
+            .receive_handshake(&PacketView::parse(&mut response).unwrap())
+            .unwrap();
+
+        let qos_message =
+            |session: &Session| match serialize::parse_payload(session.pending_plaintext()) {
+                Ok(Payload::Qos(message)) => (message.qos, message.message_id),
+                other => panic!("unexpected payload: {other:?}"),
+            };
+
+        // every message gets a new id
+        session
+            .send_with_qos(Qos::AtLeastOnce, &Payload::Application(b"a"))
+            .unwrap();
+        assert_eq!(qos_message(&session), (Qos::AtLeastOnce, 1));
+        let sent = session
+            .send_with_qos(Qos::ExactlyOnce, &Payload::Application(b"b"))
+            .unwrap();
+        assert_eq!(qos_message(&session), (Qos::ExactlyOnce, 2));
+
+        // the resent message gets a new sequence, but keeps its id
+        let sequence = session.sequence_id();
+        let resent = session.resend_pending().unwrap();
+        assert_ne!(resent, sent);
+        assert_eq!(session.sequence_id(), sequence.wrapping_add(1));
+        assert_eq!(qos_message(&session), (Qos::ExactlyOnce, 2));
+
+        // ids continue after a restart
+        session.set_qos_message_id(1000);
+        session
+            .send_with_qos(Qos::ExactlyOnce, &Payload::Application(b"c"))
+            .unwrap();
+        assert_eq!(qos_message(&session), (Qos::ExactlyOnce, 1001));
+    }
+
+    #[test]
+    fn refuse_to_wrap_sequence() {
+        let mut session = Session::new(7);
+        let request = session.initiate_handshake().unwrap();
+        let mut response = TestServer::new(1).answer(&request).unwrap();
+        session
+            .receive_handshake(&PacketView::parse(&mut response).unwrap())
+            .unwrap();
+
+        // the last sequence is used, the next message would reuse a nonce
+        session.sequence_id = u16::MAX - 1;
+        session.send_ping(1).unwrap();
+        assert_eq!(session.sequence_id(), u16::MAX);
+        assert!(matches!(
+            session.send_ping(2),
+            Err(Error::SequenceExhausted)
+        ));
+        assert_eq!(session.sequence_id(), u16::MAX);
+
+        // a new session starts the sequence over
+        let request = session.initiate_handshake().unwrap();
+        assert_eq!(session.sequence_id(), 1);
+        let mut response = TestServer::new(2).answer(&request).unwrap();
+        session
+            .receive_handshake(&PacketView::parse(&mut response).unwrap())
+            .unwrap();
+        session.send_ping(2).unwrap();
+        assert_eq!(session.sequence_id(), 2);
+    }
+
+    #[test]
+    fn round_trip_in_place_message() {
+        let mut server = TestServer::new(1);
+        let mut session = Session::new(7);
+        let mut buf = [0u8; PACKET_SIZE];
+        let size = session.initiate_handshake_in_place(&mut buf).unwrap();
+        let mut response = server.answer(&buf[..size]).unwrap();
+        session
+            .receive_handshake(&PacketView::parse(&mut response).unwrap())
+            .unwrap();
+
+        // the smallest buffer which fits the message, one byte less is rejected
+        let payload = Payload::Application(b"in place");
+        let required = in_place_buffer_size(1 + b"in place".len());
+        assert!(matches!(
+            session.encode_message(&payload, &mut buf[..required - 1]),
+            Err(Error::Serialization(SerializeError::TooBig))
+        ));
+        let size = session
+            .encode_message(&payload, &mut buf[..required])
+            .unwrap();
+
+        // the server decrypts it in place and echoes the payload
+        let mut answer = server.answer(&buf[..size]).unwrap();
+        let answer = PacketView::parse(&mut answer).unwrap();
+        assert!(answer.header().acknowledges(session.sequence_id()));
+        assert_eq!(session.receive_ack(answer).unwrap(), Some(payload));
     }
 }
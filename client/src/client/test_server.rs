@@ -0,0 +1,181 @@
+#[cfg(any(feature = "embedded-nal", feature = "embedded-nal-async"))]
+extern crate std;
+
+use shared_lib::{
+    ack::SequenceWindow,
+    close::CloseReason,
+    command::{EncodedCommand, Payload, COMMAND_SIZE, PACKET_SIZE},
+    handshake::HandshakeParams,
+    network::{MessageType, PackedHeader},
+    packet::PacketView,
+    serialize, write_command,
+};
+
+use super::session::{OutputVec, ENC_PATTERN};
+
+/// Server side of the protocol for tests, without any I/O.
+///
+/// Answers the handshake and acknowledges every message, application data and close are
+/// echoed back in the acknowledgement after its authenticated ack fields. The headers are
+/// built like the server does: the answered request in `sequence`, the received sequences
+/// in `ack` and `ack_bits`. Messages of other sessions are answered with a Timeout,
+/// like after a server restart.
+pub struct TestServer {
+    session_id: u16,
+    // sequence of the messages sent by the server on its own
+    sequence: u16,
+    received: SequenceWindow,
+    transport: Option<snow::StatelessTransportState>,
+}
+
+impl TestServer {
+    pub fn new(session_id: u16) -> Self {
+        TestServer {
+            session_id,
+            sequence: 0,
+            received: SequenceWindow::new(),
+            transport: None,
+        }
+    }
+
+    /// Answer a datagram of the client, `None` if it is not answered.
+    pub fn answer(&mut self, datagram: &[u8]) -> Option<OutputVec> {
+        let mut buf = [0u8; PACKET_SIZE];
+        let buf = &mut buf[..datagram.len()];
+        buf.copy_from_slice(datagram);
+        let mut packet = PacketView::parse(buf).ok()?;
+        let request = &PackedHeader::try_deserialize(datagram).ok()?;
+
+        let mut body = [0u8; COMMAND_SIZE];
+        let (header, size) = match request.message_type {
+            MessageType::HandshakeRequest => {
+                let mut responder = snow::Builder::new(ENC_PATTERN.parse().unwrap())
+                    .build_responder()
+                    .unwrap();
+                responder
+                    .read_message(packet.command().buf, &mut body)
+                    .unwrap();
+                let mut params = [0u8; HandshakeParams::SIZE];
+                HandshakeParams { keepalive_secs: 30 }
+                    .serialize(&mut params)
+                    .unwrap();
+                let size = responder.write_message(&params, &mut body).unwrap();
+                self.transport = Some(responder.into_stateless_transport_mode().unwrap());
+                self.received = SequenceWindow::new();
+                self.received.insert(request.sequence);
+                (self.header(MessageType::HandshakeResponse, request), size)
+            }
+            MessageType::EncryptedMessage if request.session_id == self.session_id => {
+                // decrypt in place like the server does
+                let transport = self.transport.as_ref()?;
+                let mut read_buf = [0u8; COMMAND_SIZE];
+                let plaintext = packet
+                    .decrypt_in_place(|nonce, payload| {
+                        let size = transport.read_message(nonce, payload, &mut read_buf)?;
+                        payload[..size].copy_from_slice(&read_buf[..size]);
+                        Ok::<_, snow::Error>(size)
+                    })
+                    .ok()?;
+                self.received.insert(request.sequence);
+                let header = self.header(MessageType::Ack, request);
+                // the ack fields are authenticated with the echoed payload
+                let mut answer = [0u8; COMMAND_SIZE];
+                answer[..PackedHeader::ACK_FIELDS_SIZE].copy_from_slice(&header.ack_fields());
+                let echo_size = match serialize::parse_payload(plaintext) {
+                    Ok(payload @ (Payload::Application(_) | Payload::Close(_))) => {
+                        serialize::write_payload(
+                            &payload,
+                            &mut answer[PackedHeader::ACK_FIELDS_SIZE..],
+                        )
+                        .ok()?
+                    }
+                    _ => 0,
+                };
+                let size = transport
+                    .write_message(
+                        header.nonce(),
+                        &answer[..PackedHeader::ACK_FIELDS_SIZE + echo_size],
+                        &mut body,
+                    )
+                    .ok()?;
+                (header, size)
+            }
+            MessageType::EncryptedMessage => (
+                PackedHeader::new(
+                    MessageType::Timeout,
+                    request.device_id,
+                    request.session_id,
+                    0,
+                    request.sequence,
+                ),
+                0,
+            ),
+            _ => return None,
+        };
+
+        let mut answer = OutputVec::new();
+        let _ = answer.resize_default(PACKET_SIZE);
+        let size = write_command(&header, &EncodedCommand::new(&body[..size]), &mut answer).ok()?;
+        answer.truncate(size);
+        Some(answer)
+    }
+
+    /// Close the session like the server does: an encrypted [Payload::Close] with a sequence
+    /// of its own. Returns the sequence, the device acknowledges it, and the datagram.
+    pub fn close(&mut self, device_id: u32, reason: CloseReason) -> (u16, OutputVec) {
+        self.sequence = self.sequence.wrapping_add(1);
+        let header = PackedHeader::new(
+            MessageType::EncryptedMessage,
+            device_id,
+            self.session_id,
+            self.sequence,
+            self.received.ack(),
+        )
+        .with_ack_bits(self.received.ack_bits());
+
+        let mut plaintext = [0u8; COMMAND_SIZE];
+        let plain_size = serialize::write_payload(&Payload::Close(reason), &mut plaintext).unwrap();
+        let mut body = [0u8; COMMAND_SIZE];
+        let size = self
+            .transport
+            .as_ref()
+            .unwrap()
+            .write_message(header.nonce(), &plaintext[..plain_size], &mut body)
+            .unwrap();
+
+        let mut datagram = OutputVec::new();
+        let _ = datagram.resize_default(PACKET_SIZE);
+        let size =
+            write_command(&header, &EncodedCommand::new(&body[..size]), &mut datagram).unwrap();
+        datagram.truncate(size);
+        (self.sequence, datagram)
+    }
+
+    fn header(&self, message_type: MessageType, request: &PackedHeader) -> PackedHeader {
+        PackedHeader::new(
+            message_type,
+            request.device_id,
+            self.session_id,
+            request.sequence,
+            self.received.ack(),
+        )
+        .with_ack_bits(self.received.ack_bits())
+    }
+}
+
+/// Answer `count` datagrams with the test server on a std UDP socket in its own thread.
+#[cfg(any(feature = "embedded-nal", feature = "embedded-nal-async"))]
+pub fn spawn_udp(count: usize) -> (core::net::SocketAddr, std::thread::JoinHandle<()>) {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let answering = std::thread::spawn(move || {
+        let mut server = TestServer::new(1);
+        let mut buf = [0u8; PACKET_SIZE];
+        for _ in 0..count {
+            let (size, client) = socket.recv_from(&mut buf).unwrap();
+            let answer = server.answer(&buf[..size]).unwrap();
+            socket.send_to(&answer, client).unwrap();
+        }
+    });
+    (addr, answering)
+}
@@ -0,0 +1,30 @@
+use core::{ops::Add, time::Duration};
+
+/// Monotonic timestamp in milliseconds from an arbitrary origin.
+/// The client has no clock of its own, the caller provides the current time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const fn from_millis(millis: u64) -> Self {
+        Instant(millis)
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// Time elapsed since `earlier`, zero if `earlier` is in the future.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        let millis = u64::try_from(rhs.as_millis()).unwrap_or(u64::MAX);
+        Instant(self.0.saturating_add(millis))
+    }
+}
@@ -0,0 +1,107 @@
+use shared_lib::{
+    command::Payload,
+    transfer::{Block, BlockAck, UploadQuery},
+};
+
+/// Block-wise upload of a named blob, resumable after a new session.
+///
+/// The upload starts with [Upload::query], the server answers with the next block
+/// it expects (zero for a new upload), so an interrupted upload continues from
+/// the last stored block. Each block is acknowledged with a [BlockAck].
+pub struct Upload<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    block_size: u16,
+    next_block: u32,
+    complete: bool,
+}
+
+impl<'a> Upload<'a> {
+    pub fn new(name: &'a str, data: &'a [u8], block_size: u16) -> Self {
+        Upload {
+            name,
+            data,
+            block_size: block_size.max(1),
+            next_block: 0,
+            complete: false,
+        }
+    }
+
+    /// Query for the upload state on the server.
+    pub fn query(&self) -> Payload<'a> {
+        Payload::UploadQuery(UploadQuery {
+            name: self.name,
+            block_size: self.block_size,
+        })
+    }
+
+    /// Number of blocks in the upload, an empty blob is sent as one empty block.
+    pub fn block_count(&self) -> u32 {
+        self.data
+            .len()
+            .div_ceil(usize::from(self.block_size))
+            .max(1) as u32
+    }
+
+    /// The next block to send, `None` once the upload is completed.
+    pub fn next_block(&self) -> Option<Block<'a>> {
+        if self.complete || self.next_block >= self.block_count() {
+            return None;
+        }
+
+        let block_size = usize::from(self.block_size);
+        let start = self.next_block as usize * block_size;
+        let end = (start + block_size).min(self.data.len());
+        Some(Block {
+            name: self.name,
+            index: self.next_block,
+            block_size: self.block_size,
+            last: self.next_block + 1 == self.block_count(),
+            data: &self.data[start..end],
+        })
+    }
+
+    /// Apply the server acknowledgement of a query or a block.
+    pub fn on_ack(&mut self, ack: &BlockAck) {
+        self.complete = ack.complete;
+        self.next_block = ack.next_block.min(self.block_count());
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_from_server_state() {
+        let data = [7u8; 1000];
+        let mut upload = Upload::new("device.log", &data, 300);
+        assert_eq!(upload.block_count(), 4);
+
+        // the server already stored two blocks
+        upload.on_ack(&BlockAck {
+            next_block: 2,
+            complete: false,
+        });
+        let block = upload.next_block().unwrap();
+        assert_eq!((block.index, block.last, block.data.len()), (2, false, 300));
+
+        upload.on_ack(&BlockAck {
+            next_block: 3,
+            complete: false,
+        });
+        let block = upload.next_block().unwrap();
+        assert_eq!((block.index, block.last, block.data.len()), (3, true, 100));
+
+        upload.on_ack(&BlockAck {
+            next_block: 4,
+            complete: true,
+        });
+        assert!(upload.is_complete());
+        assert!(upload.next_block().is_none());
+    }
+}
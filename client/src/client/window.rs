@@ -0,0 +1,391 @@
+use core::time::Duration;
+
+use heapless::Vec;
+use shared_lib::{ack::sequence_newer, network::PackedHeader};
+
+use super::{rtt::RttEstimator, session::OutputVec, time::Instant};
+
+/// Message sent and not acknowledged yet.
+struct InFlight {
+    sequence: u16,
+    packet: OutputVec,
+    sent_at: Instant,
+    deadline: Instant,
+    retries: u8,
+}
+
+/// Delivery report of a message in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The server acknowledged the message.
+    Delivered(u16),
+    /// The message was not acknowledged after all retries.
+    Lost(u16),
+}
+
+/// Action required by an expired message timer.
+#[derive(Debug, PartialEq)]
+pub enum Timeout<'a> {
+    /// Send the packet again.
+    Retransmit(&'a [u8]),
+    /// Retries are exhausted, the message is dropped from the window.
+    Lost(u16),
+}
+
+/// Sender statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Messages sent for the first time.
+    pub sent: u32,
+    pub retransmitted: u32,
+    pub delivered: u32,
+    pub lost: u32,
+    /// Smoothed round-trip time, `None` before the first sample.
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    /// The current retransmission timeout.
+    pub rto: Duration,
+    /// Congestion window, messages allowed in flight by the sender.
+    pub cwnd: u16,
+    /// Receive window advertised by the server.
+    pub peer_window: u16,
+}
+
+/// Keeps up to `N` messages in flight and retransmits only the lost ones.
+///
+/// Messages are acknowledged selectively with the header ack bitmap, so `N` should not be
+/// bigger than [shared_lib::ack::ACK_BITS]. Every message has its own timer derived from
+/// the [RttEstimator], the caller waits for acknowledgements until [SendWindow::deadline]
+/// and then calls [SendWindow::poll_timeout] to get the packets to retransmit.
+///
+/// New messages are sent only while [SendWindow::can_send] allows it: the number of messages
+/// in flight is limited by the receive window advertised by the server and by a congestion
+/// window. The congestion window grows by one message per delivered message up to the slow
+/// start threshold and by one message per window of delivered messages after it. A lost
+/// message halves it once per window of messages in flight (AIMD).
+pub struct SendWindow<const N: usize> {
+    in_flight: Vec<InFlight, N>,
+    rtt: RttEstimator,
+    max_retries: u8,
+    stats: Stats,
+    cwnd: u16,
+    ssthresh: u16,
+    // messages delivered since the last congestion avoidance increase
+    delivered_in_round: u16,
+    // messages sent before this sequence do not halve the congestion window again
+    recovery_end: Option<u16>,
+    next_sequence: u16,
+    peer_window: u16,
+}
+
+impl<const N: usize> SendWindow<N> {
+    pub fn new(rtt: RttEstimator, max_retries: u8) -> Self {
+        SendWindow {
+            in_flight: Vec::new(),
+            rtt,
+            max_retries,
+            stats: Stats::default(),
+            cwnd: 1,
+            ssthresh: N as u16,
+            delivered_in_round: 0,
+            recovery_end: None,
+            next_sequence: 0,
+            peer_window: PackedHeader::UNLIMITED_WINDOW,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.in_flight.is_full()
+    }
+
+    /// Check if the message with the sequence is waiting for its acknowledgement.
+    pub fn contains(&self, sequence: u16) -> bool {
+        self.in_flight
+            .iter()
+            .any(|message| message.sequence == sequence)
+    }
+
+    /// Number of messages allowed in flight by the congestion and the receive window.
+    /// One message is always allowed, it probes if a closed receive window opened again.
+    pub fn limit(&self) -> usize {
+        usize::from(self.cwnd.min(self.peer_window)).clamp(1, N)
+    }
+
+    /// Check if a new message might be sent.
+    pub fn can_send(&self) -> bool {
+        self.in_flight.len() < self.limit()
+    }
+
+    /// Track a sent message. Returns the packet back if the window is full.
+    #[allow(clippy::result_large_err)]
+    pub fn push(
+        &mut self,
+        sequence: u16,
+        packet: OutputVec,
+        now: Instant,
+    ) -> core::result::Result<(), OutputVec> {
+        if self.in_flight.is_full() {
+            return Err(packet);
+        }
+
+        let deadline = now + self.rtt.timeout(0);
+        self.stats.sent += 1;
+        self.next_sequence = sequence.wrapping_add(1);
+        self.in_flight
+            .push(InFlight {
+                sequence,
+                packet,
+                sent_at: now,
+                deadline,
+                retries: 0,
+            })
+            .map_err(|in_flight| in_flight.packet)
+    }
+
+    /// Remove all messages acknowledged by the header and report them as delivered.
+    /// Round-trip time is sampled only from messages sent once (Karn's algorithm).
+    /// The receive window is taken from the header. Returns the number of delivered messages.
+    pub fn on_ack(
+        &mut self,
+        header: &PackedHeader,
+        now: Instant,
+        mut on_delivery: impl FnMut(Delivery),
+    ) -> usize {
+        let before = self.in_flight.len();
+        let rtt = &mut self.rtt;
+        self.in_flight.retain(|message| {
+            let acked = header.acknowledges(message.sequence);
+            if acked {
+                if message.retries == 0 {
+                    rtt.on_sample(now.saturating_duration_since(message.sent_at));
+                }
+                on_delivery(Delivery::Delivered(message.sequence));
+            }
+            !acked
+        });
+
+        let delivered = before - self.in_flight.len();
+        self.stats.delivered += delivered as u32;
+        if delivered > 0 {
+            self.peer_window = header.window;
+            for _ in 0..delivered {
+                self.grow();
+            }
+        }
+        delivered
+    }
+
+    /// Drop the message answered with an error, it is not sent again.
+    /// Returns `false` if the message is not in flight.
+    pub fn remove(&mut self, sequence: u16) -> bool {
+        let Some(index) = self
+            .in_flight
+            .iter()
+            .position(|message| message.sequence == sequence)
+        else {
+            return false;
+        };
+        self.in_flight.remove(index);
+        true
+    }
+
+    /// Time when the earliest message timer expires, `None` if nothing is in flight.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.in_flight.iter().map(|message| message.deadline).min()
+    }
+
+    /// Handle the earliest expired timer, `None` if no timer is expired.
+    /// The retransmitted message timer is restarted with the doubled timeout.
+    pub fn poll_timeout(&mut self, now: Instant) -> Option<Timeout<'_>> {
+        let (index, _) = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.deadline <= now)
+            .min_by_key(|(_, message)| message.deadline)?;
+
+        self.on_loss(self.in_flight[index].sequence);
+        if self.in_flight[index].retries >= self.max_retries {
+            let message = self.in_flight.remove(index);
+            self.stats.lost += 1;
+            return Some(Timeout::Lost(message.sequence));
+        }
+
+        let message = &mut self.in_flight[index];
+        message.retries += 1;
+        message.deadline = now + self.rtt.timeout(message.retries);
+        self.stats.retransmitted += 1;
+        Some(Timeout::Retransmit(&message.packet))
+    }
+
+    /// Drop all messages in flight, e.g. when they were encrypted for a lost session.
+    /// The RTT estimation and the congestion window are kept.
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+
+    /// Sender statistics with the current RTT estimation.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            srtt: self.rtt.srtt(),
+            rttvar: self.rtt.rttvar(),
+            rto: self.rtt.rto(),
+            cwnd: self.cwnd,
+            peer_window: self.peer_window,
+            ..self.stats
+        }
+    }
+
+    /// Additive increase for a delivered message.
+    fn grow(&mut self) {
+        if self.cwnd >= N as u16 {
+            return;
+        }
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd += 1;
+            return;
+        }
+
+        self.delivered_in_round += 1;
+        if self.delivered_in_round >= self.cwnd {
+            self.delivered_in_round = 0;
+            self.cwnd += 1;
+        }
+    }
+
+    /// Multiplicative decrease for a lost message. Messages sent before the previous
+    /// decrease were lost in the same congestion event and do not decrease it again.
+    fn on_loss(&mut self, sequence: u16) {
+        if self
+            .recovery_end
+            .is_some_and(|end| sequence_newer(end, sequence))
+        {
+            return;
+        }
+
+        self.ssthresh = (self.cwnd / 2).max(1);
+        self.cwnd = self.ssthresh;
+        self.delivered_in_round = 0;
+        self.recovery_end = Some(self.next_sequence);
+    }
+
+    /// RTT estimator, e.g. to continue with the same estimation after the window is dropped.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// RTT estimator to sample or time other messages, e.g. the handshake.
+    pub fn rtt_mut(&mut self) -> &mut RttEstimator {
+        &mut self.rtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_lib::network::MessageType;
+
+    #[test]
+    fn selective_ack_and_retransmit() {
+        let rtt =
+            RttEstimator::new(7).with_bounds(Duration::from_millis(100), Duration::from_secs(10));
+        let mut window = SendWindow::<4>::new(rtt, 1);
+        for sequence in 1..=4 {
+            let packet = OutputVec::from_slice(&[sequence as u8]).unwrap();
+            window
+                .push(sequence, packet, Instant::from_millis(0))
+                .unwrap();
+        }
+        assert!(window.is_full());
+
+        // 4 and 2 are acknowledged, 1 and 3 are lost
+        let header = PackedHeader::new(MessageType::Ack, 1, 1, 4, 4).with_ack_bits(0b10);
+        let mut delivered = [0u16; 4];
+        let mut count = 0;
+        window.on_ack(&header, Instant::from_millis(100), |delivery| {
+            if let Delivery::Delivered(sequence) = delivery {
+                delivered[count] = sequence;
+                count += 1;
+            }
+        });
+        assert_eq!(&delivered[..count], &[2, 4]);
+        assert_eq!(window.stats().srtt, Some(Duration::from_millis(100)));
+
+        // timers were started with the initial RTO of 1 second
+        assert_eq!(window.poll_timeout(Instant::from_millis(999)), None);
+        let now = Instant::from_millis(2_000);
+        assert!(matches!(
+            window.poll_timeout(now),
+            Some(Timeout::Retransmit(_))
+        ));
+        assert!(matches!(
+            window.poll_timeout(now),
+            Some(Timeout::Retransmit(_))
+        ));
+        assert_eq!(window.poll_timeout(now), None);
+
+        let later = window.deadline().unwrap();
+        assert!(later > now);
+        assert!(matches!(window.poll_timeout(later), Some(Timeout::Lost(_))));
+
+        let stats = window.stats();
+        assert_eq!(
+            (stats.sent, stats.retransmitted, stats.delivered, stats.lost),
+            (4, 2, 2, 1)
+        );
+    }
+
+    #[test]
+    fn congestion_and_receive_window() {
+        let rtt =
+            RttEstimator::new(7).with_bounds(Duration::from_millis(100), Duration::from_secs(10));
+        let mut window = SendWindow::<8>::new(rtt, 3);
+        let mut sequence = 0;
+        let mut send = |window: &mut SendWindow<8>, now: u64| {
+            sequence += 1;
+            let packet = OutputVec::from_slice(&[sequence as u8]).unwrap();
+            window
+                .push(sequence, packet, Instant::from_millis(now))
+                .unwrap();
+            sequence
+        };
+
+        // slow start: every delivered message allows one more in flight
+        assert_eq!(window.limit(), 1);
+        let first = send(&mut window, 0);
+        assert!(!window.can_send());
+        let ack = PackedHeader::new(MessageType::Ack, 1, 1, first, first);
+        window.on_ack(&ack, Instant::from_millis(10), |_| {});
+        assert_eq!(window.limit(), 2);
+        send(&mut window, 10);
+        let b = send(&mut window, 10);
+        assert!(!window.can_send());
+        let ack = PackedHeader::new(MessageType::Ack, 1, 1, b, b).with_ack_bits(1);
+        window.on_ack(&ack, Instant::from_millis(20), |_| {});
+        assert_eq!((window.stats().cwnd, window.limit()), (4, 4));
+
+        // the server advertises a smaller receive window
+        let c = send(&mut window, 20);
+        let ack = PackedHeader::new(MessageType::Ack, 1, 1, c, c).with_window(2);
+        window.on_ack(&ack, Instant::from_millis(30), |_| {});
+        assert_eq!((window.stats().cwnd, window.limit()), (5, 2));
+
+        // losses in the same window halve the congestion window once
+        for _ in 0..4 {
+            send(&mut window, 30);
+        }
+        let now = Instant::from_millis(10_000);
+        while let Some(Timeout::Retransmit(_)) = window.poll_timeout(now) {}
+        assert_eq!(window.stats().cwnd, 2);
+        assert_eq!(window.stats().retransmitted, 4);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use client::{Event, OutputVec};
+use shared_lib::command::{Payload, PACKET_SIZE};
+
+/// Blocking driver of the [client::Connection] over a UDP socket.
+///
+/// The connection keeps the protocol state, the driver only moves datagrams between
+/// the socket and the connection and waits for the connection timers.
+pub struct Driver<const N: usize> {
+    socket: UdpSocket,
+    connection: client::Connection<N>,
+    started: Instant,
+}
+
+impl<const N: usize> Driver<N> {
+    pub fn new(socket: UdpSocket, connection: client::Connection<N>) -> Self {
+        Driver {
+            socket,
+            connection,
+            started: Instant::now(),
+        }
+    }
+
+    /// Session to build messages with.
+    pub fn session(&mut self) -> &mut client::Session {
+        self.connection.session()
+    }
+
+    pub fn stats(&self) -> client::Stats {
+        self.connection.stats()
+    }
+
+    /// Open a new session, failed handshakes are retried with the reconnect backoff.
+    pub fn connect(&mut self) -> std::io::Result<()> {
+        let now = self.now();
+        self.connection
+            .connect(now)
+            .map_err(std::io::Error::other)?;
+        self.run(|event| match event {
+            Event::Connected => Some(Ok(())),
+            Event::Failed => Some(Err(handshake_failed())),
+            _ => None,
+        })
+    }
+
+    /// Forget the session without telling the server.
+    pub fn abort(&mut self) {
+        self.connection.abort();
+    }
+
+    /// Send a message without waiting for the acknowledgement, e.g. a fire-and-forget message.
+    pub fn send(
+        &mut self,
+        build: impl FnOnce(&mut client::Session) -> Result<OutputVec, client::Error>,
+    ) -> std::io::Result<()> {
+        let now = self.now();
+        self.connection
+            .send_unreliable(now, build)
+            .map_err(std::io::Error::other)?;
+        self.flush()
+    }
+
+    /// Send a message and wait for the acknowledgement.
+    /// Returns the payload if the server answered with data.
+    ///
+    /// A message lost once is sent again, the connection reconnects when the session is lost.
+    pub fn request(
+        &mut self,
+        build: impl FnOnce(&mut client::Session) -> Result<OutputVec, client::Error>,
+    ) -> std::io::Result<Option<Payload<'_>>> {
+        self.wait_can_send()?;
+        let now = self.now();
+        let mut handle = self
+            .connection
+            .send(now, build)
+            .map_err(std::io::Error::other)?;
+
+        loop {
+            let answered = self.run(|event| match event {
+                Event::Delivered(delivered) if delivered == handle => Some(Ok(Some(false))),
+                Event::Response(answered) if answered == handle => Some(Ok(Some(true))),
+                Event::Rejected(rejected, code) if rejected == handle => {
+                    Some(Err(std::io::Error::other(client::Error::Server(code))))
+                }
+                Event::Lost(lost) if lost == handle => Some(Ok(None)),
+                Event::Failed => Some(Err(handshake_failed())),
+                _ => None,
+            })?;
+            match answered {
+                Some(true) => return Ok(self.connection.payload()),
+                Some(false) => return Ok(None),
+                None => {
+                    log::warn!("Message is not acknowledged, sending it again");
+                    self.wait_can_send()?;
+                    let now = self.now();
+                    handle = self
+                        .connection
+                        .send(now, |session| session.resend_pending())
+                        .map_err(std::io::Error::other)?;
+                }
+            }
+        }
+    }
+
+    /// Send messages built from `items` with up to `N` messages in flight, limited by
+    /// the congestion and receive windows. Every message is reported with `on_delivery`:
+    /// [Event::Delivered], [Event::Response], [Event::Rejected] or [Event::Lost].
+    pub fn send_windowed<T>(
+        &mut self,
+        items: impl IntoIterator<Item = T>,
+        mut build: impl FnMut(&mut client::Session, T) -> Result<OutputVec, client::Error>,
+        mut on_delivery: impl FnMut(Event),
+    ) -> std::io::Result<()> {
+        let mut items = items.into_iter();
+        let mut has_more = true;
+        let mut in_flight = HashSet::new();
+        loop {
+            // fill the window
+            while has_more && self.connection.can_send() {
+                let Some(item) = items.next() else {
+                    has_more = false;
+                    break;
+                };
+                let now = self.now();
+                let handle = self
+                    .connection
+                    .send(now, |session| build(session, item))
+                    .map_err(std::io::Error::other)?;
+                in_flight.insert(handle);
+            }
+            if !has_more && in_flight.is_empty() {
+                return Ok(());
+            }
+
+            self.step(|event| {
+                match event {
+                    Event::Delivered(handle)
+                    | Event::Response(handle)
+                    | Event::Rejected(handle, _)
+                    | Event::Lost(handle)
+                        if in_flight.remove(&handle) =>
+                    {
+                        on_delivery(event)
+                    }
+                    Event::Failed => return Some(Err(handshake_failed())),
+                    _ => {}
+                }
+                None::<std::io::Result<()>>
+            })?;
+        }
+    }
+
+    /// Send the readings kept while the server was not reachable, until the queue is empty.
+    pub fn drain<S, const M: usize>(
+        &mut self,
+        queue: &mut client::OfflineQueue<S, M>,
+    ) -> std::io::Result<()>
+    where
+        S: client::QueueStorage,
+        S::Error: Send + Sync + 'static,
+    {
+        loop {
+            let now = self.now();
+            queue
+                .poll_send(&mut self.connection, now)
+                .map_err(std::io::Error::other)?;
+            if queue.is_empty() {
+                return Ok(());
+            }
+
+            self.step(|event| match event {
+                Event::Failed => Some(Err(handshake_failed())),
+                event => match queue.handle_event(&event) {
+                    Ok(_) => None::<std::io::Result<()>>,
+                    Err(error) => Some(Err(std::io::Error::other(error))),
+                },
+            })?;
+        }
+    }
+
+    /// Run the connection until `on_event` returns a result.
+    fn run<T>(
+        &mut self,
+        mut on_event: impl FnMut(Event) -> Option<std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        loop {
+            if let Some(result) = self.step(&mut on_event)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Wait until a new message might be sent, e.g. while the session is reopened.
+    fn wait_can_send(&mut self) -> std::io::Result<()> {
+        while !self.connection.can_send() {
+            self.step(|event| match event {
+                Event::Failed => Some(Err(handshake_failed())),
+                _ => None::<std::io::Result<()>>,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Send the packets of the connection, report its events and wait for a datagram
+    /// or the next timer.
+    fn step<T>(
+        &mut self,
+        mut on_event: impl FnMut(Event) -> Option<std::io::Result<T>>,
+    ) -> std::io::Result<Option<T>> {
+        self.flush()?;
+        let mut polled = false;
+        while let Some(event) = self.connection.poll_event() {
+            if let Some(result) = on_event(event) {
+                return result.map(Some);
+            }
+            polled = true;
+        }
+        if polled {
+            // the events might allow the caller to continue, e.g. to send more messages
+            return Ok(None);
+        }
+
+        let Some(deadline) = self.connection.poll_timeout() else {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "Connection is idle",
+            ));
+        };
+        let wait = deadline.saturating_duration_since(self.now());
+        self.socket
+            .set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+
+        let mut read_buf = [0u8; PACKET_SIZE];
+        match self.socket.recv(&mut read_buf) {
+            Ok(n) => {
+                let now = self.now();
+                self.connection.handle_datagram(&read_buf[..n], now);
+            }
+            // the server might not be reachable yet, the timers retry
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+                ) => {}
+            Err(e) => return Err(e),
+        }
+        let now = self.now();
+        self.connection.handle_timeout(now);
+        Ok(None)
+    }
+
+    /// Send all packets of the connection.
+    fn flush(&mut self) -> std::io::Result<()> {
+        while let Some(packet) = self.connection.poll_transmit() {
+            match self.socket.send(&packet) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    log::warn!("Server is not reachable");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> client::Instant {
+        client::Instant::from_millis(self.started.elapsed().as_millis() as u64)
+    }
+}
+
+pub fn unexpected_response() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, "Unexpected response")
+}
+
+fn handshake_failed() -> std::io::Error {
+    std::io::Error::new(ErrorKind::TimedOut, "Handshake attempts are exhausted")
+}
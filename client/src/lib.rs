@@ -2,5 +2,23 @@
 #![forbid(unsafe_code)]
 
 mod client;
-pub use client::session::parse_request;
-pub use client::session::Session;
+#[cfg(feature = "tokio")]
+pub use client::async_client::{Client, ClientError, Message, Messages};
+pub use client::batch::{Batcher, MAX_BATCH_SIZE};
+pub use client::connection::{Connection, Event, Handle};
+pub use client::firmware::{Download, DownloadError, FirmwareStorage, Progress};
+pub use client::fragment::Reassembler;
+pub use client::keepalive::Keepalive;
+#[cfg(feature = "embedded-nal")]
+pub use client::nal::NalTransport;
+#[cfg(feature = "embedded-nal-async")]
+pub use client::nal_async::{AsyncTransport, Clock};
+pub use client::offline::{
+    Entry, Eviction, MemoryStorage, OfflineQueue, QueueError, QueueStorage, RECORD_SIZE,
+};
+pub use client::reconnect::{Reconnect, ReconnectConfig};
+pub use client::rtt::RttEstimator;
+pub use client::session::{in_place_buffer_size, Error, OutputVec, Session, TAG_SIZE};
+pub use client::time::Instant;
+pub use client::upload::Upload;
+pub use client::window::{Delivery, SendWindow, Stats, Timeout};
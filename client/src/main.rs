@@ -1,11 +1,20 @@
-use std::{net::UdpSocket, time::Duration};
+use std::{
+    net::UdpSocket,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use heapless::Vec;
-use shared_lib::command::PACKET_SIZE;
+use shared_lib::{
+    close::CloseReason,
+    command::Payload,
+    qos::Qos,
+    telemetry::{Information, Quality, Reading},
+};
 
 const SERVER_ADDR: &str = "127.0.0.1:8080";
 
-mod channel;
+mod driver;
+mod storage;
 
 fn main() -> std::io::Result<()> {
     env_logger::init();
@@ -20,50 +29,189 @@ fn main() -> std::io::Result<()> {
 
     // run the client (no_std)
     let device_id: u32 = 1234567890;
-    let mut client = client::Session::new(device_id);
-
-    let handshake_init = client
-        .initiate_handshake()
-        .expect("Failed to initiate handshake");
-
-    // send and wait for the server's response
-    let mut read_buf: Vec<u8, PACKET_SIZE> = Vec::new();
-    channel::send_and_wait(
-        &socket,
-        &handshake_init,
-        1,
-        &mut read_buf,
-        Duration::from_secs(1),
-        5,
-    )?;
+    let mut session = client::Session::new(device_id);
+    session.set_keepalive_interval(Duration::from_secs(30));
+    let rtt = client::RttEstimator::new(device_id);
+    // a lost session is reestablished and the unacknowledged messages are sent again
+    let reconnect = client::Reconnect::new(
+        client::ReconnectConfig {
+            max_attempts: Some(8),
+            ..Default::default()
+        },
+        device_id,
+    );
+
+    let connection = client::Connection::<4>::new(session, rtt, reconnect, 5);
+    let mut driver = driver::Driver::new(socket, connection);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() as u32)
+        .unwrap_or_default();
+    let readings = [
+        Information::Temperature(25f32),
+        Information::Humidity(40f32),
+        Information::AirPressure(1013.25f32),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(sensor_id, value)| Reading {
+        sensor_id: sensor_id as u16,
+        timestamp,
+        quality: Quality::GOOD,
+        value,
+    });
+
+    // readings taken while the server is not reachable are kept until the next run
+    let mut queue = client::OfflineQueue::<_, 32>::open(
+        storage::FileQueueStorage::open(Path::new("device"))?,
+        client::Eviction::OldestFirst,
+    )
+    .map_err(std::io::Error::other)?;
+    if let Err(error) = driver.connect() {
+        for reading in readings {
+            queue.push(reading, 0).map_err(std::io::Error::other)?;
+        }
+        log::warn!(
+            "Server is not reachable, {} readings are stored",
+            queue.len()
+        );
+        return Err(error);
+    }
+    driver.drain(&mut queue)?;
+    log::info!(
+        "Keepalive interval: {:?}",
+        driver.session().keepalive_interval()
+    );
+
+    // collect readings into one batch
+    let started = std::time::Instant::now();
+    let now = || client::Instant::from_millis(started.elapsed().as_millis() as u64);
+    // message ids must not repeat after a restart, seed them from the clock
+    driver.session().set_qos_message_id(timestamp);
+
+    let mut batcher = client::Batcher::<8>::new(Duration::from_secs(1));
+    for reading in readings {
+        batcher
+            .push(reading, now())
+            .expect("Batch should fit all readings");
+    }
 
-    let (hrh, body) = client::parse_request(&read_buf).expect("Failed to parse ack");
-    client
-        .receive_handshake(hrh, &body)
-        .expect("Failed to process received handshake");
-
-    // prepare the temperature request
-    log::info!("Sending encrypted temperature request");
-    let temperature = client
-        .temperature_message()
-        .expect("Failed to create temperature message");
-
-    // send and wait for the server's response
-    channel::send_and_wait(
-        &socket,
-        temperature.as_slice(),
-        2,
-        &mut read_buf,
-        Duration::from_secs(1),
-        5,
+    // prepare the telemetry request
+    log::info!("Sending encrypted batch of {} readings", batcher.len());
+    driver.request(|session| {
+        batcher
+            .flush(session)
+            .map(|batch| batch.expect("Batch should not be empty"))
+    })?;
+
+    // pipeline counter readings, several messages are in flight at the same time
+    let mut delivered = 0;
+    driver.send_windowed(
+        1..=16,
+        |session, counter| session.send_information(&Information::Counter(counter)),
+        |delivery| match delivery {
+            client::Event::Lost(handle) => log::warn!("Message {handle:?} is lost"),
+            _ => delivered += 1,
+        },
     )?;
+    log::info!("Delivered {delivered} of 16 pipelined readings");
+    log::info!("Sender statistics: {:?}", driver.stats());
+
+    // a status reading is not worth a retransmission
+    driver.send(|session| {
+        session.send_with_qos(Qos::AtMostOnce, &Payload::Application(b"status: running"))
+    })?;
+
+    // the event must be counted exactly once, even if it is retransmitted
+    driver.request(|session| {
+        session.send_with_qos(
+            Qos::ExactlyOnce,
+            &Payload::Application(b"event: door opened"),
+        )
+    })?;
+
+    // diagnostic dump does not fit into one datagram, send it in fragments
+    let dump: std::vec::Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+    let fragments = driver
+        .session()
+        .fragment_application(&dump)
+        .expect("Failed to fragment diagnostic dump");
+    log::info!(
+        "Sending diagnostic dump of {} bytes in {} fragments",
+        dump.len(),
+        fragments.count()
+    );
+    for index in 0..fragments.count() {
+        let fragment = fragments.fragment(index).expect("Fragment is in range");
+        driver.request(|session| session.send_fragment(&fragment))?;
+    }
+
+    // upload the device log block by block, an interrupted upload resumes from the server state
+    let device_log: std::vec::Vec<u8> = (0..200u32)
+        .flat_map(|i| format!("{i}: device is alive\n").into_bytes())
+        .collect();
+    let mut upload = client::Upload::new("device.log", &device_log, 512);
+    let query = upload.query();
+    let Some(Payload::BlockAck(ack)) = driver.request(|session| session.send_payload(&query))?
+    else {
+        return Err(driver::unexpected_response());
+    };
+    upload.on_ack(&ack);
+    while let Some(block) = upload.next_block() {
+        log::info!(
+            "Uploading block {}/{} of [device.log]",
+            block.index + 1,
+            upload.block_count()
+        );
+        let Some(Payload::BlockAck(ack)) =
+            driver.request(|session| session.send_payload(&Payload::Block(block)))?
+        else {
+            return Err(driver::unexpected_response());
+        };
+        upload.on_ack(&ack);
+    }
+
+    // download the firmware update, the progress survives restarts
+    let storage = storage::FileStorage::open(Path::new("device"))?;
+    let mut download = client::Download::new("sensor", 2, 512, 1024 * 1024, storage);
+    let query = download.query();
+    match driver.request(|session| session.send_payload(&query)) {
+        Ok(Some(Payload::FirmwareInfo(info))) => {
+            log::info!("Firmware update: {} bytes", info.size);
+            download.on_info(info).map_err(std::io::Error::other)?;
+        }
+        Ok(_) => return Err(driver::unexpected_response()),
+        Err(error) => log::warn!("Firmware update is not available: {error}"),
+    }
+    while let Some(request) = download.next_request() {
+        let Some(Payload::Block(block)) =
+            driver.request(|session| session.send_payload(&request))?
+        else {
+            return Err(driver::unexpected_response());
+        };
+        log::info!("Received firmware block {}", block.index);
+        download.on_block(&block).map_err(std::io::Error::other)?;
+    }
+    if download.is_complete() {
+        log::info!("Firmware update is downloaded and verified");
+    }
 
-    // wait for acknowledgement
-    let (hrh, body) = client::parse_request(&read_buf).expect("Failed to parse ack");
-    client
-        .receive_ack(hrh, &body)
-        .expect("Failed to process received ack");
+    // check the session is still alive before going idle
+    let Some(Payload::Pong(1)) = driver.request(|session| session.send_ping(1))? else {
+        return Err(driver::unexpected_response());
+    };
+    log::info!("Session is alive");
 
-    log::info!("Received ack, close connection");
+    // close the session explicitly, so the server does not wait for the keepalive
+    match driver.request(|session| session.send_close(CloseReason::Normal)) {
+        Ok(Some(Payload::Close(_))) => log::info!("Session is closed"),
+        Ok(_) => return Err(driver::unexpected_response()),
+        Err(error) => {
+            // the server removes the session anyway when the keepalive expires
+            log::warn!("Close is not acknowledged: {error}");
+            driver.abort();
+        }
+    }
     Ok(())
 }
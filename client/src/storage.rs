@@ -0,0 +1,128 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use client::{FirmwareStorage, Progress, QueueStorage, RECORD_SIZE};
+use shared_lib::firmware::FirmwareInfo;
+
+/// Firmware storage backed by files, emulates an update slot and a progress record in flash.
+pub struct FileStorage {
+    image: File,
+    progress_path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let image = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(dir.join("firmware.bin"))?;
+
+        Ok(FileStorage {
+            image,
+            progress_path: dir.join("firmware.progress"),
+        })
+    }
+}
+
+impl FirmwareStorage for FileStorage {
+    type Error = std::io::Error;
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> std::io::Result<()> {
+        self.image.seek(SeekFrom::Start(u64::from(offset)))?;
+        self.image.write_all(data)?;
+        self.image.sync_data()
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> std::io::Result<()> {
+        self.image.seek(SeekFrom::Start(u64::from(offset)))?;
+        self.image.read_exact(buf)
+    }
+
+    fn load_progress(&mut self) -> std::io::Result<Option<Progress>> {
+        let record = match fs::read(&self.progress_path) {
+            Ok(record) => record,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        // a damaged record restarts the download
+        let Some(next_block) = record.get(FirmwareInfo::SIZE..FirmwareInfo::SIZE + 4) else {
+            return Ok(None);
+        };
+        let Ok(info) = FirmwareInfo::parse(&record) else {
+            return Ok(None);
+        };
+        Ok(Some(Progress {
+            info,
+            next_block: u32::from_be_bytes(next_block.try_into().unwrap_or_default()),
+        }))
+    }
+
+    fn save_progress(&mut self, progress: &Progress) -> std::io::Result<()> {
+        let mut record = [0u8; FirmwareInfo::SIZE + 4];
+        progress
+            .info
+            .serialize(&mut record)
+            .map_err(std::io::Error::other)?;
+        record[FirmwareInfo::SIZE..].copy_from_slice(&progress.next_block.to_be_bytes());
+
+        // replace the record atomically
+        let tmp_path = self.progress_path.with_extension("tmp");
+        fs::write(&tmp_path, record)?;
+        fs::rename(tmp_path, &self.progress_path)
+    }
+}
+
+/// Offline queue storage backed by a file of fixed-size slots, emulates an EEPROM.
+/// Erased slots are filled with `0xff` like erased flash.
+pub struct FileQueueStorage {
+    file: File,
+}
+
+impl FileQueueStorage {
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(dir.join("readings.queue"))?;
+        Ok(FileQueueStorage { file })
+    }
+
+    fn seek(&mut self, slot: usize) -> std::io::Result<()> {
+        self.file
+            .seek(SeekFrom::Start((slot * RECORD_SIZE) as u64))
+            .map(|_| ())
+    }
+}
+
+impl QueueStorage for FileQueueStorage {
+    type Error = std::io::Error;
+
+    fn read(&mut self, slot: usize, record: &mut [u8; RECORD_SIZE]) -> std::io::Result<bool> {
+        self.seek(slot)?;
+        match self.file.read_exact(record) {
+            Ok(()) => Ok(record.iter().any(|byte| *byte != 0xff)),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn write(&mut self, slot: usize, record: &[u8; RECORD_SIZE]) -> std::io::Result<()> {
+        self.seek(slot)?;
+        self.file.write_all(record)?;
+        self.file.sync_data()
+    }
+
+    fn erase(&mut self, slot: usize) -> std::io::Result<()> {
+        self.write(slot, &[0xff; RECORD_SIZE])
+    }
+}
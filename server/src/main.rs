@@ -1,6 +1,8 @@
 #![forbid(unsafe_code)]
 
-use service::start_server;
+use std::sync::Arc;
+
+use service::{start_server, Config, LoggingApplication};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 const SERVER_ADDR: &str = "127.0.0.1:8080";
@@ -15,7 +17,7 @@ async fn main() -> std::io::Result<()> {
         .init();
 
     // Start the server
-    start_server(SERVER_ADDR).await?;
+    start_server(SERVER_ADDR, Config::default(), Arc::new(LoggingApplication)).await?;
 
     Ok(())
 }
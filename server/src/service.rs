@@ -1,6 +1,7 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use shared_lib::command::PACKET_SIZE;
+use session::{KeepaliveLimits, SessionContext};
+use shared_lib::{close::CloseReason, command::PACKET_SIZE, packet::PacketBuffer};
 use state::State;
 use tokio::{
     net::UdpSocket,
@@ -9,13 +10,59 @@ use tokio::{
 };
 use tracing::{span, Instrument, Level};
 
+mod application;
+mod deliveries;
+mod firmware;
+mod limiter;
 mod session;
 mod state;
+mod upload;
+
+pub use application::{Application, DeliveryMode, LoggingApplication};
+use deliveries::DeliveryLog;
+use firmware::FirmwareStore;
+use upload::UploadStore;
 
 /// Cleanup interval in seconds.
-/// This is used to remove inactive sessions.
+/// This is used to remove closed sessions.
 const CLEANUP_INTERVAL: u64 = 5 * 60; // 5 minutes
 
+/// Number of responses waiting to be sent, shared by all sessions.
+/// Sessions wait for a free slot, so the queue only needs to absorb bursts.
+const RESPONSE_QUEUE_SIZE: usize = 256;
+
+/// Time given to the sessions to notify their devices on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Server configuration.
+pub struct Config {
+    /// Directory where block-wise uploads are stored.
+    pub upload_dir: PathBuf,
+    /// The largest upload accepted from a device, in bytes.
+    pub max_upload_size: u64,
+    /// Directory with firmware images served to devices.
+    pub firmware_dir: PathBuf,
+    /// Keepalive interval of devices which do not ask for one.
+    pub keepalive: Duration,
+    /// The shortest keepalive interval a device might ask for.
+    pub min_keepalive: Duration,
+    /// The longest keepalive interval a device might ask for.
+    pub max_keepalive: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            upload_dir: PathBuf::from("uploads"),
+            max_upload_size: 16 * 1024 * 1024,
+            firmware_dir: PathBuf::from("firmware"),
+            keepalive: Duration::from_secs(60),
+            min_keepalive: Duration::from_secs(10),
+            max_keepalive: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
 /// Response to be sent to the client.
 /// Contains the address of the client, session ID, ACK ID, and the message buffer.
 #[derive(Clone)]
@@ -23,7 +70,7 @@ struct Response {
     addr: SocketAddr,
     session_id: u16,
     ack_id: u16,
-    buf: Vec<u8>,
+    buf: PacketBuffer,
 }
 
 /// Start the server.
@@ -32,16 +79,36 @@ struct Response {
 ///
 /// **Arguments**
 /// - `addr`: The address to bind the server to.
+/// - `config`: The server configuration.
+/// - `application`: The handler for messages received from devices.
 ///
-pub async fn start_server(addr: &str) -> std::io::Result<()> {
+pub async fn start_server(
+    addr: &str,
+    config: Config,
+    application: Arc<dyn Application>,
+) -> std::io::Result<()> {
     // Try to open UDP socket
     let socket = UdpSocket::bind(addr).await?;
     log::info!("UDP server started on {}", addr);
 
-    let (sender, mut receiver) = mpsc::channel::<Response>(10);
+    let (sender, mut receiver) = mpsc::channel::<Response>(RESPONSE_QUEUE_SIZE);
+    let (closed_sender, mut closed_receiver) = mpsc::unbounded_channel::<u16>();
 
     // server state
-    let mut state: State = State::new(sender);
+    let context = SessionContext {
+        response_queue: sender,
+        application,
+        uploads: Arc::new(UploadStore::new(config.upload_dir, config.max_upload_size)),
+        firmware: Arc::new(FirmwareStore::new(config.firmware_dir)),
+        deliveries: Arc::new(DeliveryLog::default()),
+        keepalive: KeepaliveLimits {
+            default: config.keepalive,
+            min: config.min_keepalive,
+            max: config.max_keepalive,
+        },
+        closed_sessions: closed_sender,
+    };
+    let mut state: State = State::new(context);
     let mut buf = [0u8; PACKET_SIZE];
 
     // schedule cleanup task every 5 minutes
@@ -60,14 +127,14 @@ pub async fn start_server(addr: &str) -> std::io::Result<()> {
                 match socket_received {
                     Ok((amt, addr)) => {
                         // randomly ignore it
-                        if rand::random::<u8>() % 3 == 0 {
+                        if rand::random::<u8>().is_multiple_of(3) {
                             log::warn!("Ignoring message from {}", addr);
                             continue;
                         }
                         let socket_span = span!(Level::INFO, "udp_server", addr = addr.to_string());
-                        // wait for the message to be processed (ignore errors)
-                        let bytes = &buf[..amt];
-                        let _ = state.process_received_message(bytes, addr).instrument(socket_span).await;
+                        // queue the message for processing (ignore errors)
+                        let bytes = &mut buf[..amt];
+                        let _ = socket_span.in_scope(|| state.process_received_message(bytes, addr));
                     }
                     Err(err) => {
                         log::error!("Failed to receive message: {:?}", err);
@@ -78,7 +145,7 @@ pub async fn start_server(addr: &str) -> std::io::Result<()> {
                 if let Some(output_message) = output_message {
                     let socket_span = span!(Level::INFO, "udp_server", addr = output_message.addr.to_string());
                     // wait for the message to be sent (ignore errors)
-                    if rand::random::<u8>() % 3 == 0 {
+                    if rand::random::<u8>().is_multiple_of(3) {
                         log::warn!("Ignoring message to {}", output_message.addr);
                         continue;
                     }
@@ -89,6 +156,9 @@ pub async fn start_server(addr: &str) -> std::io::Result<()> {
                     break;
                 }
             },
+            Some(session_id) = closed_receiver.recv() => {
+                state.remove_session(session_id);
+            },
             _ = cleanup_task => {
                 log::debug!("Run cleanup task");
                 state.cleanup();
@@ -100,6 +170,16 @@ pub async fn start_server(addr: &str) -> std::io::Result<()> {
         };
     }
 
+    // notify the devices, the queue is closed when all session tasks are finished
+    state.close_all(CloseReason::Shutdown);
+    drop(state);
+    let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        while let Some(output_message) = receiver.recv().await {
+            let _ = send_response(&socket, output_message).await;
+        }
+    })
+    .await;
+
     Ok(())
 }
 
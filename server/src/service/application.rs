@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use shared_lib::{qos::Qos, telemetry::Reading};
+
+/// Order in which messages received out of order are delivered to the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Messages are delivered as soon as they are received.
+    Unordered,
+    /// Messages are delivered in the order they were sent. A missing message holds the
+    /// following ones back until it is retransmitted, too many messages are held or a timeout
+    /// expires, then it is delivered late if it arrives at all.
+    Ordered,
+}
+
+/// Application handler for messages delivered by device sessions.
+///
+/// Called from the session tasks, so implementations must be cheap and must not block.
+/// `qos` is the delivery guarantee requested by the device: at-least-once messages
+/// might be delivered twice, exactly-once messages are delivered once.
+pub trait Application: Send + Sync + 'static {
+    /// Telemetry reading received from the device. Batched readings are delivered one by one.
+    fn on_reading(&self, device_id: u32, qos: Qos, reading: Reading);
+
+    /// Opaque application-defined data received from the device.
+    fn on_application_data(&self, device_id: u32, qos: Qos, data: &[u8]);
+
+    /// Block-wise upload is completed and stored at `path`.
+    fn on_upload_complete(&self, device_id: u32, name: &str, path: &Path);
+
+    /// Order of readings and application data, read once per session.
+    fn delivery_mode(&self) -> DeliveryMode {
+        DeliveryMode::Unordered
+    }
+}
+
+/// Default application, logs everything it receives in order.
+pub struct LoggingApplication;
+
+impl Application for LoggingApplication {
+    fn on_reading(&self, device_id: u32, qos: Qos, reading: Reading) {
+        log::info!("Device [{device_id}] reading ({:?}): {:?}", qos, reading);
+    }
+
+    fn on_application_data(&self, device_id: u32, qos: Qos, data: &[u8]) {
+        log::info!(
+            "Device [{device_id}] application data ({:?}): {} bytes",
+            qos,
+            data.len()
+        );
+    }
+
+    fn on_upload_complete(&self, device_id: u32, name: &str, path: &Path) {
+        log::info!("Device [{device_id}] uploaded [{name}]: {}", path.display());
+    }
+
+    fn delivery_mode(&self) -> DeliveryMode {
+        DeliveryMode::Ordered
+    }
+}
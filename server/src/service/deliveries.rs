@@ -0,0 +1,52 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Mutex,
+};
+
+/// Number of exactly-once message ids remembered per device.
+const DEVICE_LOG_SIZE: usize = 1024;
+
+/// Remembers the ids of exactly-once messages accepted for delivery to the application.
+///
+/// Shared by all sessions, so a message retransmitted after a re-handshake is still
+/// recognized as a duplicate. Only the latest [DEVICE_LOG_SIZE] ids of every device are kept.
+#[derive(Default)]
+pub struct DeliveryLog {
+    devices: Mutex<HashMap<u32, DeviceLog>>,
+}
+
+#[derive(Default)]
+struct DeviceLog {
+    ids: HashSet<u32>,
+    order: VecDeque<u32>,
+}
+
+impl DeliveryLog {
+    /// Check if the message was already delivered.
+    pub fn is_delivered(&self, device_id: u32, message_id: u32) -> bool {
+        self.devices
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&device_id)
+            .is_some_and(|log| log.ids.contains(&message_id))
+    }
+
+    /// Record the accepted message, the oldest id of the device is forgotten.
+    pub fn record(&self, device_id: u32, message_id: u32) {
+        let mut devices = self
+            .devices
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let log = devices.entry(device_id).or_default();
+        if !log.ids.insert(message_id) {
+            return;
+        }
+
+        log.order.push_back(message_id);
+        if log.order.len() > DEVICE_LOG_SIZE {
+            if let Some(oldest) = log.order.pop_front() {
+                log.ids.remove(&oldest);
+            }
+        }
+    }
+}
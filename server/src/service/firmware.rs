@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, SeekFrom},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use blake2::{Blake2s256, Digest};
+use shared_lib::{
+    firmware::{FirmwareInfo, FirmwareQuery, FirmwareRequest},
+    transfer::Block,
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// Serves firmware images to devices.
+///
+/// Images are stored as `<dir>/<name>-<version>.bin` and sent block by block,
+/// devices verify the whole image with the BLAKE2s-256 hash from [FirmwareInfo].
+///
+/// An image is never replaced under the same version, so its description is computed
+/// once and cached by name and version.
+pub struct FirmwareStore {
+    dir: PathBuf,
+    infos: Mutex<HashMap<(String, u32), FirmwareInfo>>,
+}
+
+impl FirmwareStore {
+    pub fn new(dir: PathBuf) -> Self {
+        FirmwareStore {
+            dir,
+            infos: Mutex::default(),
+        }
+    }
+
+    /// Describe the image: size and hash.
+    pub async fn info(&self, query: &FirmwareQuery<'_>) -> std::io::Result<FirmwareInfo> {
+        let key = (query.name.to_owned(), query.version);
+        if let Some(info) = self.infos.lock().unwrap().get(&key) {
+            return Ok(*info);
+        }
+
+        let image = fs::read(self.path(query.name, query.version)?).await?;
+        let size = u32::try_from(image.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Firmware image is too big"))?;
+        let info = FirmwareInfo {
+            version: query.version,
+            size,
+            hash: Blake2s256::digest(&image).into(),
+        };
+
+        self.infos.lock().unwrap().insert(key, info);
+        Ok(info)
+    }
+
+    /// Read the requested block into `buf` and return it as a block of the image.
+    pub async fn read_block<'a>(
+        &self,
+        request: &FirmwareRequest<'a>,
+        buf: &'a mut [u8],
+    ) -> std::io::Result<Block<'a>> {
+        let mut file = File::open(self.path(request.name, request.version)?).await?;
+        let image_size = file.metadata().await?.len();
+
+        let block_size = u64::from(request.block_size);
+        let offset = u64::from(request.index) * block_size;
+        if offset >= image_size && !(offset == 0 && image_size == 0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Firmware block is out of range",
+            ));
+        }
+
+        let size = block_size.min(image_size - offset) as usize;
+        let data = buf
+            .get_mut(..size)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Firmware block is too big"))?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.read_exact(data).await?;
+
+        Ok(Block {
+            name: request.name,
+            index: request.index,
+            block_size: request.block_size,
+            last: offset + block_size >= image_size,
+            data,
+        })
+    }
+
+    /// Path of the image. Names are restricted to a safe subset of characters.
+    fn path(&self, name: &str, version: u32) -> std::io::Result<PathBuf> {
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid firmware name"));
+        }
+
+        Ok(self.dir.join(format!("{name}-{version}.bin")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cache_info_per_version() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let store = FirmwareStore::new(dir.to_owned());
+        fs::write(dir.join("app-1.bin"), b"first").await.unwrap();
+        fs::write(dir.join("app-2.bin"), b"second").await.unwrap();
+
+        let query = FirmwareQuery {
+            name: "app",
+            version: 1,
+        };
+        let info = store.info(&query).await.unwrap();
+        assert_eq!(info.size, 5);
+        assert_eq!(info.hash, <[u8; 32]>::from(Blake2s256::digest(b"first")));
+
+        // the cached description is returned without reading the image again
+        fs::remove_file(dir.join("app-1.bin")).await.unwrap();
+        assert_eq!(store.info(&query).await.unwrap(), info);
+
+        let query = FirmwareQuery {
+            name: "app",
+            version: 2,
+        };
+        assert_eq!(store.info(&query).await.unwrap().size, 6);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Limits the answers to unauthenticated packets.
+///
+/// The source address of such packets might be spoofed, so the server must not flood
+/// a victim with answers: every address gets one answer per interval and all addresses
+/// together get at most `burst` answers per interval.
+pub struct RateLimiter {
+    interval: Duration,
+    burst: usize,
+    window_started: Instant,
+    answered: usize,
+    last_answers: HashMap<IpAddr, Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration, burst: usize, now: Instant) -> Self {
+        RateLimiter {
+            interval,
+            burst,
+            window_started: now,
+            answered: 0,
+            last_answers: HashMap::new(),
+        }
+    }
+
+    /// Check if the address might be answered now, the answer is counted if it is allowed.
+    pub fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if now.duration_since(self.window_started) >= self.interval {
+            // new interval, forget the addresses which might be answered again
+            self.window_started = now;
+            self.answered = 0;
+            let interval = self.interval;
+            self.last_answers
+                .retain(|_, answered| now.duration_since(*answered) < interval);
+        }
+
+        if self.answered >= self.burst
+            || self
+                .last_answers
+                .get(&addr)
+                .is_some_and(|answered| now.duration_since(*answered) < self.interval)
+        {
+            return false;
+        }
+
+        self.answered += 1;
+        self.last_answers.insert(addr, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn limit_per_address_and_burst() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut limiter = RateLimiter::new(second, 2, now);
+        let addr = |last| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+
+        // one answer per address
+        assert!(limiter.allow(addr(1), now));
+        assert!(!limiter.allow(addr(1), now));
+
+        // and a few answers for all addresses
+        assert!(limiter.allow(addr(2), now));
+        assert!(!limiter.allow(addr(3), now));
+
+        // everything is allowed again in the next interval
+        assert!(limiter.allow(addr(1), now + second));
+        assert!(limiter.allow(addr(3), now + second));
+    }
+}
@@ -1,25 +1,92 @@
-use std::time::Instant;
-use tokio::sync::mpsc::{self, Sender};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::{
+    select,
+    sync::mpsc::{self, Sender, UnboundedSender},
+    time::Instant,
+};
 
 use std::net::SocketAddr;
 
 use shared_lib::{
-    command::{EncodedCommand, COMMAND_SIZE, PACKET_SIZE},
+    ack::{SequenceWindow, ACK_BITS},
+    close::CloseReason,
+    command::{Buffer, Payload, COMMAND_SIZE, PACKET_SIZE},
+    error::SerializeError,
+    fragment::Reassembly,
     network::{MessageType, PackedHeader},
-    write_command,
+    packet::{PacketBuffer, PacketView},
+    write_command, write_command_header,
 };
 use tracing::{info_span, Instrument};
 
-use super::Response;
+use super::{
+    deliveries::DeliveryLog, firmware::FirmwareStore, upload::UploadStore, Application,
+    DeliveryMode, Response,
+};
+use reorder::ReorderBuffer;
 
 mod handler;
+mod reorder;
+#[cfg(test)]
+mod test_device;
 
 const ENC_PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
 const QUEUE_SIZE: usize = 10;
+/// Number of responses kept for resending, covers the client window of messages in flight.
+const RESPONSE_CACHE_SIZE: usize = 8;
+
+/// The biggest message which can be reassembled from fragments.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Incomplete fragmented message is dropped after this timeout.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of messages held back for ordered delivery, covers the received sequence window.
+const REORDER_BUFFER_SIZE: usize = ACK_BITS as usize;
+/// A missing message is skipped after the following message waits for it this long.
+const REORDER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of keepalive intervals without any message after which the session is closed.
+const KEEPALIVE_MISSES: u32 = 3;
+
+/// The close of the server is sent again if the device does not acknowledge it in time.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of times the close is sent again before the session ends anyway.
+const CLOSE_RETRIES: u32 = 3;
+
+/// Keepalive intervals accepted from devices.
+#[derive(Clone, Copy)]
+pub struct KeepaliveLimits {
+    pub default: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl KeepaliveLimits {
+    /// Accept the interval requested by the device in seconds, zero asks for the default.
+    pub fn accept(&self, requested_secs: u16) -> Duration {
+        if requested_secs == 0 {
+            return self.default;
+        }
+        Duration::from_secs(u64::from(requested_secs)).clamp(self.min, self.max)
+    }
+}
+
+/// Shared services available to every session task.
+#[derive(Clone)]
+pub struct SessionContext {
+    pub response_queue: Sender<Response>,
+    pub application: Arc<dyn Application>,
+    pub uploads: Arc<UploadStore>,
+    pub firmware: Arc<FirmwareStore>,
+    pub deliveries: Arc<DeliveryLog>,
+    pub keepalive: KeepaliveLimits,
+    /// Sessions report their id here when their task finishes, so the server removes them.
+    pub closed_sessions: UnboundedSender<u16>,
+}
 
 pub struct Session {
+    pub device_id: u32,
     pub last_sequence_id: u16,
-    pub last_timestamp: Instant,
     pub channel: Sender<ChannelMessage>,
 }
 
@@ -27,11 +94,32 @@ struct SessionState {
     device_id: u32,
     session_id: u16,
     sequnce_id: u16,
-    last_ack_id: u16,
+    // received sequences, reported to the client in the ack bitmap
+    received: SequenceWindow,
     receiver: mpsc::Receiver<ChannelMessage>,
-    response_queue: Sender<Response>,
+    context: SessionContext,
     snow_state: SnowState,
-    last_response: Option<Response>,
+    // responses to the latest messages, resent when a request is duplicated
+    recent_responses: VecDeque<Response>,
+    // scratch buffer for decryption, snow does not decrypt in place
+    read_buf: Buffer,
+    // fragmented message being reassembled, allocated on the first fragment
+    pending_message: Option<PendingMessage>,
+    delivery_mode: DeliveryMode,
+    // plaintext payloads held back until the messages before them are delivered
+    reorder: ReorderBuffer<Vec<u8>>,
+    // keepalive interval negotiated in the handshake
+    keepalive: Duration,
+    // time of the last authenticated message
+    last_activity: Instant,
+    // address of the last authenticated message, messages of the server are sent to it
+    peer: Option<SocketAddr>,
+}
+
+/// Fragmented message being reassembled.
+pub struct PendingMessage {
+    started: Instant,
+    pub reassembly: Box<Reassembly<MAX_MESSAGE_SIZE>>,
 }
 
 enum SnowState {
@@ -49,28 +137,37 @@ impl Session {
     pub fn spawn_new(
         device_id: u32,
         session_id: u16,
-        response_queue: Sender<Response>,
+        context: SessionContext,
     ) -> Sender<ChannelMessage> {
         let (sender, receiver) = mpsc::channel::<ChannelMessage>(QUEUE_SIZE);
 
         // start new async task to handle messages
         tokio::spawn(async move {
+            let delivery_mode = context.application.delivery_mode();
             let mut session_state = SessionState {
                 device_id,
                 session_id,
                 sequnce_id: 0,
-                last_ack_id: 0,
+                received: SequenceWindow::new(),
                 receiver,
-                response_queue,
                 snow_state: SnowState::Handshake(Box::new(
                     snow::Builder::new(ENC_PATTERN.parse().unwrap())
                         .build_responder()
                         .expect("Failed to build initiator"),
                 )),
-                last_response: None,
+                recent_responses: VecDeque::with_capacity(RESPONSE_CACHE_SIZE),
+                read_buf: [0u8; COMMAND_SIZE],
+                pending_message: None,
+                delivery_mode,
+                reorder: ReorderBuffer::new(REORDER_BUFFER_SIZE, REORDER_TIMEOUT),
+                keepalive: context.keepalive.default,
+                last_activity: Instant::now(),
+                peer: None,
+                context,
             };
 
             session_state.run_loop().await;
+            session_state.finish();
         });
 
         sender
@@ -78,73 +175,153 @@ impl Session {
 }
 
 /// Session channel message types.
-pub struct ChannelMessage {
-    pub addr: SocketAddr,
-    pub header: PackedHeader,
-    pub body: Vec<u8>,
+/// The packet is stored inline, so queueing a message does not allocate.
+#[allow(clippy::large_enum_variant)]
+pub enum ChannelMessage {
+    /// Datagram received from the device.
+    Packet {
+        addr: SocketAddr,
+        packet: PacketBuffer,
+    },
+    /// The server closes the session, the device is notified.
+    Close(CloseReason),
 }
 
 impl SessionState {
     async fn run_loop(&mut self) {
         loop {
-            if let Some(ChannelMessage { addr, header, body }) = self.receiver.recv().await {
-                if self.last_ack_id < header.sequence {
-                    // increase sequence id for the future response
-                    self.sequnce_id += 1;
-                    self.last_ack_id = header.sequence;
+            // wait for the next message, until a gap in the ordered delivery is skipped
+            // or until the device misses too many keepalive intervals
+            let reorder_deadline = self.reorder.deadline().map(Instant::from_std);
+            let idle_deadline = self.last_activity + self.keepalive * KEEPALIVE_MISSES;
+            let message = select! {
+                message = self.receiver.recv() => message,
+                _ = tokio::time::sleep_until(reorder_deadline.unwrap_or(idle_deadline)), if reorder_deadline.is_some() => {
+                    let (context, device_id) = (&self.context, self.device_id);
+                    self.reorder.poll_timeout(Instant::now().into_std(), |plaintext| {
+                        deliver_held_back(context, device_id, &plaintext)
+                    });
+                    continue;
+                }
+                _ = tokio::time::sleep_until(idle_deadline) => {
+                    log::info!(
+                        "Session [{}], device [{}] missed {KEEPALIVE_MISSES} keepalive intervals of {:?}, closed",
+                        self.session_id,
+                        self.device_id,
+                        self.keepalive
+                    );
+                    self.send_close(CloseReason::Idle).await;
+                    break;
+                }
+            };
+
+            if let Some(ChannelMessage::Packet { addr, mut packet }) = message {
+                let mut packet = match PacketView::parse(&mut packet) {
+                    Ok(packet) => packet,
+                    Err(error) => {
+                        log::error!("Failed to parse queued packet: {:?}", error);
+                        continue;
+                    }
+                };
+                let sequence = packet.header().sequence;
+
+                if self.received.contains(sequence) {
+                    // duplicate, the response was lost: resend it if it is still cached
+                    let Some(response) = self
+                        .recent_responses
+                        .iter()
+                        .find(|response| response.ack_id == sequence)
+                    else {
+                        log::info!(
+                            "Received duplicate message, response is not available: {} (sequence: {})",
+                            self.session_id,
+                            sequence
+                        );
+                        continue;
+                    };
+
+                    // check if the previous addr is the same
+                    if response.addr != addr {
+                        log::warn!("Security issue: requested duplicate message from another addr [orig: {}, new: {}]", response.addr, addr);
+                        break;
+                    }
+                    if let Err(err) = self.context.response_queue.send(response.clone()).await {
+                        log::error!("Failed to send response, server might be stopped: {err}");
+                        // close the session
+                        break;
+                    }
+                } else if self.received.accepts(sequence) {
+                    // new message, might be out of order inside the window,
+                    // the sequence is received once the handler authenticates the message
 
                     // handle the message
                     let socket_src = addr.to_string();
                     let span = info_span!("handle_message", remote = socket_src);
 
-                    let request_sequence_id = header.sequence;
-
-                    let result = handler::process(self, header, &body)
+                    let result = handler::process(self, &mut packet)
                         .instrument(span.clone())
                         .await;
 
                     // if message is processed successfully, send response back
                     // otherwise, send an error
-                    let (response_type, response) = match result {
-                        Ok(success) => (success.message_type, success.command),
+                    let mut response = match result {
+                        Ok(success) => {
+                            // only authenticated messages keep the session alive and are counted
+                            self.sequnce_id = self.sequnce_id.wrapping_add(1);
+                            self.last_activity = Instant::now();
+                            self.peer = Some(addr);
+                            success
+                        }
                         Err(error) => {
                             span.in_scope(|| {
                                 log::error!("Failed to process message: {:?}", error);
                             });
-                            (
-                                MessageType::Error,
-                                EncodedCommand {
-                                    size: 0,
-                                    buf: [0u8; COMMAND_SIZE],
-                                },
-                            )
+                            handler::ProcessedMessage::error(error.code())
                         }
                     };
 
-                    // try to serialize
-                    let header = PackedHeader::new(
-                        response_type,
-                        self.device_id,
-                        self.session_id,
-                        self.last_ack_id,
-                        request_sequence_id,
-                    );
-                    let mut buf = [0u8; PACKET_SIZE];
-                    match write_command(&header, &response, &mut buf) {
+                    // every authenticated sequence moves the ordered delivery forward,
+                    // forged messages do not take the slot of the authentic ones
+                    if self.delivery_mode == DeliveryMode::Ordered
+                        && self.received.contains(sequence)
+                    {
+                        let (context, device_id) = (&self.context, self.device_id);
+                        self.reorder.push(
+                            sequence,
+                            response.delivery.take(),
+                            Instant::now().into_std(),
+                            |plaintext| deliver_held_back(context, device_id, &plaintext),
+                        );
+                    }
+
+                    // fire-and-forget messages are not acknowledged
+                    if !response.respond {
+                        continue;
+                    }
+
+                    // try to serialize, acknowledge all received sequences
+                    // and advertise the free queue slots as the receive window
+                    let header = self.response_header(response.message_type, sequence);
+                    let mut content = PacketBuffer::new();
+                    let _ = content.resize_default(PACKET_SIZE);
+                    match self.write_response(&header, &response, &mut content) {
                         Ok(content_size) => {
-                            let mut content: Vec<u8> = Vec::with_capacity(content_size);
-                            content.extend_from_slice(&buf[..content_size]);
-
-                            // copy response for the future resend
-                            self.last_response = Some(Response {
-                                addr,
-                                buf: content.clone(),
-                                session_id: self.session_id,
-                                ack_id: header.sequence,
-                            });
+                            content.truncate(content_size);
+
+                            // copy response for the future resend, responses to forged
+                            // messages are not resent for the authentic ones
+                            if self.received.contains(sequence) {
+                                self.remember_response(Response {
+                                    addr,
+                                    buf: content.clone(),
+                                    session_id: self.session_id,
+                                    ack_id: header.sequence,
+                                });
+                            }
 
                             // send response back to the client
                             if let Err(err) = self
+                                .context
                                 .response_queue
                                 .send(Response {
                                     addr,
@@ -160,6 +337,15 @@ impl SessionState {
                                 // close the session
                                 break;
                             }
+
+                            if response.close {
+                                log::info!(
+                                    "Session [{}], device [{}] closed by the device",
+                                    self.session_id,
+                                    self.device_id
+                                );
+                                break;
+                            }
                         }
                         Err(error) => {
                             // response will not be send
@@ -168,39 +354,26 @@ impl SessionState {
                             break;
                         }
                     }
-                } else if let Some(last_response) = self.last_response.as_ref() {
-                    // check if the previous addr is the same
-                    if last_response.addr == addr {
-                        if last_response.ack_id == header.sequence {
-                            // resend the last message
-                            if let Err(err) = self.response_queue.send(last_response.clone()).await
-                            {
-                                log::error!(
-                                    "Failed to send response, server might be stopped: {err}"
-                                );
-                                // close the session
-                                break;
-                            }
-                        } else {
-                            // nothing to do, just ignore
-                            log::info!(
-                                "Received old message, ignored: {} (sequence: {})",
-                                self.session_id,
-                                header.sequence
-                            );
-                        }
-                    } else {
-                        log::warn!("Security issue: requested duplicate the last message from another addr [orig: {}, new: {}]", self.last_response.as_ref().unwrap().addr, addr);
-                        break;
-                    }
                 } else {
-                    log::warn!("Security issue: requested duplicate the last message, but it is not available");
-                    break;
+                    // nothing to do, just ignore
+                    log::info!(
+                        "Received old message, ignored: {} (sequence: {})",
+                        self.session_id,
+                        sequence
+                    );
                 }
+            } else if let Some(ChannelMessage::Close(reason)) = message {
+                self.send_close(reason).await;
+                log::info!(
+                    "Session [{}], device [{}] closed by the server: {reason:?}",
+                    self.session_id,
+                    self.device_id
+                );
+                break;
             } else {
-                // handle timeout
+                // the server removed the session
                 log::info!(
-                    "Session [{}], device [{}] timed out",
+                    "Session [{}], device [{}] closed",
                     self.session_id,
                     self.device_id
                 );
@@ -209,6 +382,156 @@ impl SessionState {
         }
     }
 
+    /// Header of a message to the device, it acknowledges all received sequences
+    /// and advertises the free queue slots as the receive window.
+    fn response_header(&self, message_type: MessageType, sequence: u16) -> PackedHeader {
+        PackedHeader::new(
+            message_type,
+            self.device_id,
+            self.session_id,
+            sequence,
+            self.received.ack(),
+        )
+        .with_ack_bits(self.received.ack_bits())
+        .with_window(self.receive_window())
+    }
+
+    /// Notify the device that the server closes the session.
+    /// The close is sent again until the device acknowledges it, at most [CLOSE_RETRIES] times.
+    async fn send_close(&mut self, reason: CloseReason) {
+        // the device is not authenticated yet, nobody to notify
+        let Some(addr) = self.peer else {
+            return;
+        };
+
+        // messages of the server have a sequence of their own, see [PackedHeader::nonce]
+        self.sequnce_id = self.sequnce_id.wrapping_add(1);
+        let header = self.response_header(MessageType::EncryptedMessage, self.sequnce_id);
+        let mut content = PacketBuffer::new();
+        let _ = content.resize_default(PACKET_SIZE);
+        let written = handler::ProcessedMessage::encrypted(
+            MessageType::EncryptedMessage,
+            &Payload::Close(reason),
+        )
+        .and_then(|message| self.write_response(&header, &message, &mut content));
+        let content_size = match written {
+            Ok(content_size) => content_size,
+            Err(error) => {
+                log::error!("Failed to serialize close: {:?}", error);
+                return;
+            }
+        };
+        content.truncate(content_size);
+        let response = Response {
+            addr,
+            buf: content,
+            session_id: self.session_id,
+            ack_id: header.sequence,
+        };
+
+        for _ in 0..=CLOSE_RETRIES {
+            if self
+                .context
+                .response_queue
+                .send(response.clone())
+                .await
+                .is_err()
+            {
+                return;
+            }
+            if self.wait_close_ack(header.sequence).await {
+                return;
+            }
+        }
+        log::warn!(
+            "Session [{}], device [{}] did not acknowledge the close",
+            self.session_id,
+            self.device_id
+        );
+    }
+
+    /// Wait until the device acknowledges the close with `sequence`.
+    /// Returns `false` after [CLOSE_TIMEOUT], so the close is sent again.
+    /// Other messages are ignored, the session is closing anyway.
+    async fn wait_close_ack(&mut self, sequence: u16) -> bool {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        loop {
+            let message = select! {
+                message = self.receiver.recv() => message,
+                _ = tokio::time::sleep_until(deadline) => return false,
+            };
+            match message {
+                Some(ChannelMessage::Packet { mut packet, .. }) => {
+                    let Ok(packet) = PacketView::parse(&mut packet) else {
+                        continue;
+                    };
+                    let header = packet.header();
+                    if header.message_type == MessageType::Ack && header.ack == sequence {
+                        return true;
+                    }
+                }
+                Some(ChannelMessage::Close(_)) => {}
+                // the server removed the session, e.g. on shutdown, nobody receives the ack
+                None => return true,
+            }
+        }
+    }
+
+    /// Deliver the messages held back for ordered delivery, they are acknowledged already,
+    /// and let the server remove the session.
+    fn finish(&mut self) {
+        let (context, device_id) = (&self.context, self.device_id);
+        self.reorder
+            .flush(|plaintext| deliver_held_back(context, device_id, &plaintext));
+        let _ = self.context.closed_sessions.send(self.session_id);
+    }
+
+    /// Number of messages the session accepts without dropping them.
+    fn receive_window(&self) -> u16 {
+        u16::try_from(self.receiver.capacity()).unwrap_or(u16::MAX)
+    }
+
+    /// Keep the response for resending, the oldest one is dropped.
+    fn remember_response(&mut self, response: Response) {
+        if self.recent_responses.len() == RESPONSE_CACHE_SIZE {
+            self.recent_responses.pop_front();
+        }
+        self.recent_responses.push_back(response);
+    }
+
+    /// Serialize the response into the buffer. Returns the packet size.
+    /// The payload of encrypted responses is encrypted with the response header nonce.
+    fn write_response(
+        &self,
+        header: &PackedHeader,
+        response: &handler::ProcessedMessage,
+        buf: &mut [u8],
+    ) -> Result<usize, handler::ProcessingError> {
+        if !response.encrypted {
+            return Ok(write_command(header, &response.command(), buf)?);
+        }
+
+        let SnowState::Transport(ref noise) = self.snow_state else {
+            return Err(handler::ProcessingError::IncorrectState);
+        };
+        let payload = buf
+            .get_mut(PacketView::PAYLOAD_OFFSET..)
+            .ok_or(SerializeError::TooBig)?;
+        let command = response.command().buf;
+        let size = if header.message_type == MessageType::Ack {
+            // the ack fields are encrypted with the payload, so the device can trust them
+            let mut plaintext = [0u8; PackedHeader::ACK_FIELDS_SIZE + COMMAND_SIZE];
+            let size = PackedHeader::ACK_FIELDS_SIZE + command.len();
+            plaintext[..PackedHeader::ACK_FIELDS_SIZE].copy_from_slice(&header.ack_fields());
+            plaintext[PackedHeader::ACK_FIELDS_SIZE..size].copy_from_slice(command);
+            noise.write_message(header.nonce(), &plaintext[..size], payload)?
+        } else {
+            noise.write_message(header.nonce(), command, payload)?
+        };
+
+        Ok(write_command_header(header, size, buf)?)
+    }
+
     fn make_transport_mode(&mut self) -> Result<bool, snow::Error> {
         match self.snow_state.take() {
             SnowState::Handshake(handshake) => {
@@ -221,9 +544,201 @@ impl SessionState {
     }
 }
 
+/// Deliver a payload held back for ordered delivery, it was acknowledged already.
+fn deliver_held_back(context: &SessionContext, device_id: u32, plaintext: &[u8]) {
+    if let Err(error) = handler::deliver(context, device_id, plaintext) {
+        log::error!("Failed to deliver message: {:?}", error);
+    }
+}
+
+impl PendingMessage {
+    /// Get the reassembly buffer for the fragment message.
+    /// Incomplete messages are dropped after [REASSEMBLY_TIMEOUT], a new message replaces the previous one.
+    pub fn for_fragment(pending: &mut Option<PendingMessage>, message_id: u16) -> &mut Self {
+        if let Some(message) = pending.as_ref() {
+            if message.started.elapsed() > REASSEMBLY_TIMEOUT {
+                log::warn!("Reassembly timed out, message dropped");
+                *pending = None;
+            } else if message.reassembly.message_id() != Some(message_id) {
+                log::warn!("New fragmented message started, incomplete message dropped");
+                *pending = None;
+            }
+        }
+
+        pending.get_or_insert_with(|| PendingMessage {
+            started: Instant::now(),
+            reassembly: Box::default(),
+        })
+    }
+}
+
 impl SnowState {
     /// Take the current state and replace it with `None`.
     fn take(&mut self) -> SnowState {
         std::mem::replace(self, SnowState::None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use shared_lib::{error::ErrorCode, parse_payload, qos::Qos};
+
+    use super::*;
+    use test_device::{TestApplication, TestDevice, TestServer};
+
+    #[tokio::test]
+    async fn forged_message_does_not_take_sequence() {
+        let mut server = TestServer::new(TestApplication::new(DeliveryMode::Unordered));
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // a forged message arrives first with the sequence of the authentic one
+        let (sequence, packet) = device.send(&[Payload::PING, 0, 0, 0, 1]);
+        session.send(device.forge(sequence)).await.unwrap();
+        let (header, body) = device.read(&server.response().await);
+        assert_eq!(header.message_type, MessageType::Error);
+        assert_eq!(ErrorCode::parse(&body), ErrorCode::DecryptFailed);
+        assert!(!header.acknowledges(sequence));
+
+        // the authentic message is processed, it is not a duplicate
+        session.send(packet).await.unwrap();
+        let (header, body) = device.read(&server.response().await);
+        assert_eq!(header.message_type, MessageType::Ack);
+        assert!(header.acknowledges(sequence));
+        assert_eq!(parse_payload(&body).unwrap(), Payload::Pong(1));
+    }
+
+    #[tokio::test]
+    async fn count_only_authenticated_messages() {
+        let mut server = TestServer::new(TestApplication::new(DeliveryMode::Unordered));
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        for sequence in [10, 11, u16::MAX] {
+            session.send(device.forge(sequence)).await.unwrap();
+            let (header, _) = device.read(&server.response().await);
+            assert_eq!(header.message_type, MessageType::Error);
+        }
+
+        // the close of the server follows the handshake, forged messages are not counted
+        session
+            .send(ChannelMessage::Close(CloseReason::Shutdown))
+            .await
+            .unwrap();
+        let (header, _) = device.read(&server.response().await);
+        assert_eq!(header.sequence, 2);
+        session
+            .send(device.acknowledge(header.sequence))
+            .await
+            .unwrap();
+        assert_eq!(server.closed().await, 1);
+    }
+
+    #[tokio::test]
+    async fn forged_message_does_not_take_ordered_slot() {
+        let application = TestApplication::new(DeliveryMode::Ordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // a forged message arrives first with the sequence of the second authentic one,
+        // then both authentic messages arrive out of order
+        let (_, first) = device.send(&[Payload::APPLICATION, b'a']);
+        let (sequence, second) = device.send(&[Payload::APPLICATION, b'b']);
+        for packet in [device.forge(sequence), second, first] {
+            session.send(packet).await.unwrap();
+            server.response().await;
+        }
+
+        assert_eq!(
+            application.data(),
+            vec![
+                (Qos::AtLeastOnce, b"a".to_vec()),
+                (Qos::AtLeastOnce, b"b".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn close_by_device() {
+        let mut server = TestServer::new(TestApplication::new(DeliveryMode::Unordered));
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // the close is echoed in the acknowledgement and the session ends
+        let (sequence, packet) = device.send(&[Payload::CLOSE, 0]);
+        session.send(packet).await.unwrap();
+        let (header, body) = device.read(&server.response().await);
+        assert_eq!(
+            (header.message_type, header.sequence),
+            (MessageType::Ack, sequence)
+        );
+        assert_eq!(
+            parse_payload(&body).unwrap(),
+            Payload::Close(CloseReason::Normal)
+        );
+        assert_eq!(server.closed().await, 1);
+        assert!(session.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmit_close_until_acknowledged() {
+        let mut server = TestServer::new(TestApplication::new(DeliveryMode::Unordered));
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // the first close is lost, it is sent again after the timeout
+        session
+            .send(ChannelMessage::Close(CloseReason::Shutdown))
+            .await
+            .unwrap();
+        let close = server.response().await;
+        let started = Instant::now();
+        assert_eq!(server.response().await.buf, close.buf);
+        assert_eq!(started.elapsed(), CLOSE_TIMEOUT);
+
+        let (header, body) = device.read(&close);
+        assert_eq!(header.message_type, MessageType::EncryptedMessage);
+        assert_eq!(
+            parse_payload(&body).unwrap(),
+            Payload::Close(CloseReason::Shutdown)
+        );
+        session
+            .send(device.acknowledge(header.sequence))
+            .await
+            .unwrap();
+        // no more retransmissions after the acknowledgement
+        assert_eq!(server.closed().await, 1);
+        assert_eq!(started.elapsed(), CLOSE_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn give_up_unacknowledged_close() {
+        let mut server = TestServer::new(TestApplication::new(DeliveryMode::Unordered));
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        session
+            .send(ChannelMessage::Close(CloseReason::Shutdown))
+            .await
+            .unwrap();
+        let started = Instant::now();
+        for _ in 0..=CLOSE_RETRIES {
+            server.response().await;
+        }
+        assert_eq!(server.closed().await, 1);
+        assert_eq!(started.elapsed(), CLOSE_TIMEOUT * (CLOSE_RETRIES + 1));
+    }
+}
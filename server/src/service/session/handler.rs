@@ -1,13 +1,21 @@
 use shared_lib::{
-    command::{EncodedCommand, COMMAND_SIZE},
-    error::SerializeError,
-    network::{MessageType, PackedHeader},
-    parse_command, parse_non_encrypted,
+    command::{Buffer, EncodedCommand, Payload, COMMAND_SIZE},
+    error::{ErrorCode, SerializeError},
+    firmware::MAX_FIRMWARE_BLOCK_SIZE,
+    handshake::HandshakeParams,
+    network::MessageType,
+    packet::PacketView,
+    parse_payload,
+    qos::Qos,
+    write_payload,
 };
 use thiserror::Error;
 use tracing::instrument;
 
-use crate::service::session::SnowState;
+use crate::service::{
+    session::{PendingMessage, SessionContext, SnowState},
+    DeliveryMode,
+};
 
 #[derive(Error, Debug)]
 pub enum ProcessingError {
@@ -23,23 +31,134 @@ pub enum ProcessingError {
     IncorrectState,
     #[error("Encryption error")]
     EncryptionError(#[from] snow::Error),
+    #[error("Payload is not expected from the device: {0}")]
+    UnexpectedPayload(&'static str),
+    #[error("Storage error: {0}")]
+    Storage(#[from] std::io::Error),
+}
+
+impl ProcessingError {
+    /// Code reported to the device in the Error message.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProcessingError::NotExpectedMessage(_) | ProcessingError::NotImplemented(_) => {
+                ErrorCode::UnsupportedType
+            }
+            ProcessingError::IncorrectHandshake { .. } | ProcessingError::IncorrectState => {
+                ErrorCode::BadState
+            }
+            ProcessingError::MessageCorrupted(
+                SerializeError::UnknownPayload | SerializeError::UnknownMessageType,
+            ) => ErrorCode::UnsupportedType,
+            ProcessingError::MessageCorrupted(_) => ErrorCode::Malformed,
+            ProcessingError::EncryptionError(_) => ErrorCode::DecryptFailed,
+            ProcessingError::UnexpectedPayload(_) => ErrorCode::UnexpectedPayload,
+            ProcessingError::Storage(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                ErrorCode::NotFound
+            }
+            ProcessingError::Storage(error) if error.kind() == std::io::ErrorKind::FileTooLarge => {
+                ErrorCode::TooLarge
+            }
+            ProcessingError::Storage(_) => ErrorCode::Internal,
+        }
+    }
 }
 
 /// Processed message, containing the message type and the command.
 pub struct ProcessedMessage {
     pub message_type: MessageType,
-    pub command: EncodedCommand,
+    pub size: usize,
+    pub buf: Buffer,
+    /// The command is a plaintext payload which has to be encrypted before sending.
+    pub encrypted: bool,
+    /// The response is sent to the client, fire-and-forget messages are not acknowledged.
+    pub respond: bool,
+    /// Plaintext payload held back for ordered delivery, see [DeliveryMode::Ordered].
+    pub delivery: Option<Vec<u8>>,
+    /// The session is closed after the response is sent.
+    pub close: bool,
 }
 
-#[instrument(skip_all, fields(seq=header.sequence, device=header.device_id, session=session_state.session_id))]
+impl ProcessedMessage {
+    /// Response without a body.
+    pub fn empty(message_type: MessageType) -> Self {
+        ProcessedMessage {
+            message_type,
+            size: 0,
+            buf: [0u8; COMMAND_SIZE],
+            encrypted: false,
+            respond: true,
+            delivery: None,
+            close: false,
+        }
+    }
+
+    /// Acknowledgement without a payload, only its ack fields are encrypted.
+    pub fn ack() -> Self {
+        ProcessedMessage {
+            encrypted: true,
+            ..Self::empty(MessageType::Ack)
+        }
+    }
+
+    /// Error response with the code in the body, it is not encrypted.
+    pub fn error(code: ErrorCode) -> Self {
+        let mut response = Self::empty(MessageType::Error);
+        response.buf[0] = code.into();
+        response.size = 1;
+        response
+    }
+
+    /// No response is sent.
+    pub fn no_response() -> Self {
+        ProcessedMessage {
+            respond: false,
+            ..Self::empty(MessageType::Ack)
+        }
+    }
+
+    /// Response with a payload, encrypted by the session before sending.
+    pub fn encrypted(
+        message_type: MessageType,
+        payload: &Payload,
+    ) -> Result<Self, ProcessingError> {
+        let mut buf = [0u8; COMMAND_SIZE];
+        let size = write_payload(payload, &mut buf)?;
+
+        Ok(ProcessedMessage {
+            message_type,
+            size,
+            buf,
+            encrypted: true,
+            respond: true,
+            delivery: None,
+            close: false,
+        })
+    }
+
+    /// Hold the plaintext payload back for ordered delivery.
+    fn with_delivery(mut self, delivery: Option<Vec<u8>>) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Command to be written into the response packet.
+    pub fn command(&self) -> EncodedCommand<'_> {
+        EncodedCommand::new(&self.buf[..self.size])
+    }
+}
+
+#[instrument(skip_all, fields(seq=packet.header().sequence, device=packet.header().device_id, session=session_state.session_id))]
 pub async fn process(
     session_state: &mut super::SessionState,
-    header: PackedHeader,
-    body: &[u8],
+    packet: &mut PacketView<'_>,
 ) -> Result<ProcessedMessage, ProcessingError> {
+    let header = packet.header();
     log::info!("Received message: {:?}", header);
 
-    match header.message_type {
+    let message_type = header.message_type;
+    let sequence = header.sequence;
+    match message_type {
         MessageType::HandshakeRequest => {
             // session should not be opened yet
             if header.session_id != 0 {
@@ -49,7 +168,7 @@ pub async fn process(
                 });
             }
 
-            let handshake_body = parse_command(body)?;
+            let handshake_body = packet.command();
             log::info!("Handshake body: {:?}", handshake_body);
 
             // expected handshake ready state
@@ -60,56 +179,576 @@ pub async fn process(
                 });
             };
 
-            // read handshake message
-            let EncodedCommand { size, buf } = handshake_body;
+            // read handshake message with the requested session parameters
             let mut read_buf = [0u8; COMMAND_SIZE];
-            noise.read_message(&buf[..size], &mut read_buf)?;
+            let read_size = noise.read_message(handshake_body.buf, &mut read_buf)?;
+            let requested = HandshakeParams::parse(&read_buf[..read_size])?;
+
+            // accept the keepalive interval within the server limits
+            let keepalive = session_state
+                .context
+                .keepalive
+                .accept(requested.keepalive_secs);
+            let accepted = HandshakeParams {
+                keepalive_secs: u16::try_from(keepalive.as_secs()).unwrap_or(u16::MAX),
+            };
+            log::info!("Keepalive interval: {:?}", keepalive);
 
-            // write handshake message
+            // write handshake message with the accepted session parameters
+            let mut params = [0u8; HandshakeParams::SIZE];
+            accepted.serialize(&mut params)?;
             let mut write_buf = [0u8; COMMAND_SIZE];
-            let write_size = noise.write_message(&[], &mut write_buf)?;
+            let write_size = noise.write_message(&params, &mut write_buf)?;
+            session_state.keepalive = keepalive;
 
             // transition to the next state
             session_state.make_transport_mode()?;
+            session_state.received.insert(sequence);
 
             // generate key and write back
             Ok(ProcessedMessage {
                 message_type: MessageType::HandshakeResponse,
-                command: EncodedCommand {
-                    size: write_size,
-                    buf: write_buf,
-                },
+                size: write_size,
+                buf: write_buf,
+                encrypted: false,
+                respond: true,
+                delivery: None,
+                close: false,
             })
         }
-        MessageType::HandshakeResponse => {
-            Err(ProcessingError::NotExpectedMessage(header.message_type))
-        }
+        MessageType::HandshakeResponse => Err(ProcessingError::NotExpectedMessage(message_type)),
         MessageType::EncryptedMessage => {
-            let SnowState::Transport(ref mut noise) = session_state.snow_state else {
+            let SnowState::Transport(ref noise) = session_state.snow_state else {
                 return Err(ProcessingError::IncorrectState);
             };
 
-            let encrypted_body = parse_command(body)?;
-            log::info!("Encrypted body: {:?}", encrypted_body);
+            log::info!("Encrypted body: {:?}", packet.command());
 
-            // read encrypted message
-            let EncodedCommand { size, buf } = encrypted_body;
-            let mut read_buf = [0u8; COMMAND_SIZE];
-            noise.read_message(header.nonce(), &buf[..size], &mut read_buf)?;
+            // decrypt message in place, using the session scratch buffer
+            let read_buf = &mut session_state.read_buf;
+            let plaintext = packet.decrypt_in_place(|nonce, payload| {
+                let size = noise.read_message(nonce, payload, read_buf)?;
+                payload[..size].copy_from_slice(&read_buf[..size]);
+                Ok::<_, snow::Error>(size)
+            })?;
+            // only authenticated sequences are acknowledged and treated as duplicates later
+            session_state.received.insert(sequence);
 
-            let decrypted_body = parse_non_encrypted(&read_buf[..size])?;
-            log::info!("Decrypted body: {:?}", decrypted_body);
+            let context = &session_state.context;
+            let device_id = session_state.device_id;
+            let ordered = session_state.delivery_mode == DeliveryMode::Ordered;
+            let delivery = match parse_payload(plaintext)? {
+                Payload::Fragment(fragment) => {
+                    log::info!(
+                        "Received fragment {}/{} of message {}",
+                        fragment.index + 1,
+                        fragment.count,
+                        fragment.message_id
+                    );
 
-            Ok(ProcessedMessage {
-                message_type: MessageType::Ack,
-                command: EncodedCommand {
-                    size: 0,
-                    buf: [0u8; COMMAND_SIZE],
-                },
-            })
+                    let pending = PendingMessage::for_fragment(
+                        &mut session_state.pending_message,
+                        fragment.message_id,
+                    );
+                    let delivery = match pending.reassembly.push(&fragment)? {
+                        Some((kind, message)) => {
+                            log::info!("Reassembled message: {} bytes", message.len());
+                            let plaintext = [&[kind], message].concat();
+                            let delivery = accept(context, device_id, ordered, plaintext)?;
+                            session_state.pending_message = None;
+                            delivery
+                        }
+                        None => None,
+                    };
+                    delivery
+                }
+                Payload::Block(block) => {
+                    log::info!(
+                        "Received block {} of [{}]: {} bytes",
+                        block.index,
+                        block.name,
+                        block.data.len()
+                    );
+
+                    let uploads = &context.uploads;
+                    let (ack, completed) = uploads.write_block(device_id, &block).await?;
+                    if completed {
+                        let path = uploads.path(device_id, block.name)?;
+                        context
+                            .application
+                            .on_upload_complete(device_id, block.name, &path);
+                    }
+                    return ProcessedMessage::encrypted(MessageType::Ack, &Payload::BlockAck(ack));
+                }
+                Payload::UploadQuery(query) => {
+                    let ack = context.uploads.query(device_id, &query).await?;
+                    log::info!("Upload [{}] state: {:?}", query.name, ack);
+                    return ProcessedMessage::encrypted(MessageType::Ack, &Payload::BlockAck(ack));
+                }
+                Payload::FirmwareQuery(query) => {
+                    let info = context.firmware.info(&query).await?;
+                    log::info!("Firmware [{}] info: {:?}", query.name, info);
+                    return ProcessedMessage::encrypted(
+                        MessageType::Ack,
+                        &Payload::FirmwareInfo(info),
+                    );
+                }
+                Payload::FirmwareRequest(request) => {
+                    let mut buf = [0u8; MAX_FIRMWARE_BLOCK_SIZE as usize];
+                    let block = context.firmware.read_block(&request, &mut buf).await?;
+                    log::info!(
+                        "Sending firmware [{}] block {}: {} bytes",
+                        request.name,
+                        block.index,
+                        block.data.len()
+                    );
+                    return ProcessedMessage::encrypted(MessageType::Ack, &Payload::Block(block));
+                }
+                Payload::Ping(token) => {
+                    log::info!("Keepalive from device [{device_id}]");
+                    return ProcessedMessage::encrypted(MessageType::Ack, &Payload::Pong(token));
+                }
+                Payload::Close(reason) => {
+                    log::info!("Device [{device_id}] closes the session: {reason:?}");
+                    // the close is echoed, so the device knows it was authenticated
+                    let mut response =
+                        ProcessedMessage::encrypted(MessageType::Ack, &Payload::Close(reason))?;
+                    response.close = true;
+                    return Ok(response);
+                }
+                Payload::Qos(message) => {
+                    if message.qos == Qos::ExactlyOnce
+                        && context
+                            .deliveries
+                            .is_delivered(device_id, message.message_id)
+                    {
+                        // the previous ack was lost, acknowledge it again
+                        log::info!(
+                            "Message {} is already delivered, ignored",
+                            message.message_id
+                        );
+                        return Ok(ProcessedMessage::ack());
+                    }
+
+                    let delivery = accept(context, device_id, ordered, plaintext.to_vec())?;
+                    if message.qos == Qos::AtMostOnce {
+                        return Ok(ProcessedMessage::no_response().with_delivery(delivery));
+                    }
+                    delivery
+                }
+                _ => accept(context, device_id, ordered, plaintext.to_vec())?,
+            };
+
+            Ok(ProcessedMessage::ack().with_delivery(delivery))
+        }
+        MessageType::Ack => Err(ProcessingError::NotImplemented(message_type)),
+        MessageType::Timeout => Err(ProcessingError::NotExpectedMessage(message_type)),
+        MessageType::Error => Err(ProcessingError::NotImplemented(message_type)),
+    }
+}
+
+/// Deliver the plaintext payload now, or return it to be held back for ordered delivery.
+/// The payload is validated before it is accepted in both cases.
+///
+/// Exactly-once messages are recorded as delivered once they are accepted, so a replay
+/// in another session is dropped even while the message is still held back.
+fn accept(
+    context: &SessionContext,
+    device_id: u32,
+    ordered: bool,
+    plaintext: Vec<u8>,
+) -> Result<Option<Vec<u8>>, ProcessingError> {
+    let payload = parse_payload(&plaintext)?;
+    validate(&payload)?;
+    if let Payload::Qos(message) = payload {
+        if message.qos == Qos::ExactlyOnce {
+            context.deliveries.record(device_id, message.message_id);
+        }
+    }
+
+    if ordered {
+        return Ok(Some(plaintext));
+    }
+
+    deliver(context, device_id, &plaintext)?;
+    Ok(None)
+}
+
+/// Deliver a complete plaintext payload to the application.
+pub fn deliver(
+    context: &SessionContext,
+    device_id: u32,
+    plaintext: &[u8],
+) -> Result<(), ProcessingError> {
+    let payload = parse_payload(plaintext)?;
+    validate(&payload)?;
+
+    let application = context.application.as_ref();
+    let (qos, payload) = match payload {
+        Payload::Qos(message) => (message.qos, message.payload()?),
+        payload => (Qos::AtLeastOnce, payload),
+    };
+
+    match payload {
+        Payload::Telemetry(telemetry) => {
+            for reading in telemetry.readings().flatten() {
+                application.on_reading(device_id, qos, reading);
+            }
+        }
+        Payload::Application(data) => {
+            application.on_application_data(device_id, qos, data);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Check that the payload can be delivered to the application.
+fn validate(payload: &Payload) -> Result<(), ProcessingError> {
+    match payload {
+        Payload::Telemetry(telemetry) => {
+            // validate the whole batch first, a corrupted batch is not delivered at all
+            let count = telemetry
+                .readings()
+                .try_fold(0, |count, reading| reading.map(|_| count + 1))?;
+            log::info!("Received {count} readings");
+        }
+        Payload::Application(_) => {}
+        Payload::Fragment(_) => {
+            // fragments cannot be nested
+            return Err(ProcessingError::MessageCorrupted(
+                SerializeError::InvalidFragment,
+            ));
         }
-        MessageType::Ack => Err(ProcessingError::NotImplemented(header.message_type)),
-        MessageType::Timeout => Err(ProcessingError::NotExpectedMessage(header.message_type)),
-        MessageType::Error => Err(ProcessingError::NotImplemented(header.message_type)),
+        Payload::Block(_) | Payload::UploadQuery(_) => {
+            // uploads are not reassembled from fragments, blocks fit into one datagram
+            return Err(ProcessingError::UnexpectedPayload("fragmented upload"));
+        }
+        Payload::FirmwareQuery(_) | Payload::FirmwareRequest(_) => {
+            return Err(ProcessingError::UnexpectedPayload(
+                "fragmented firmware request",
+            ));
+        }
+        Payload::Qos(message) => match message.payload()? {
+            Payload::Qos(_) => {
+                return Err(ProcessingError::UnexpectedPayload("nested QoS message"));
+            }
+            payload => validate(&payload)?,
+        },
+        Payload::Ping(_) => {
+            return Err(ProcessingError::UnexpectedPayload("wrapped keepalive"));
+        }
+        Payload::Close(_) => {
+            return Err(ProcessingError::UnexpectedPayload("wrapped close"));
+        }
+        Payload::BlockAck(_) | Payload::FirmwareInfo(_) | Payload::Pong(_) => {
+            // server to device payloads
+            return Err(ProcessingError::UnexpectedPayload("server payload"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shared_lib::{
+        close::CloseReason,
+        transfer::{Block, BlockAck},
+        write_payload, write_qos_payload,
+    };
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::service::session::{
+        test_device::{TestApplication, TestDevice, TestServer, MAX_UPLOAD_SIZE},
+        KeepaliveLimits, KEEPALIVE_MISSES,
+    };
+
+    const LIMITS: KeepaliveLimits = KeepaliveLimits {
+        default: Duration::from_secs(60),
+        min: Duration::from_secs(10),
+        max: Duration::from_secs(600),
+    };
+
+    fn qos_payload(qos: Qos, message_id: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = [0u8; COMMAND_SIZE];
+        let size =
+            write_qos_payload(qos, message_id, &Payload::Application(data), &mut buf).unwrap();
+        buf[..size].to_vec()
+    }
+
+    fn block_payload(name: &str, index: u32, block_size: u16, last: bool) -> Vec<u8> {
+        let data = vec![index as u8; usize::from(block_size)];
+        let block = Block {
+            name,
+            index,
+            block_size,
+            last,
+            data: &data,
+        };
+        let mut buf = [0u8; COMMAND_SIZE];
+        let size = write_payload(&Payload::Block(block), &mut buf).unwrap();
+        buf[..size].to_vec()
+    }
+
+    #[tokio::test]
+    async fn drop_replay_of_held_back_message() {
+        let application = TestApplication::new(DeliveryMode::Ordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(7);
+        let message = qos_payload(Qos::ExactlyOnce, 42, b"once");
+
+        let first = server.spawn(7, 1);
+        first.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // the message before it is lost, so the message is held back
+        let _lost = device.send(&[Payload::PING, 0, 0, 0, 1]);
+        let (_, packet) = device.send(&message);
+        first.send(packet).await.unwrap();
+        let (header, _) = device.read(&server.response().await);
+        assert_eq!(header.message_type, MessageType::Ack);
+        assert!(application.data().is_empty());
+
+        // the ack was lost, the device replays the message after a re-handshake
+        let second = server.spawn(7, 2);
+        second.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+        let (_, packet) = device.send(&message);
+        second.send(packet).await.unwrap();
+        let (header, _) = device.read(&server.response().await);
+        assert_eq!(header.message_type, MessageType::Ack);
+        assert!(application.data().is_empty());
+
+        // the held back message is delivered once, when the first session finishes
+        drop(first);
+        assert_eq!(server.closed().await, 1);
+        drop(second);
+        assert_eq!(server.closed().await, 2);
+        assert_eq!(
+            application.data(),
+            vec![(Qos::ExactlyOnce, b"once".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn acknowledge_by_qos() {
+        let application = TestApplication::new(DeliveryMode::Unordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // at-most-once is delivered without an ack, the next response is the pong
+        let (_, packet) = device.send(&qos_payload(Qos::AtMostOnce, 1, b"status"));
+        session.send(packet).await.unwrap();
+        let (ping, packet) = device.send(&[Payload::PING, 0, 0, 0, 1]);
+        session.send(packet).await.unwrap();
+        let (header, body) = device.read(&server.response().await);
+        assert_eq!(header.sequence, ping);
+        assert_eq!(parse_payload(&body).unwrap(), Payload::Pong(1));
+
+        // at-least-once is acknowledged, the retransmission gets the same ack again
+        let (sequence, packet) = device.send(&qos_payload(Qos::AtLeastOnce, 2, b"event"));
+        session.send(packet).await.unwrap();
+        let ack = server.response().await;
+        let (header, _) = device.read(&ack);
+        assert_eq!(
+            (header.message_type, header.sequence),
+            (MessageType::Ack, sequence)
+        );
+        let packet = device.send_with(sequence, &qos_payload(Qos::AtLeastOnce, 2, b"event"));
+        session.send(packet).await.unwrap();
+        assert_eq!(server.response().await.buf, ack.buf);
+
+        assert_eq!(
+            application.data(),
+            vec![
+                (Qos::AtMostOnce, b"status".to_vec()),
+                (Qos::AtLeastOnce, b"event".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_exactly_once_duplicate_in_new_session() {
+        let application = TestApplication::new(DeliveryMode::Unordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(7);
+
+        for session_id in [1, 2] {
+            let session = server.spawn(7, session_id);
+            session.send(device.handshake(0)).await.unwrap();
+            device.accept(&server.response().await);
+
+            // the ack was lost, both messages are sent again in the new session
+            for message in [
+                qos_payload(Qos::ExactlyOnce, 42, b"once"),
+                qos_payload(Qos::AtLeastOnce, 43, b"twice"),
+            ] {
+                let (sequence, packet) = device.send(&message);
+                session.send(packet).await.unwrap();
+                let (header, _) = device.read(&server.response().await);
+                assert_eq!(
+                    (header.message_type, header.sequence),
+                    (MessageType::Ack, sequence)
+                );
+            }
+        }
+
+        assert_eq!(
+            application.data(),
+            vec![
+                (Qos::ExactlyOnce, b"once".to_vec()),
+                (Qos::AtLeastOnce, b"twice".to_vec()),
+                (Qos::AtLeastOnce, b"twice".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn negotiate_keepalive() {
+        let mut server =
+            TestServer::new(TestApplication::new(DeliveryMode::Unordered)).keepalive(LIMITS);
+
+        // zero asks for the default, other intervals are clamped to the limits
+        for (session_id, requested, accepted) in
+            [(1, 0, 60), (2, 5, 10), (3, 30, 30), (4, 1000, 600)]
+        {
+            let mut device = TestDevice::new(7);
+            let session = server.spawn(7, session_id);
+            session.send(device.handshake(requested)).await.unwrap();
+            let params = device.accept(&server.response().await);
+            assert_eq!(params.keepalive_secs, accepted);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_idle_session() {
+        let mut server =
+            TestServer::new(TestApplication::new(DeliveryMode::Unordered)).keepalive(LIMITS);
+        let mut device = TestDevice::new(7);
+        let session = server.spawn(7, 1);
+        session.send(device.handshake(30)).await.unwrap();
+        let keepalive = Duration::from_secs(u64::from(
+            device.accept(&server.response().await).keepalive_secs,
+        ));
+        let started = Instant::now();
+
+        // a ping before the missed intervals keeps the session alive
+        tokio::time::sleep(keepalive * (KEEPALIVE_MISSES - 1)).await;
+        let (_, packet) = device.send(&[Payload::PING, 0, 0, 0, 1]);
+        session.send(packet).await.unwrap();
+        let (_, body) = device.read(&server.response().await);
+        assert_eq!(parse_payload(&body).unwrap(), Payload::Pong(1));
+
+        // without messages the session is closed after the missed intervals
+        let close = server.response().await;
+        assert_eq!(
+            started.elapsed(),
+            keepalive * (KEEPALIVE_MISSES - 1) + keepalive * KEEPALIVE_MISSES
+        );
+        let (header, body) = device.read(&close);
+        assert_eq!(
+            parse_payload(&body).unwrap(),
+            Payload::Close(CloseReason::Idle)
+        );
+        session
+            .send(device.acknowledge(header.sequence))
+            .await
+            .unwrap();
+        assert_eq!(server.closed().await, 1);
+    }
+
+    #[tokio::test]
+    async fn complete_upload_once() {
+        let application = TestApplication::new(DeliveryMode::Unordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(21);
+        let session = server.spawn(21, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        // the ack of the last block is lost, so the device sends it again
+        for (index, last) in [(0, false), (1, true), (1, true)] {
+            let (_, packet) = device.send(&block_payload("log.bin", index, 64, last));
+            session.send(packet).await.unwrap();
+            let (_, body) = device.read(&server.response().await);
+            assert_eq!(
+                parse_payload(&body).unwrap(),
+                Payload::BlockAck(BlockAck {
+                    next_block: index + 1,
+                    complete: last
+                })
+            );
+        }
+
+        assert_eq!(application.uploads(), vec!["log.bin".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn complete_single_block_upload_once() {
+        let application = TestApplication::new(DeliveryMode::Unordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(23);
+
+        // the ack is lost, the only block is sent again after a reconnect,
+        // an upload named like a part file is an upload of its own
+        for (session_id, name) in [(1, "log.bin"), (2, "log.bin"), (2, "log.bin.part")] {
+            let session = server.spawn(23, session_id);
+            session.send(device.handshake(0)).await.unwrap();
+            device.accept(&server.response().await);
+            let (_, packet) = device.send(&block_payload(name, 0, 64, true));
+            session.send(packet).await.unwrap();
+            let (_, body) = device.read(&server.response().await);
+            assert_eq!(
+                parse_payload(&body).unwrap(),
+                Payload::BlockAck(BlockAck {
+                    next_block: 1,
+                    complete: true
+                })
+            );
+        }
+
+        assert_eq!(
+            application.uploads(),
+            vec!["log.bin".to_owned(), "log.bin.part".to_owned()]
+        );
+        for name in ["log.bin", "log.bin.part"] {
+            let path = server.uploads(23).join(name);
+            assert_eq!(tokio::fs::metadata(path).await.unwrap().len(), 64);
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_too_large_upload() {
+        let application = TestApplication::new(DeliveryMode::Unordered);
+        let mut server = TestServer::new(application.clone());
+        let mut device = TestDevice::new(22);
+        let session = server.spawn(22, 1);
+        session.send(device.handshake(0)).await.unwrap();
+        device.accept(&server.response().await);
+
+        let block_size = 300;
+        let fitting = (MAX_UPLOAD_SIZE / block_size) as u32;
+        for index in 0..fitting {
+            let (_, packet) =
+                device.send(&block_payload("dump.bin", index, block_size as u16, false));
+            session.send(packet).await.unwrap();
+            let (header, _) = device.read(&server.response().await);
+            assert_eq!(header.message_type, MessageType::Ack);
+        }
+
+        // the next block would grow the upload over the limit
+        let (_, packet) = device.send(&block_payload("dump.bin", fitting, block_size as u16, true));
+        session.send(packet).await.unwrap();
+        let (header, body) = device.read(&server.response().await);
+        assert_eq!(header.message_type, MessageType::Error);
+        assert_eq!(ErrorCode::parse(&body), ErrorCode::TooLarge);
+        assert!(application.uploads().is_empty());
     }
 }
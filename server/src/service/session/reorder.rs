@@ -0,0 +1,177 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use shared_lib::ack::sequence_newer;
+
+/// Message waiting for the missing messages before it.
+struct Pending<T> {
+    sequence: u16,
+    received: Instant,
+    item: Option<T>,
+}
+
+/// Restores the order of messages received out of order.
+///
+/// Every authenticated sequence is pushed, with an item to deliver or without it. Items are
+/// delivered in the sequence order, a missing sequence holds the following ones back until
+/// it is received, the buffer is full or the oldest held message waits longer than the timeout.
+/// Then the gap is skipped, a skipped message received later is delivered immediately.
+pub struct ReorderBuffer<T> {
+    capacity: usize,
+    timeout: Duration,
+    // the next sequence to deliver, unknown before the first message
+    next: Option<u16>,
+    // messages after a gap, sorted by sequence
+    pending: VecDeque<Pending<T>>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(capacity: usize, timeout: Duration) -> Self {
+        ReorderBuffer {
+            capacity,
+            timeout,
+            next: None,
+            pending: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Accept the processed sequence, `item` is `None` if the message has nothing to deliver.
+    /// Items which are ready are passed to `deliver` in order.
+    pub fn push(
+        &mut self,
+        sequence: u16,
+        item: Option<T>,
+        now: Instant,
+        mut deliver: impl FnMut(T),
+    ) {
+        let next = *self.next.get_or_insert(sequence);
+
+        if sequence == next {
+            self.next = Some(next.wrapping_add(1));
+            item.into_iter().for_each(&mut deliver);
+            self.drain_ready(&mut deliver);
+        } else if sequence_newer(sequence, next) {
+            let index = self
+                .pending
+                .partition_point(|pending| sequence_newer(sequence, pending.sequence));
+            if self
+                .pending
+                .get(index)
+                .is_some_and(|pending| pending.sequence == sequence)
+            {
+                // duplicates are filtered by the session, nothing to do
+                return;
+            }
+            self.pending.insert(
+                index,
+                Pending {
+                    sequence,
+                    received: now,
+                    item,
+                },
+            );
+
+            if self.pending.len() > self.capacity {
+                self.skip_gap(&mut deliver);
+            }
+        } else {
+            log::warn!("Message {sequence} arrived after its gap was skipped, delivered late");
+            item.into_iter().for_each(&mut deliver);
+        }
+    }
+
+    /// Time when the oldest held message stops waiting for the gap before it.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .front()
+            .map(|pending| pending.received + self.timeout)
+    }
+
+    /// Skip the gaps which are waited for longer than the timeout.
+    pub fn poll_timeout(&mut self, now: Instant, mut deliver: impl FnMut(T)) {
+        while self.deadline().is_some_and(|deadline| deadline <= now) {
+            self.skip_gap(&mut deliver);
+        }
+    }
+
+    /// Skip all gaps and deliver the held messages, e.g. when the session is closed.
+    pub fn flush(&mut self, mut deliver: impl FnMut(T)) {
+        while !self.pending.is_empty() {
+            self.skip_gap(&mut deliver);
+        }
+    }
+
+    /// Give up on the missing messages before the oldest held one.
+    fn skip_gap(&mut self, deliver: &mut impl FnMut(T)) {
+        let (Some(next), Some(oldest)) = (self.next, self.pending.front()) else {
+            return;
+        };
+
+        log::warn!("Messages {next}..{} are missing, skipped", oldest.sequence);
+        self.next = Some(oldest.sequence);
+        self.drain_ready(deliver);
+    }
+
+    fn drain_ready(&mut self, deliver: &mut impl FnMut(T)) {
+        while let Some(next) = self.next {
+            if self
+                .pending
+                .front()
+                .is_none_or(|pending| pending.sequence != next)
+            {
+                break;
+            }
+
+            if let Some(pending) = self.pending.pop_front() {
+                self.next = Some(next.wrapping_add(1));
+                pending.item.into_iter().for_each(&mut *deliver);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reorder_and_skip_gaps() {
+        let now = Instant::now();
+        let mut buffer = ReorderBuffer::new(2, Duration::from_secs(1));
+        let mut delivered = Vec::new();
+
+        // 1 has nothing to deliver, 3 waits for 2
+        buffer.push(1, None, now, |item| delivered.push(item));
+        buffer.push(3, Some(3), now, |item| delivered.push(item));
+        assert!(delivered.is_empty());
+        buffer.push(2, Some(2), now, |item| delivered.push(item));
+        assert_eq!(delivered, [2, 3]);
+
+        // the gap at 4 is skipped when the buffer is full
+        for sequence in 5..=7 {
+            buffer.push(sequence, Some(sequence), now, |item| delivered.push(item));
+        }
+        assert_eq!(delivered, [2, 3, 5, 6, 7]);
+
+        // late message is delivered immediately
+        buffer.push(4, Some(4), now, |item| delivered.push(item));
+        assert_eq!(delivered, [2, 3, 5, 6, 7, 4]);
+
+        // the gap at 8 is skipped after the timeout
+        buffer.push(9, Some(9), now, |item| delivered.push(item));
+        assert_eq!(buffer.deadline(), Some(now + Duration::from_secs(1)));
+        buffer.poll_timeout(now, |item| delivered.push(item));
+        assert_eq!(delivered.len(), 6);
+        buffer.poll_timeout(now + Duration::from_secs(1), |item| delivered.push(item));
+        assert_eq!(delivered, [2, 3, 5, 6, 7, 4, 9]);
+        assert_eq!(buffer.deadline(), None);
+
+        // held messages are delivered when the session is closed
+        buffer.push(12, Some(12), now, |item| delivered.push(item));
+        buffer.push(11, Some(11), now, |item| delivered.push(item));
+        buffer.flush(|item| delivered.push(item));
+        assert_eq!(delivered, [2, 3, 5, 6, 7, 4, 9, 11, 12]);
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use shared_lib::{
+    command::{EncodedCommand, COMMAND_SIZE},
+    handshake::HandshakeParams,
+    network::{MessageType, PackedHeader},
+    packet::{PacketBuffer, PacketView},
+    qos::Qos,
+    telemetry::Reading,
+    write_command,
+};
+use tempfile::TempDir;
+use tokio::sync::mpsc::{self, Receiver, UnboundedReceiver};
+
+use super::{ChannelMessage, KeepaliveLimits, Session, SessionContext, ENC_PATTERN};
+use crate::service::{
+    deliveries::DeliveryLog, firmware::FirmwareStore, upload::UploadStore, Application,
+    DeliveryMode, Response,
+};
+
+/// Size limit of the uploads in tests.
+pub const MAX_UPLOAD_SIZE: u64 = 1024;
+
+/// Address of the test device.
+pub const ADDR: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(
+    std::net::Ipv4Addr::LOCALHOST,
+    5000,
+));
+
+/// Application which records everything delivered to it.
+pub struct TestApplication {
+    mode: DeliveryMode,
+    data: Mutex<Vec<(Qos, Vec<u8>)>>,
+    uploads: Mutex<Vec<String>>,
+}
+
+impl TestApplication {
+    pub fn new(mode: DeliveryMode) -> Arc<Self> {
+        Arc::new(TestApplication {
+            mode,
+            data: Mutex::default(),
+            uploads: Mutex::default(),
+        })
+    }
+
+    /// Application data delivered so far.
+    pub fn data(&self) -> Vec<(Qos, Vec<u8>)> {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// Names of the uploads completed so far.
+    pub fn uploads(&self) -> Vec<String> {
+        self.uploads.lock().unwrap().clone()
+    }
+}
+
+impl Application for TestApplication {
+    fn on_reading(&self, _device_id: u32, _qos: Qos, _reading: Reading) {}
+
+    fn on_application_data(&self, _device_id: u32, qos: Qos, data: &[u8]) {
+        self.data.lock().unwrap().push((qos, data.to_vec()));
+    }
+
+    fn on_upload_complete(&self, _device_id: u32, name: &str, _path: &Path) {
+        self.uploads.lock().unwrap().push(name.to_owned());
+    }
+
+    fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
+    }
+}
+
+/// Server side of the sessions for tests: spawns session tasks sharing one context
+/// and collects their responses instead of sending them.
+pub struct TestServer {
+    // removed with the server at the end of the test
+    dir: TempDir,
+    context: SessionContext,
+    responses: Receiver<Response>,
+    closed: UnboundedReceiver<u16>,
+}
+
+impl TestServer {
+    /// Server with the stores in a temp directory of its own.
+    pub fn new(application: Arc<TestApplication>) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let (response_queue, responses) = mpsc::channel(16);
+        let (closed_sessions, closed) = mpsc::unbounded_channel();
+        let context = SessionContext {
+            response_queue,
+            application,
+            uploads: Arc::new(UploadStore::new(
+                dir.path().join("uploads"),
+                MAX_UPLOAD_SIZE,
+            )),
+            firmware: Arc::new(FirmwareStore::new(dir.path().join("firmware"))),
+            deliveries: Arc::new(DeliveryLog::default()),
+            keepalive: KeepaliveLimits {
+                default: Duration::from_secs(60),
+                min: Duration::from_secs(10),
+                max: Duration::from_secs(600),
+            },
+            closed_sessions,
+        };
+        TestServer {
+            dir,
+            context,
+            responses,
+            closed,
+        }
+    }
+
+    pub fn keepalive(mut self, keepalive: KeepaliveLimits) -> Self {
+        self.context.keepalive = keepalive;
+        self
+    }
+
+    /// Directory of the completed uploads of the device.
+    pub fn uploads(&self, device_id: u32) -> PathBuf {
+        self.dir.path().join("uploads").join(device_id.to_string())
+    }
+
+    pub fn spawn(&self, device_id: u32, session_id: u16) -> mpsc::Sender<ChannelMessage> {
+        Session::spawn_new(device_id, session_id, self.context.clone())
+    }
+
+    /// Next response of any session.
+    pub async fn response(&mut self) -> Response {
+        self.responses
+            .recv()
+            .await
+            .expect("response queue is closed")
+    }
+
+    /// Id of the next finished session.
+    pub async fn closed(&mut self) -> u16 {
+        self.closed.recv().await.expect("closed queue is dropped")
+    }
+}
+
+/// Device side of a session for tests, it builds the datagrams by hand.
+pub struct TestDevice {
+    device_id: u32,
+    session_id: u16,
+    sequence: u16,
+    handshake: Option<snow::HandshakeState>,
+    transport: Option<snow::StatelessTransportState>,
+}
+
+impl TestDevice {
+    pub fn new(device_id: u32) -> Self {
+        TestDevice {
+            device_id,
+            session_id: 0,
+            sequence: 0,
+            handshake: None,
+            transport: None,
+        }
+    }
+
+    /// Start a new session, asking for the keepalive interval.
+    pub fn handshake(&mut self, keepalive_secs: u16) -> ChannelMessage {
+        let mut initiator = snow::Builder::new(ENC_PATTERN.parse().unwrap())
+            .build_initiator()
+            .unwrap();
+        let mut params = [0u8; HandshakeParams::SIZE];
+        HandshakeParams { keepalive_secs }
+            .serialize(&mut params)
+            .unwrap();
+        let mut body = [0u8; COMMAND_SIZE];
+        let size = initiator.write_message(&params, &mut body).unwrap();
+        self.handshake = Some(initiator);
+        self.session_id = 0;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        let header = self.header(MessageType::HandshakeRequest, self.sequence);
+        packet(&header, &body[..size])
+    }
+
+    /// Finish the handshake with the response. Returns the accepted parameters.
+    pub fn accept(&mut self, response: &Response) -> HandshakeParams {
+        let (header, body) = parse(response);
+        assert_eq!(header.message_type, MessageType::HandshakeResponse);
+
+        let mut initiator = self.handshake.take().expect("handshake is not started");
+        let mut read_buf = [0u8; COMMAND_SIZE];
+        let size = initiator.read_message(&body, &mut read_buf).unwrap();
+        self.transport = Some(initiator.into_stateless_transport_mode().unwrap());
+        self.session_id = header.session_id;
+        HandshakeParams::parse(&read_buf[..size]).unwrap()
+    }
+
+    /// Encrypt the payload with the next sequence. Returns the sequence and the datagram.
+    pub fn send(&mut self, plaintext: &[u8]) -> (u16, ChannelMessage) {
+        self.sequence = self.sequence.wrapping_add(1);
+        (self.sequence, self.send_with(self.sequence, plaintext))
+    }
+
+    /// Encrypt the payload with the given sequence, e.g. to send it again.
+    pub fn send_with(&self, sequence: u16, plaintext: &[u8]) -> ChannelMessage {
+        let header = self.header(MessageType::EncryptedMessage, sequence);
+        let transport = self.transport.as_ref().expect("session is not open");
+        let mut body = [0u8; COMMAND_SIZE];
+        let size = transport
+            .write_message(header.nonce(), plaintext, &mut body)
+            .unwrap();
+        packet(&header, &body[..size])
+    }
+
+    /// Acknowledge the server message with `sequence`, e.g. its close.
+    pub fn acknowledge(&self, sequence: u16) -> ChannelMessage {
+        let header = PackedHeader::new(
+            MessageType::Ack,
+            self.device_id,
+            self.session_id,
+            self.sequence,
+            sequence,
+        );
+        packet(&header, &[])
+    }
+
+    /// Message with the given sequence and garbage instead of the encrypted payload.
+    pub fn forge(&self, sequence: u16) -> ChannelMessage {
+        let header = self.header(MessageType::EncryptedMessage, sequence);
+        packet(&header, &[0xa5; 40])
+    }
+
+    /// Read a response, encrypted bodies are decrypted and the authenticated ack fields
+    /// are checked and removed. Returns the header and the body.
+    pub fn read(&self, response: &Response) -> (PackedHeader, Vec<u8>) {
+        let (header, body) = parse(response);
+        if body.is_empty() || header.message_type == MessageType::Error {
+            return (header, body);
+        }
+
+        let transport = self.transport.as_ref().expect("session is not open");
+        let mut plaintext = [0u8; COMMAND_SIZE];
+        let size = transport
+            .read_message(header.nonce(), &body, &mut plaintext)
+            .unwrap();
+        let mut plaintext = &plaintext[..size];
+        if header.message_type == MessageType::Ack {
+            plaintext = plaintext
+                .strip_prefix(&header.ack_fields()[..])
+                .expect("ack fields are not authenticated");
+        }
+        (header, plaintext.to_vec())
+    }
+
+    fn header(&self, message_type: MessageType, sequence: u16) -> PackedHeader {
+        PackedHeader::new(message_type, self.device_id, self.session_id, sequence, 0)
+    }
+}
+
+fn packet(header: &PackedHeader, body: &[u8]) -> ChannelMessage {
+    let mut packet = PacketBuffer::new();
+    let _ = packet.resize_default(packet.capacity());
+    let size = write_command(header, &EncodedCommand::new(body), &mut packet).unwrap();
+    packet.truncate(size);
+    ChannelMessage::Packet { addr: ADDR, packet }
+}
+
+fn parse(response: &Response) -> (PackedHeader, Vec<u8>) {
+    let mut buf = response.buf.clone();
+    let header = PackedHeader::try_deserialize(&buf).unwrap();
+    let packet = PacketView::parse(&mut buf).unwrap();
+    (header, packet.payload().to_vec())
+}
@@ -1,25 +1,39 @@
-use std::{collections::HashMap, net::SocketAddr, time::Instant};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use shared_lib::{error::SerializeError, network::PackedHeader};
+use shared_lib::{
+    ack::{sequence_newer, ACK_BITS},
+    close::CloseReason,
+    command::{EncodedCommand, PACKET_SIZE},
+    error::SerializeError,
+    network::{MessageType, PackedHeader},
+    packet::{PacketBuffer, PacketView},
+    write_command,
+};
 use thiserror::Error;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
 
 const SESSIONS_MAX_COUNT: usize = 100;
 
-/// Timeout for inactive sessions.
-/// This is used to remove inactive sessions.
-const INACTIVE_SESSION_TIMEOUT_SEC: u64 = 15 * 160; // 15 minutes
+/// Every address gets one timeout answer per interval.
+const TIMEOUT_ANSWER_INTERVAL: Duration = Duration::from_secs(1);
+/// Timeout answers to all addresses per interval.
+const TIMEOUT_ANSWER_BURST: usize = 32;
 
-use super::{
-    session::{self, Session},
-    Response,
-};
+use super::limiter::RateLimiter;
+use super::session::{self, Session, SessionContext};
+use super::Response;
 
 /// Server state. Contains the last session ID and a map of sessions.
 pub struct State {
-    pub sender: Sender<Response>,
+    context: SessionContext,
     last_session_id: u16,
     sessions: HashMap<u16, Session>,
+    // limits the timeout answers to unknown sessions
+    timeout_limiter: RateLimiter,
 }
 
 #[derive(Error, Debug)]
@@ -32,42 +46,51 @@ pub enum ProcessingError {
     SessionsNotFound(u16),
     #[error("Session has been closed: {0}")]
     SessionClosed(u16),
+    #[error("Session queue is full, message dropped: {0}")]
+    SessionBusy(u16),
 }
 
 impl State {
-    pub fn new(sender: Sender<Response>) -> Self {
+    pub fn new(context: SessionContext) -> Self {
         Self {
-            sender,
+            context,
             last_session_id: 0,
             sessions: HashMap::new(),
+            timeout_limiter: RateLimiter::new(
+                TIMEOUT_ANSWER_INTERVAL,
+                TIMEOUT_ANSWER_BURST,
+                Instant::now(),
+            ),
         }
     }
 
     /// Process a received message from a client.
+    /// Never waits for a session, messages to a busy session are dropped.
     ///
     /// **Arguments**
     /// - `buffer`: The buffer containing the message.
     /// - `socket_addr`: The address of the client.
-    pub async fn process_received_message(
+    pub fn process_received_message(
         &mut self,
-        buffer: &[u8],
+        buffer: &mut [u8],
         socket_addr: SocketAddr,
     ) -> Result<(), ProcessingError> {
         // try to parse if the message has the correct format
         log::info!("Received new message size: {}", buffer.len());
-        let (header, body) = parse_request(buffer)?;
+        let packet = PacketView::parse(buffer)?;
 
-        if let Err(error) = self.process_message(socket_addr, header, body).await {
+        if let Err(error) = self.process_message(socket_addr, packet) {
             log::error!("Failed to process message: {:?}", error);
         }
         Ok(())
     }
-    async fn process_message(
+    fn process_message(
         &mut self,
         addr: SocketAddr,
-        header: PackedHeader,
-        body: Vec<u8>,
+        packet: PacketView<'_>,
     ) -> Result<(), ProcessingError> {
+        let header = packet.header();
+        let packet_size = packet.as_bytes().len();
         let session_id = if header.session_id == 0 {
             // todo: restart if sessions are MAX_SIZE
             self.last_session_id += 1;
@@ -75,10 +98,10 @@ impl State {
             let session_id = self.last_session_id;
             log::info!("Assign new session id: {}", session_id);
 
-            let queue = Session::spawn_new(header.device_id, session_id, self.sender.clone());
+            let queue = Session::spawn_new(header.device_id, session_id, self.context.clone());
             let new_session = Session {
+                device_id: header.device_id,
                 last_sequence_id: 0,
-                last_timestamp: Instant::now(),
                 channel: queue,
             };
 
@@ -97,28 +120,41 @@ impl State {
             header.session_id
         };
 
-        // get the session from the map and update it
-        if let Some(session) = self.sessions.get_mut(&session_id) {
-            if session.last_sequence_id <= header.sequence {
-                session.last_sequence_id = header.sequence;
-                session.last_timestamp = Instant::now();
+        // get the session from the map and update it,
+        // ids of a restarted server might be in use by another device
+        if let Some(session) = self
+            .sessions
+            .get_mut(&session_id)
+            .filter(|session| session.device_id == header.device_id)
+        {
+            // messages in flight might arrive out of order, only messages older than the window are dropped
+            let too_old = sequence_newer(session.last_sequence_id, header.sequence)
+                && session.last_sequence_id.wrapping_sub(header.sequence) > ACK_BITS;
+            if !too_old {
+                if sequence_newer(header.sequence, session.last_sequence_id) {
+                    session.last_sequence_id = header.sequence;
+                }
+
+                // copy the datagram into the session queue (no heap allocation)
+                let Ok(packet) = PacketBuffer::from_slice(packet.as_bytes()) else {
+                    return Err(ProcessingError::DeserializeFailed(SerializeError::TooBig));
+                };
 
-                // send message to processing
-                if let Err(error) = session
+                // send message to processing, the receive loop must not wait for a busy session:
+                // the message is dropped and the client retransmits it within the advertised window
+                match session
                     .channel
-                    .send(session::ChannelMessage { addr, header, body })
-                    .await
+                    .try_send(session::ChannelMessage::Packet { addr, packet })
                 {
-                    log::error!(
-                        "Failed to send message to session [{}]: {}",
-                        session_id,
-                        error
-                    );
-
-                    self.sessions.remove(&session_id);
-                    Err(ProcessingError::SessionClosed(session_id))
-                } else {
-                    Ok(())
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(_)) => Err(ProcessingError::SessionBusy(session_id)),
+                    Err(TrySendError::Closed(_)) => {
+                        log::error!("Failed to send message to session [{}]", session_id);
+
+                        self.sessions.remove(&session_id);
+                        self.answer_timeout(addr, header, packet_size);
+                        Err(ProcessingError::SessionClosed(session_id))
+                    }
                 }
             } else {
                 // ignore as duplicate
@@ -132,40 +168,94 @@ impl State {
                 Ok(())
             }
         } else {
+            self.answer_timeout(addr, header, packet_size);
             Err(ProcessingError::SessionsNotFound(session_id))
         }
     }
 
-    /// Cleanup inactive sessions.
-    pub fn cleanup(&mut self) {
-        let now = Instant::now();
-        let mut to_remove = Vec::new();
-
-        for (session_id, session) in &mut self.sessions {
-            if now.duration_since(session.last_timestamp).as_secs() > INACTIVE_SESSION_TIMEOUT_SEC {
-                log::info!(
-                    "Session {} is inactive for more than {INACTIVE_SESSION_TIMEOUT_SEC} seconds",
-                    session_id
-                );
-                to_remove.push(*session_id);
-            }
+    /// Tell the client that its session has expired, so it starts a new handshake.
+    ///
+    /// The answer is not authenticated and the source address might be spoofed, so only
+    /// encrypted messages are answered, the answer is never bigger than the message
+    /// and the answers are rate limited.
+    ///
+    /// **Arguments**
+    /// - `addr`: The address of the client.
+    /// - `header`: The header of the message for the unknown session.
+    /// - `packet_size`: The size of the message.
+    fn answer_timeout(&mut self, addr: SocketAddr, header: &PackedHeader, packet_size: usize) {
+        if header.message_type != MessageType::EncryptedMessage
+            || packet_size < PacketView::PAYLOAD_OFFSET
+        {
+            return;
         }
+        if !self.timeout_limiter.allow(addr.ip(), Instant::now()) {
+            log::warn!("Too many timeout answers, {} is not answered", addr);
+            return;
+        }
+
+        // the timeout acknowledges the message, so the client matches it to the pending one
+        let timeout = PackedHeader::new(
+            MessageType::Timeout,
+            header.device_id,
+            header.session_id,
+            0,
+            header.sequence,
+        );
+        let mut buf = PacketBuffer::new();
+        let _ = buf.resize_default(PACKET_SIZE);
+        let Ok(size) = write_command(&timeout, &EncodedCommand::new(&[]), &mut buf) else {
+            return;
+        };
+        buf.truncate(size);
 
-        for session_id in to_remove {
-            self.sessions.remove(&session_id);
+        let response = Response {
+            addr,
+            session_id: header.session_id,
+            ack_id: header.sequence,
+            buf,
+        };
+        if self.context.response_queue.try_send(response).is_err() {
+            log::warn!("Response queue is full, timeout is not sent to {}", addr);
+        }
+    }
+
+    /// Remove the session as soon as its task has finished.
+    ///
+    /// **Arguments**
+    /// - `session_id`: The session reported by the finished task.
+    pub fn remove_session(&mut self, session_id: u16) {
+        if self.sessions.remove(&session_id).is_some() {
+            log::info!("Session {} is closed, removed", session_id);
         }
     }
-}
 
-/// Parse a buffer to header and command. Buffer expected size should be at least PackedHeader::SIZE.
-///
-/// Returns a tuple with the header and the command buffer.
-///
-/// **Arguments**
-/// - `buf`: The buffer to parse.
-fn parse_request(buf: &[u8]) -> Result<(PackedHeader, Vec<u8>), SerializeError> {
-    let header: PackedHeader = PackedHeader::try_deserialize(buf)?;
-    let mut buffer = Vec::with_capacity(buf.len() - PackedHeader::SIZE);
-    buffer.extend_from_slice(&buf[PackedHeader::SIZE..buf.len()]);
-    Ok((header, buffer))
+    /// Ask all sessions to notify their devices and close.
+    ///
+    /// **Arguments**
+    /// - `reason`: The reason sent to the devices.
+    pub fn close_all(&mut self, reason: CloseReason) {
+        for (session_id, session) in self.sessions.drain() {
+            if session
+                .channel
+                .try_send(session::ChannelMessage::Close(reason))
+                .is_err()
+            {
+                log::warn!("Session {} is busy or closed, not notified", session_id);
+            }
+        }
+    }
+
+    /// Remove closed sessions.
+    /// Session tasks close themselves when the device misses its keepalive intervals,
+    /// normally they are removed by [State::remove_session] right away.
+    pub fn cleanup(&mut self) {
+        self.sessions.retain(|session_id, session| {
+            let closed = session.channel.is_closed();
+            if closed {
+                log::info!("Session {} is closed, removed", session_id);
+            }
+            !closed
+        });
+    }
 }
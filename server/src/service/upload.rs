@@ -0,0 +1,173 @@
+use std::{
+    io::{Error, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use blake2::{Blake2s256, Digest};
+use shared_lib::transfer::{Block, BlockAck, UploadQuery};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+/// Directory of uploads in progress, upload names never start with a dot.
+const PART_DIR: &str = ".part";
+
+/// Persists block-wise uploads on disk, so an interrupted upload can be resumed
+/// from the last stored block, even after a new session.
+///
+/// Blocks are stored in `<dir>/<device_id>/.part/<name>`, the file is moved
+/// to `<dir>/<device_id>/<name>` once the last block is received.
+/// Uploads larger than `max_size` bytes are rejected.
+pub struct UploadStore {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl UploadStore {
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        UploadStore { dir, max_size }
+    }
+
+    /// Get the state of the upload: the next expected block or completion.
+    pub async fn query(
+        &self,
+        device_id: u32,
+        query: &UploadQuery<'_>,
+    ) -> std::io::Result<BlockAck> {
+        let path = self.path(device_id, query.name)?;
+        Self::state(&path, query.block_size).await
+    }
+
+    /// Store the block and return the next expected block, with a flag set when this block
+    /// completed the upload.
+    ///
+    /// Blocks must be sent in order. Block 0 (re)starts the upload, any other block
+    /// which is not the next expected one is not stored and the client gets the expected block back.
+    /// A single-block upload sent again with the same content is already complete.
+    /// A block which would grow the upload over the size limit fails with [ErrorKind::FileTooLarge].
+    pub async fn write_block(
+        &self,
+        device_id: u32,
+        block: &Block<'_>,
+    ) -> std::io::Result<(BlockAck, bool)> {
+        let path = self.path(device_id, block.name)?;
+        let part_path = part_path(&path);
+
+        if block.offset() + block.data.len() as u64 > self.max_size {
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                "Upload exceeds the size limit",
+            ));
+        }
+
+        if block.index == 0 && block.last && Self::is_stored(&path, block.data).await? {
+            log::warn!("Upload [{}] is already completed", block.name);
+            let ack = BlockAck {
+                next_block: 1,
+                complete: true,
+            };
+            return Ok((ack, false));
+        }
+        if block.index != 0 {
+            let state = Self::state(&path, block.block_size).await?;
+            if state.complete || state.next_block != block.index {
+                log::warn!(
+                    "Unexpected block {} of [{}], expected: {:?}",
+                    block.index,
+                    block.name,
+                    state
+                );
+                return Ok((state, false));
+            }
+        }
+
+        if let Some(parent) = part_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&part_path)
+            .await?;
+
+        // drop a partially written block left after a crash
+        file.set_len(block.offset()).await?;
+        file.seek(SeekFrom::Start(block.offset())).await?;
+        file.write_all(block.data).await?;
+        file.sync_data().await?;
+
+        if block.last {
+            fs::rename(&part_path, &path).await?;
+            log::info!("Upload [{}] is completed: {}", block.name, path.display());
+        }
+
+        let ack = BlockAck {
+            next_block: block.index + 1,
+            complete: block.last,
+        };
+        Ok((ack, block.last))
+    }
+
+    /// Path of the completed upload. Names are restricted to a safe subset of characters.
+    pub fn path(&self, device_id: u32, name: &str) -> std::io::Result<PathBuf> {
+        let valid = !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        if !valid {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid upload name"));
+        }
+
+        Ok(self.dir.join(device_id.to_string()).join(name))
+    }
+
+    /// Check if the completed upload has the same size and hash as `data`.
+    async fn is_stored(path: &Path, data: &[u8]) -> std::io::Result<bool> {
+        match fs::metadata(path).await {
+            Ok(metadata) if metadata.len() == data.len() as u64 => {}
+            Ok(_) => return Ok(false),
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error),
+        }
+
+        let stored = fs::read(path).await?;
+        Ok(Blake2s256::digest(&stored) == Blake2s256::digest(data))
+    }
+
+    async fn state(path: &Path, block_size: u16) -> std::io::Result<BlockAck> {
+        let block_size = u64::from(block_size.max(1));
+
+        match fs::metadata(part_path(path)).await {
+            Ok(metadata) => {
+                return Ok(BlockAck {
+                    next_block: (metadata.len() / block_size) as u32,
+                    complete: false,
+                })
+            }
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+            Err(_) => {}
+        }
+
+        match fs::metadata(path).await {
+            Ok(metadata) => Ok(BlockAck {
+                next_block: metadata.len().div_ceil(block_size) as u32,
+                complete: true,
+            }),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(BlockAck {
+                next_block: 0,
+                complete: false,
+            }),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Path of the upload in progress, next to the completed uploads of the device.
+fn part_path(path: &Path) -> PathBuf {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_owned();
+    };
+    dir.join(PART_DIR).join(name)
+}
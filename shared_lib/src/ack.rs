@@ -0,0 +1,115 @@
+/// Number of sequences before the acknowledged one covered by the ack bitmap.
+pub const ACK_BITS: u16 = 32;
+
+/// Compare sequences with wrap-around: `true` if `a` is newer than `b`.
+pub fn sequence_newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+/// Check if the sequence is acknowledged by `ack` and the bitmap of the previous sequences.
+/// Bit `i` of `ack_bits` acknowledges the sequence `ack - 1 - i`.
+pub fn is_acked(ack: u16, ack_bits: u32, sequence: u16) -> bool {
+    if sequence == ack {
+        return true;
+    }
+
+    let distance = ack.wrapping_sub(sequence);
+    (1..=ACK_BITS).contains(&distance) && ack_bits & (1 << (distance - 1)) != 0
+}
+
+/// Received sequences: the newest sequence and a bitmap of the [ACK_BITS] sequences before it.
+/// Used to acknowledge several messages in flight with one header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SequenceWindow {
+    newest: Option<u16>,
+    bits: u32,
+}
+
+impl SequenceWindow {
+    pub const fn new() -> Self {
+        SequenceWindow {
+            newest: None,
+            bits: 0,
+        }
+    }
+
+    /// Record the received sequence.
+    /// Returns `false` if the sequence is a duplicate or older than the window.
+    pub fn insert(&mut self, sequence: u16) -> bool {
+        let Some(newest) = self.newest else {
+            self.newest = Some(sequence);
+            return true;
+        };
+
+        if sequence_newer(sequence, newest) {
+            let shift = sequence.wrapping_sub(newest);
+            self.bits = if shift > ACK_BITS {
+                0
+            } else {
+                // the previous newest sequence becomes bit `shift - 1`
+                ((u64::from(self.bits) << shift) | (1 << (shift - 1))) as u32
+            };
+            self.newest = Some(sequence);
+            return true;
+        }
+
+        if self.contains(sequence) {
+            return false;
+        }
+        let distance = newest.wrapping_sub(sequence);
+        if distance > ACK_BITS {
+            return false;
+        }
+        self.bits |= 1 << (distance - 1);
+        true
+    }
+
+    /// Check if [SequenceWindow::insert] would record the sequence, without recording it.
+    pub fn accepts(&self, sequence: u16) -> bool {
+        let mut window = *self;
+        window.insert(sequence)
+    }
+
+    /// Check if the sequence was received. Sequences older than the window are unknown.
+    pub fn contains(&self, sequence: u16) -> bool {
+        self.newest
+            .is_some_and(|newest| is_acked(newest, self.bits, sequence))
+    }
+
+    /// The newest received sequence, 0 if nothing is received yet.
+    pub fn ack(&self) -> u16 {
+        self.newest.unwrap_or_default()
+    }
+
+    /// Bitmap of received sequences before [SequenceWindow::ack].
+    pub fn ack_bits(&self) -> u32 {
+        self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_order_and_wrap_around() {
+        let mut window = SequenceWindow::new();
+        assert!(window.insert(65534));
+        assert!(window.insert(1));
+        assert!(window.insert(65535));
+        assert!(!window.insert(65535));
+
+        assert_eq!(window.ack(), 1);
+        // 65535 is 2 before 1, 65534 is 3 before 1, 0 is missing
+        assert_eq!(window.ack_bits(), 0b110);
+        assert!(is_acked(window.ack(), window.ack_bits(), 65534));
+        assert!(!is_acked(window.ack(), window.ack_bits(), 0));
+
+        // a jump further than the window forgets the older sequences
+        assert!(window.insert(40));
+        assert!(!window.contains(1));
+        assert!(!window.insert(7));
+        assert!(window.insert(39));
+        assert_eq!(window.ack_bits(), 1);
+    }
+}
@@ -0,0 +1,41 @@
+/// Reason of an explicit session close, sent in [crate::command::Payload::Close].
+///
+/// Encoded as one byte. Unknown reasons are kept as [CloseReason::Other], the session
+/// is closed whatever the reason is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer has nothing more to send.
+    Normal,
+    /// The peer is shutting down or restarting.
+    Shutdown,
+    /// The device missed its keepalive intervals.
+    Idle,
+    /// The peer cannot continue the session, e.g. after a failure.
+    Error,
+    /// Reason unknown to this version of the protocol.
+    Other(u8),
+}
+
+impl From<u8> for CloseReason {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Normal,
+            1 => Self::Shutdown,
+            2 => Self::Idle,
+            3 => Self::Error,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<CloseReason> for u8 {
+    fn from(val: CloseReason) -> Self {
+        match val {
+            CloseReason::Normal => 0,
+            CloseReason::Shutdown => 1,
+            CloseReason::Idle => 2,
+            CloseReason::Error => 3,
+            CloseReason::Other(other) => other,
+        }
+    }
+}
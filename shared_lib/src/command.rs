@@ -1,29 +1,110 @@
 use core::fmt::Debug;
 
-use musli::{Decode, Encode};
+use crate::close::CloseReason;
+use crate::firmware::{FirmwareInfo, FirmwareQuery, FirmwareRequest};
+use crate::fragment::Fragment;
+use crate::qos::QosMessage;
+use crate::telemetry::Telemetry;
+use crate::transfer::{Block, BlockAck, UploadQuery};
 
 pub const COMMAND_SIZE: usize = 1400;
 pub type Buffer = [u8; COMMAND_SIZE];
 
 pub const PACKET_SIZE: usize = 1500;
 
-#[derive(Encode, Decode, Debug)]
-pub enum Information {
-    Temparature(f32),
-    AirPressure(f32),
+/// Decrypted message body.
+/// Encoded on the wire as a one byte kind followed by the kind specific body:
+/// 1 - Telemetry (versioned readings, see [Telemetry])
+/// 2 - Application (opaque bytes until the end of the message)
+/// 3 - Fragment (part of a bigger payload, see [Fragment])
+/// 4 - Block (block of a named blob, see [Block])
+/// 5 - UploadQuery (state of an interrupted upload)
+/// 6 - BlockAck (server answer to Block and UploadQuery)
+/// 7 - FirmwareQuery (firmware image description request)
+/// 8 - FirmwareInfo (firmware image size and hash)
+/// 9 - FirmwareRequest (firmware block request, answered with a Block)
+/// 10 - Qos (payload with a delivery guarantee, see [QosMessage])
+/// 11 - Ping (keepalive with a token, u32)
+/// 12 - Pong (server answer to Ping with the same token, u32)
+/// 13 - Close (explicit session close with a reason, see [CloseReason])
+#[derive(Debug, PartialEq)]
+pub enum Payload<'a> {
+    /// Built-in telemetry readings.
+    Telemetry(Telemetry<'a>),
+    /// Application-defined data, delivered to the application as is.
+    Application(&'a [u8]),
+    /// Part of a payload which does not fit into one datagram.
+    Fragment(Fragment<'a>),
+    /// Block of a named blob: uploaded by the device or a firmware block sent by the server.
+    Block(Block<'a>),
+    /// Request for the state of an upload.
+    UploadQuery(UploadQuery<'a>),
+    /// The next block expected by the server.
+    BlockAck(BlockAck),
+    /// Request for the firmware image description.
+    FirmwareQuery(FirmwareQuery<'a>),
+    /// Firmware image description.
+    FirmwareInfo(FirmwareInfo),
+    /// Request for a firmware block.
+    FirmwareRequest(FirmwareRequest<'a>),
+    /// Payload with a delivery guarantee.
+    Qos(QosMessage<'a>),
+    /// Keepalive, keeps the session and the NAT binding open.
+    Ping(u32),
+    /// Answer to the keepalive.
+    Pong(u32),
+    /// Explicit session close, sent by the device or the server and echoed in the acknowledgement.
+    Close(CloseReason),
 }
 
-#[derive(Encode, Decode)]
-pub struct EncodedCommand {
-    pub size: usize,
-    pub buf: Buffer,
+impl Payload<'_> {
+    pub const TELEMETRY: u8 = 1;
+    pub const APPLICATION: u8 = 2;
+    pub const FRAGMENT: u8 = 3;
+    pub const BLOCK: u8 = 4;
+    pub const UPLOAD_QUERY: u8 = 5;
+    pub const BLOCK_ACK: u8 = 6;
+    pub const FIRMWARE_QUERY: u8 = 7;
+    pub const FIRMWARE_INFO: u8 = 8;
+    pub const FIRMWARE_REQUEST: u8 = 9;
+    pub const QOS: u8 = 10;
+    pub const PING: u8 = 11;
+    pub const PONG: u8 = 12;
+    pub const CLOSE: u8 = 13;
 }
 
-impl Debug for EncodedCommand {
+/// Command body borrowed from a packet buffer.
+/// On the wire it is encoded as a fixed-width length (u16, network byte order)
+/// followed by exactly `size()` bytes, so the packet size tracks the actual content.
+#[derive(Clone, Copy)]
+pub struct EncodedCommand<'a> {
+    pub buf: &'a [u8],
+}
+
+impl<'a> EncodedCommand<'a> {
+    /// Size of the length prefix in bytes.
+    pub const LENGTH_SIZE: usize = 2;
+
+    pub fn new(buf: &'a [u8]) -> Self {
+        EncodedCommand { buf }
+    }
+
+    /// Payload size in bytes (without the length prefix).
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Number of bytes the command takes on the wire.
+    pub fn encoded_size(&self) -> usize {
+        Self::LENGTH_SIZE + self.buf.len()
+    }
+}
+
+impl Debug for EncodedCommand<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.write_fmt(format_args!(
             "NetworkCommand {{ size: {}, buf: <redundant> }}",
-            self.size
+            self.size()
         ))
     }
 }
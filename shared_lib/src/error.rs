@@ -18,4 +18,148 @@ pub enum SerializeError {
     UnsupportedVersion,
     #[error("Unsupported message type")]
     UnknownMessageType,
+    #[error("Unsupported payload kind")]
+    UnknownPayload,
+    #[error("Fragment does not match the message")]
+    InvalidFragment,
+    #[error("Unknown delivery guarantee")]
+    UnknownQos,
+}
+
+/// Reason of a failure reported by the server in an Error message.
+///
+/// Encoded as one byte in the message body, an empty body is [ErrorCode::Unspecified].
+/// The body is not encrypted, the message might fail before the session keys are known,
+/// so the code is a hint for the device and not an authenticated answer.
+/// Codes are stable, unknown codes are kept as [ErrorCode::Other].
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    #[error("Unspecified error")]
+    Unspecified,
+    /// The message cannot be parsed.
+    #[error("Message is malformed")]
+    Malformed,
+    /// The message cannot be decrypted or authenticated.
+    #[error("Message cannot be decrypted")]
+    DecryptFailed,
+    /// The message is not expected in the current session state, e.g. a repeated handshake.
+    #[error("Session is in a wrong state")]
+    BadState,
+    /// The device is not allowed to use the server.
+    #[error("Device is unknown")]
+    UnknownDevice,
+    /// The device sends more messages than the server accepts.
+    #[error("Rate limited")]
+    RateLimited,
+    /// The message type or the payload kind is not supported by the server.
+    #[error("Message type is not supported")]
+    UnsupportedType,
+    /// The payload is valid but not expected from a device, e.g. a server payload.
+    #[error("Payload is not expected")]
+    UnexpectedPayload,
+    /// The requested resource, e.g. a firmware image, does not exist.
+    #[error("Resource is not found")]
+    NotFound,
+    /// The server failed to process a valid message, e.g. a storage failure.
+    #[error("Internal server error")]
+    Internal,
+    /// The transfer exceeds the size accepted by the server, e.g. an upload.
+    #[error("Transfer is too large")]
+    TooLarge,
+    /// Code unknown to this version of the protocol.
+    #[error("Unknown error code {0}")]
+    Other(u8),
+}
+
+impl ErrorCode {
+    /// Parse the code from the body of an Error message.
+    pub fn parse(body: &[u8]) -> Self {
+        body.first()
+            .map_or(Self::Unspecified, |code| Self::from(*code))
+    }
+}
+
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Unspecified,
+            1 => Self::Malformed,
+            2 => Self::DecryptFailed,
+            3 => Self::BadState,
+            4 => Self::UnknownDevice,
+            5 => Self::RateLimited,
+            6 => Self::UnsupportedType,
+            7 => Self::UnexpectedPayload,
+            8 => Self::NotFound,
+            9 => Self::Internal,
+            10 => Self::TooLarge,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(val: ErrorCode) -> Self {
+        match val {
+            ErrorCode::Unspecified => 0,
+            ErrorCode::Malformed => 1,
+            ErrorCode::DecryptFailed => 2,
+            ErrorCode::BadState => 3,
+            ErrorCode::UnknownDevice => 4,
+            ErrorCode::RateLimited => 5,
+            ErrorCode::UnsupportedType => 6,
+            ErrorCode::UnexpectedPayload => 7,
+            ErrorCode::NotFound => 8,
+            ErrorCode::Internal => 9,
+            ErrorCode::TooLarge => 10,
+            ErrorCode::Other(other) => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::EncodedCommand;
+    use crate::network::{MessageType, PackedHeader};
+    use crate::packet::PacketView;
+    use crate::write_command;
+
+    /// Write an Error frame with the body and parse the code back from it.
+    fn round_trip(body: &[u8]) -> ErrorCode {
+        let mut buf = [0u8; 64];
+        let header = PackedHeader::new(MessageType::Error, 7, 3, 5, 5);
+        let size = write_command(&header, &EncodedCommand::new(body), &mut buf).unwrap();
+        let packet = PacketView::parse(&mut buf[..size]).unwrap();
+        assert_eq!(packet.header().message_type, MessageType::Error);
+        ErrorCode::parse(packet.payload())
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        let codes = [
+            ErrorCode::Unspecified,
+            ErrorCode::Malformed,
+            ErrorCode::DecryptFailed,
+            ErrorCode::BadState,
+            ErrorCode::UnknownDevice,
+            ErrorCode::RateLimited,
+            ErrorCode::UnsupportedType,
+            ErrorCode::UnexpectedPayload,
+            ErrorCode::NotFound,
+            ErrorCode::Internal,
+            ErrorCode::TooLarge,
+        ];
+        for (value, code) in codes.into_iter().enumerate() {
+            assert_eq!(u8::from(code), value as u8);
+            assert_eq!(round_trip(&[code.into()]), code);
+        }
+
+        // unknown codes are kept, an empty body is unspecified
+        for value in [codes.len() as u8, 200, u8::MAX] {
+            assert_eq!(round_trip(&[value]), ErrorCode::Other(value));
+            assert_eq!(u8::from(ErrorCode::Other(value)), value);
+        }
+        assert_eq!(round_trip(&[]), ErrorCode::Unspecified);
+    }
 }
@@ -0,0 +1,142 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::error::SerializeError;
+use crate::transfer::{read_name, write_name};
+
+/// Size of the BLAKE2s-256 image hash.
+pub const HASH_SIZE: usize = 32;
+
+/// The biggest firmware block the server sends, a block with its header
+/// has to fit into one encrypted response.
+pub const MAX_FIRMWARE_BLOCK_SIZE: u16 = 1024;
+
+/// Request for the firmware image description, sent before the download.
+///
+/// Encoded as `[name_len u8][name][version u32]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirmwareQuery<'a> {
+    pub name: &'a str,
+    pub version: u32,
+}
+
+/// Firmware image description: size and BLAKE2s-256 hash of the whole image.
+///
+/// Encoded as `[version u32][size u32][hash 32 bytes]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    pub version: u32,
+    pub size: u32,
+    pub hash: [u8; HASH_SIZE],
+}
+
+/// Request for one block of the firmware image, the server answers with a
+/// [crate::transfer::Block] of the image.
+///
+/// Encoded as `[name_len u8][name][version u32][index u32][block_size u16]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirmwareRequest<'a> {
+    pub name: &'a str,
+    pub version: u32,
+    pub index: u32,
+    pub block_size: u16,
+}
+
+impl<'a> FirmwareQuery<'a> {
+    pub fn encoded_size(&self) -> usize {
+        1 + self.name.len() + 4
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = self.encoded_size();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        let offset = write_name(self.name, buf)?;
+        NetworkEndian::write_u32(&mut buf[offset..offset + 4], self.version);
+        Ok(size)
+    }
+
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        let (name, offset) = read_name(buf)?;
+        if buf.len() < offset + 4 {
+            return Err(SerializeError::NotEnough);
+        }
+
+        Ok(FirmwareQuery {
+            name,
+            version: NetworkEndian::read_u32(&buf[offset..offset + 4]),
+        })
+    }
+}
+
+impl FirmwareInfo {
+    pub const SIZE: usize = 8 + HASH_SIZE;
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        if buf.len() < Self::SIZE {
+            return Err(SerializeError::TooBig);
+        }
+
+        NetworkEndian::write_u32(&mut buf[0..4], self.version);
+        NetworkEndian::write_u32(&mut buf[4..8], self.size);
+        buf[8..Self::SIZE].copy_from_slice(&self.hash);
+        Ok(Self::SIZE)
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, SerializeError> {
+        if buf.len() < Self::SIZE {
+            return Err(SerializeError::NotEnough);
+        }
+
+        let mut hash = [0u8; HASH_SIZE];
+        hash.copy_from_slice(&buf[8..Self::SIZE]);
+        Ok(FirmwareInfo {
+            version: NetworkEndian::read_u32(&buf[0..4]),
+            size: NetworkEndian::read_u32(&buf[4..8]),
+            hash,
+        })
+    }
+
+    /// Number of blocks of the given size in the image.
+    pub fn block_count(&self, block_size: u16) -> u32 {
+        self.size.div_ceil(u32::from(block_size.max(1)))
+    }
+}
+
+impl<'a> FirmwareRequest<'a> {
+    pub fn encoded_size(&self) -> usize {
+        1 + self.name.len() + 10
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = self.encoded_size();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        let offset = write_name(self.name, buf)?;
+        NetworkEndian::write_u32(&mut buf[offset..offset + 4], self.version);
+        NetworkEndian::write_u32(&mut buf[offset + 4..offset + 8], self.index);
+        NetworkEndian::write_u16(&mut buf[offset + 8..offset + 10], self.block_size);
+        Ok(size)
+    }
+
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        let (name, offset) = read_name(buf)?;
+        if buf.len() < offset + 10 {
+            return Err(SerializeError::NotEnough);
+        }
+
+        let block_size = NetworkEndian::read_u16(&buf[offset + 8..offset + 10]);
+        if block_size == 0 || block_size > MAX_FIRMWARE_BLOCK_SIZE {
+            return Err(SerializeError::NotParsed);
+        }
+        Ok(FirmwareRequest {
+            name,
+            version: NetworkEndian::read_u32(&buf[offset..offset + 4]),
+            index: NetworkEndian::read_u32(&buf[offset + 4..offset + 8]),
+            block_size,
+        })
+    }
+}
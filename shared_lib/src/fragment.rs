@@ -0,0 +1,256 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use heapless::Vec;
+
+use crate::error::SerializeError;
+
+/// Maximal number of fragments in one message.
+pub const MAX_FRAGMENTS: usize = 256;
+
+/// Default fragment data size. A fragment with this size can be encrypted
+/// in place in a single [crate::command::PACKET_SIZE] buffer.
+pub const FRAGMENT_DATA_SIZE: usize = 512;
+
+/// Part of a message which does not fit into one datagram.
+///
+/// Encoded as `[message_id u16][index u16][count u16][total_size u32][kind u8][data]`.
+/// The message is split into `count` chunks of equal size (the last one might be shorter),
+/// so the data offset is derived from the index, total size and count.
+/// `kind` is the payload kind of the reassembled message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fragment<'a> {
+    pub message_id: u16,
+    pub index: u16,
+    pub count: u16,
+    pub total_size: u32,
+    pub kind: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    pub const HEADER_SIZE: usize = 11;
+
+    /// Size of the fragment on the wire.
+    pub fn encoded_size(&self) -> usize {
+        Self::HEADER_SIZE + self.data.len()
+    }
+
+    /// Serialize the fragment into the buffer. Returns the number of bytes written.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = self.encoded_size();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        NetworkEndian::write_u16(&mut buf[0..2], self.message_id);
+        NetworkEndian::write_u16(&mut buf[2..4], self.index);
+        NetworkEndian::write_u16(&mut buf[4..6], self.count);
+        NetworkEndian::write_u32(&mut buf[6..10], self.total_size);
+        buf[10] = self.kind;
+        buf[Self::HEADER_SIZE..size].copy_from_slice(self.data);
+
+        Ok(size)
+    }
+
+    /// Parse a fragment, the data is borrowed from the buffer.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(SerializeError::NotEnough);
+        }
+
+        Ok(Fragment {
+            message_id: NetworkEndian::read_u16(&buf[0..2]),
+            index: NetworkEndian::read_u16(&buf[2..4]),
+            count: NetworkEndian::read_u16(&buf[4..6]),
+            total_size: NetworkEndian::read_u32(&buf[6..10]),
+            kind: buf[10],
+            data: &buf[Self::HEADER_SIZE..],
+        })
+    }
+
+    /// Chunk size of all fragments except the last one.
+    fn chunk_size(total_size: usize, count: usize) -> usize {
+        total_size.div_ceil(count)
+    }
+}
+
+/// Splits a message into fragments.
+pub struct Fragmenter<'a> {
+    message_id: u16,
+    kind: u8,
+    data: &'a [u8],
+    count: u16,
+    chunk_size: usize,
+}
+
+impl<'a> Fragmenter<'a> {
+    /// Split `data` of the payload `kind` into chunks no bigger than `max_chunk_size`.
+    pub fn new(
+        message_id: u16,
+        kind: u8,
+        data: &'a [u8],
+        max_chunk_size: usize,
+    ) -> Result<Self, SerializeError> {
+        let count = data.len().div_ceil(max_chunk_size.max(1)).max(1);
+        if count > MAX_FRAGMENTS {
+            return Err(SerializeError::TooBig);
+        }
+
+        Ok(Fragmenter {
+            message_id,
+            kind,
+            data,
+            count: count as u16,
+            chunk_size: Fragment::chunk_size(data.len(), count),
+        })
+    }
+
+    pub fn count(&self) -> u16 {
+        self.count
+    }
+
+    /// Get the fragment by index, `None` if the index is out of range.
+    pub fn fragment(&self, index: u16) -> Option<Fragment<'a>> {
+        if index >= self.count {
+            return None;
+        }
+
+        let start = (usize::from(index) * self.chunk_size).min(self.data.len());
+        let end = (start + self.chunk_size).min(self.data.len());
+        Some(Fragment {
+            message_id: self.message_id,
+            index,
+            count: self.count,
+            total_size: self.data.len() as u32,
+            kind: self.kind,
+            data: &self.data[start..end],
+        })
+    }
+}
+
+/// Bounded reassembly buffer for one message at a time.
+/// A fragment of another message drops the incomplete one.
+pub struct Reassembly<const N: usize> {
+    message_id: Option<u16>,
+    kind: u8,
+    count: u16,
+    received_count: u16,
+    received: [u32; MAX_FRAGMENTS / 32],
+    buf: Vec<u8, N>,
+}
+
+impl<const N: usize> Default for Reassembly<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Reassembly<N> {
+    pub const fn new() -> Self {
+        Reassembly {
+            message_id: None,
+            kind: 0,
+            count: 0,
+            received_count: 0,
+            received: [0; MAX_FRAGMENTS / 32],
+            buf: Vec::new(),
+        }
+    }
+
+    /// Message being reassembled.
+    pub fn message_id(&self) -> Option<u16> {
+        self.message_id
+    }
+
+    /// Drop the incomplete message.
+    pub fn reset(&mut self) {
+        self.message_id = None;
+        self.received_count = 0;
+        self.received = [0; MAX_FRAGMENTS / 32];
+        self.buf.clear();
+    }
+
+    /// Add a fragment. Returns the payload kind and the message once all fragments are received.
+    /// Duplicated fragments are ignored.
+    pub fn push(&mut self, fragment: &Fragment) -> Result<Option<(u8, &[u8])>, SerializeError> {
+        let total_size = fragment.total_size as usize;
+        let count = usize::from(fragment.count);
+        if count == 0 || count > MAX_FRAGMENTS || fragment.index >= fragment.count {
+            return Err(SerializeError::InvalidFragment);
+        }
+        if total_size > N {
+            return Err(SerializeError::TooBig);
+        }
+
+        // validate the fragment size
+        let chunk_size = Fragment::chunk_size(total_size, count);
+        let offset = usize::from(fragment.index) * chunk_size;
+        let expected_size = chunk_size.min(total_size.saturating_sub(offset));
+        if fragment.data.len() != expected_size {
+            return Err(SerializeError::InvalidFragment);
+        }
+
+        if self.message_id != Some(fragment.message_id) {
+            // start a new message
+            self.reset();
+            self.message_id = Some(fragment.message_id);
+            self.kind = fragment.kind;
+            self.count = fragment.count;
+            self.buf
+                .resize_default(total_size)
+                .map_err(|_| SerializeError::TooBig)?;
+        } else if self.count != fragment.count
+            || self.kind != fragment.kind
+            || self.buf.len() != total_size
+        {
+            return Err(SerializeError::InvalidFragment);
+        }
+
+        let (word, bit) = (usize::from(fragment.index) / 32, fragment.index % 32);
+        if self.received[word] & (1 << bit) != 0 {
+            // duplicate
+            return Ok(None);
+        }
+        self.received[word] |= 1 << bit;
+        self.received_count += 1;
+        self.buf[offset..offset + expected_size].copy_from_slice(fragment.data);
+
+        if self.received_count == self.count {
+            Ok(Some((self.kind, &self.buf)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let mut data = [0u8; 1000];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let fragmenter = Fragmenter::new(7, 2, &data, 300).unwrap();
+        assert_eq!(fragmenter.count(), 4);
+
+        let mut reassembly = Reassembly::<1024>::new();
+        for index in [3, 1, 1, 0] {
+            let fragment = fragmenter.fragment(index).unwrap();
+
+            let mut buf = [0u8; 512];
+            let size = fragment.serialize(&mut buf).unwrap();
+            let parsed = Fragment::parse(&buf[..size]).unwrap();
+            assert_eq!(parsed, fragment);
+
+            assert!(reassembly.push(&parsed).unwrap().is_none());
+        }
+
+        let last = fragmenter.fragment(2).unwrap();
+        let (kind, message) = reassembly.push(&last).unwrap().unwrap();
+        assert_eq!(kind, 2);
+        assert_eq!(message, &data);
+    }
+}
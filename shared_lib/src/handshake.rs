@@ -0,0 +1,40 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::error::SerializeError;
+
+/// Session parameters carried in the Noise handshake payloads.
+///
+/// Encoded as `[keepalive_secs u16]`. The device proposes the keepalive interval,
+/// the server answers with the interval it accepted. An empty payload or zero interval
+/// asks for the server default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HandshakeParams {
+    /// Interval of keepalive pings in seconds. The server closes the session if it does
+    /// not receive any message for several intervals.
+    pub keepalive_secs: u16,
+}
+
+impl HandshakeParams {
+    pub const SIZE: usize = 2;
+
+    /// Serialize the parameters into the buffer. Returns the number of bytes written.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        if buf.len() < Self::SIZE {
+            return Err(SerializeError::TooBig);
+        }
+
+        NetworkEndian::write_u16(&mut buf[0..2], self.keepalive_secs);
+        Ok(Self::SIZE)
+    }
+
+    /// Parse the parameters, an empty payload has the default parameters.
+    pub fn parse(buf: &[u8]) -> Result<Self, SerializeError> {
+        match buf.len() {
+            0 => Ok(Self::default()),
+            1 => Err(SerializeError::NotEnough),
+            _ => Ok(HandshakeParams {
+                keepalive_secs: NetworkEndian::read_u16(&buf[0..2]),
+            }),
+        }
+    }
+}
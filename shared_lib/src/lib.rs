@@ -1,9 +1,18 @@
 #![no_std]
 #![forbid(unsafe_code)]
 
+pub mod ack;
+pub mod close;
 pub mod command;
 pub mod error;
+pub mod firmware;
+pub mod fragment;
+pub mod handshake;
 pub mod network;
+pub mod packet;
+pub mod qos;
 pub mod serialize;
+pub mod telemetry;
+pub mod transfer;
 
 pub use serialize::*;
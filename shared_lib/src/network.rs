@@ -1,5 +1,6 @@
 use byteorder::{ByteOrder, NetworkEndian};
 
+use crate::ack;
 use crate::error::SerializeError;
 
 /// Network message types.
@@ -9,7 +10,7 @@ use crate::error::SerializeError;
 /// 4 - Ack
 /// 5 - Timeout (session expired)
 /// FF - Error
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum MessageType {
     HandshakeRequest,
     HandshakeResponse,
@@ -19,10 +20,11 @@ pub enum MessageType {
     Error,
 }
 
-/// Network header structure.
-/// Total size - 14 bytes for each packet.
-/// Additional padding 2 bytes
-#[derive(PartialEq, Debug)]
+/// Network header structure, protocol version 4.
+/// Total size - 20 bytes for each packet, the acknowledgement bitmap and the receive window included.
+/// The 2 byte length of the payload follows the header, so the payload starts at byte 22
+/// ([crate::packet::PacketView::PAYLOAD_OFFSET]).
+#[derive(PartialEq, Debug, Clone)]
 pub struct PackedHeader {
     // Protocol ID 2 bytes / 0-2
     protocol_id: u16,
@@ -38,6 +40,10 @@ pub struct PackedHeader {
     pub sequence: u16,
     // The most recent received sequence 2 bytes / 12-14
     pub ack: u16,
+    // Received sequences before `ack`, bit i acknowledges `ack - 1 - i` 4 bytes / 14-18
+    pub ack_bits: u32,
+    // Number of messages the sender of the header accepts in flight 2 bytes / 18-20
+    pub window: u16,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -70,9 +76,13 @@ impl From<MessageType> for u8 {
 }
 
 impl PackedHeader {
-    pub const SIZE: usize = 14;
+    pub const SIZE: usize = 20;
+    /// Receive window of a sender which does not limit the messages in flight.
+    pub const UNLIMITED_WINDOW: u16 = u16::MAX;
+    /// Size of the acknowledgement fields, see [PackedHeader::ack_fields].
+    pub const ACK_FIELDS_SIZE: usize = 8;
     const PROTOCOL_ID: u16 = 0xDEFA;
-    const VERSION: u8 = 1;
+    const VERSION: u8 = 4;
 
     pub fn new(
         message_type: MessageType,
@@ -83,15 +93,46 @@ impl PackedHeader {
     ) -> Self {
         PackedHeader {
             protocol_id: Self::PROTOCOL_ID,
-            version: 1,
+            version: Self::VERSION,
             message_type,
             device_id,
             session_id,
             sequence,
             ack,
+            ack_bits: 0,
+            window: Self::UNLIMITED_WINDOW,
         }
     }
 
+    /// Set the bitmap of received sequences before `ack`, see [crate::ack::SequenceWindow].
+    pub fn with_ack_bits(mut self, ack_bits: u32) -> Self {
+        self.ack_bits = ack_bits;
+        self
+    }
+
+    /// Advertise the number of messages the sender of the header accepts in flight.
+    pub fn with_window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Check if the header acknowledges the sequence, directly or with the ack bitmap.
+    pub fn acknowledges(&self, sequence: u16) -> bool {
+        ack::is_acked(self.ack, self.ack_bits, sequence)
+    }
+
+    /// Acknowledgement fields as they are encoded in the header: ack, ack_bits and window.
+    ///
+    /// The nonce covers only the type, the session and the sequence, so an Ack message
+    /// repeats these fields at the start of its encrypted payload to authenticate them.
+    pub fn ack_fields(&self) -> [u8; Self::ACK_FIELDS_SIZE] {
+        let mut fields = [0u8; Self::ACK_FIELDS_SIZE];
+        NetworkEndian::write_u16(&mut fields[0..2], self.ack);
+        NetworkEndian::write_u32(&mut fields[2..6], self.ack_bits);
+        NetworkEndian::write_u16(&mut fields[6..8], self.window);
+        fields
+    }
+
     /// Serialize the header into the buffer. Returns the number of bytes written.
     /// If the buffer is too small, function returns an error. Buffer recommended size is bigger or equal [PackedHeader::SIZE].
     pub fn serialize_info(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
@@ -101,11 +142,13 @@ impl PackedHeader {
 
         NetworkEndian::write_u16(&mut buf[0..2], self.protocol_id);
         buf[2] = self.version;
-        buf[3] = self.message_type.clone().into();
+        buf[3] = self.message_type.into();
         NetworkEndian::write_u32(&mut buf[4..8], self.device_id);
         NetworkEndian::write_u16(&mut buf[8..10], self.session_id);
         NetworkEndian::write_u16(&mut buf[10..12], self.sequence);
         NetworkEndian::write_u16(&mut buf[12..14], self.ack);
+        NetworkEndian::write_u32(&mut buf[14..18], self.ack_bits);
+        NetworkEndian::write_u16(&mut buf[18..20], self.window);
 
         Ok(PackedHeader::SIZE)
     }
@@ -127,6 +170,8 @@ impl PackedHeader {
             let session_id: u16 = NetworkEndian::read_u16(&buf[8..10]);
             let sequence: u16 = NetworkEndian::read_u16(&buf[10..12]);
             let ack: u16 = NetworkEndian::read_u16(&buf[12..14]);
+            let ack_bits: u32 = NetworkEndian::read_u32(&buf[14..18]);
+            let window: u16 = NetworkEndian::read_u16(&buf[18..20]);
 
             Ok(PackedHeader {
                 protocol_id,
@@ -136,15 +181,22 @@ impl PackedHeader {
                 session_id,
                 sequence,
                 ack,
+                ack_bits,
+                window,
             })
         } else {
             Err(SerializeError::NotEnough)
         }
     }
 
-    /// Get nonce from session id and sequence.
+    /// Get nonce from message type, session id and sequence.
+    ///
+    /// Responses reuse the sequence of the request, messages sent by the server on its own
+    /// (e.g. a close) have a sequence of their own, the message type keeps their nonces apart.
     pub fn nonce(&self) -> u64 {
-        ((self.session_id as u64) << 32) | (self.sequence as u64)
+        (u64::from(u8::from(self.message_type)) << 48)
+            | ((self.session_id as u64) << 32)
+            | (self.sequence as u64)
     }
 }
 
@@ -156,7 +208,9 @@ mod tests {
     fn serialize_deserialize_header() {
         let mut buf = [0u8; PackedHeader::SIZE];
 
-        let header = PackedHeader::new(MessageType::HandshakeRequest, 1234567890, 100, 200, 150);
+        let header = PackedHeader::new(MessageType::HandshakeRequest, 1234567890, 100, 200, 150)
+            .with_ack_bits(0b101)
+            .with_window(3);
         let _ = header
             .serialize_info(&mut buf)
             .expect("Failed to serialize header");
@@ -164,5 +218,7 @@ mod tests {
         // try to deserialize now
         let deserialized_header = PackedHeader::try_deserialize(&buf).unwrap();
         assert_eq!(deserialized_header, header);
+        assert!(deserialized_header.acknowledges(147));
+        assert!(!deserialized_header.acknowledges(148));
     }
 }
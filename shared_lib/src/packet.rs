@@ -0,0 +1,137 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::command::{EncodedCommand, COMMAND_SIZE, PACKET_SIZE};
+use crate::error::SerializeError;
+use crate::network::PackedHeader;
+
+/// Owned packet storage without heap allocation.
+/// Used to move a received datagram between tasks.
+pub type PacketBuffer = heapless::Vec<u8, PACKET_SIZE>;
+
+/// Zero-copy view over a packet: [PackedHeader] followed by a length-prefixed command.
+///
+/// The view borrows the receive buffer, the header is decoded once and the payload is
+/// exposed as a slice of the same buffer. The payload can be decrypted in place,
+/// after that [PacketView::payload] returns the plaintext.
+pub struct PacketView<'a> {
+    header: PackedHeader,
+    buf: &'a mut [u8],
+    payload_size: usize,
+}
+
+impl<'a> PacketView<'a> {
+    /// Offset of the payload from the beginning of the packet.
+    pub const PAYLOAD_OFFSET: usize = PackedHeader::SIZE + EncodedCommand::LENGTH_SIZE;
+
+    /// Parse the packet header and validate the payload size.
+    /// Every packet has a length prefix, packets without it are rejected.
+    pub fn parse(buf: &'a mut [u8]) -> Result<Self, SerializeError> {
+        let header = PackedHeader::try_deserialize(buf)?;
+        if buf.len() < Self::PAYLOAD_OFFSET {
+            return Err(SerializeError::NotEnough);
+        }
+
+        let payload_size = usize::from(NetworkEndian::read_u16(
+            &buf[PackedHeader::SIZE..Self::PAYLOAD_OFFSET],
+        ));
+        if payload_size > COMMAND_SIZE {
+            return Err(SerializeError::TooBig);
+        }
+        if buf.len() < Self::PAYLOAD_OFFSET + payload_size {
+            return Err(SerializeError::NotEnough);
+        }
+
+        Ok(PacketView {
+            header,
+            buf,
+            payload_size,
+        })
+    }
+
+    pub fn header(&self) -> &PackedHeader {
+        &self.header
+    }
+
+    /// The whole packet including the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf
+    }
+
+    /// Payload bytes (without the length prefix).
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[Self::PAYLOAD_OFFSET..Self::PAYLOAD_OFFSET + self.payload_size]
+    }
+
+    /// Consume the view and return the payload borrowed from the packet buffer,
+    /// e.g. to return the decrypted payload from a function.
+    pub fn into_payload(self) -> &'a [u8] {
+        &self.buf[Self::PAYLOAD_OFFSET..Self::PAYLOAD_OFFSET + self.payload_size]
+    }
+
+    /// Payload as a command, borrowed from the packet buffer.
+    pub fn command(&self) -> EncodedCommand<'_> {
+        EncodedCommand::new(self.payload())
+    }
+
+    /// Decrypt the payload in place.
+    ///
+    /// The `decrypt` function receives the header nonce and the encrypted payload,
+    /// it must overwrite the beginning of the payload with the plaintext and return its size.
+    /// Returns the decrypted payload.
+    pub fn decrypt_in_place<E>(
+        &mut self,
+        decrypt: impl FnOnce(u64, &mut [u8]) -> Result<usize, E>,
+    ) -> Result<&[u8], E> {
+        let nonce = self.header.nonce();
+        let payload = &mut self.buf[Self::PAYLOAD_OFFSET..Self::PAYLOAD_OFFSET + self.payload_size];
+
+        let size = decrypt(nonce, payload)?;
+        self.payload_size = size.min(self.payload_size);
+
+        Ok(self.payload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::MessageType;
+    use crate::write_command;
+
+    #[test]
+    fn decrypt_payload_in_place() {
+        let mut buf = [0u8; 64];
+        let header = PackedHeader::new(MessageType::EncryptedMessage, 7, 3, 5, 4);
+        let size = write_command(&header, &EncodedCommand::new(&[9, 8, 7, 6]), &mut buf).unwrap();
+
+        let mut packet = PacketView::parse(&mut buf[..size]).unwrap();
+        assert_eq!(packet.header(), &header);
+        assert_eq!(packet.payload(), &[9, 8, 7, 6]);
+
+        // "decrypt" by dropping the last two bytes as a tag
+        let plaintext = packet
+            .decrypt_in_place(|nonce, payload| {
+                assert_eq!(nonce, header.nonce());
+                payload[0] = 1;
+                Ok::<_, SerializeError>(payload.len() - 2)
+            })
+            .unwrap();
+        assert_eq!(plaintext, &[1, 8]);
+    }
+
+    #[test]
+    fn reject_packet_without_length_prefix() {
+        let mut buf = [0u8; 64];
+        let header = PackedHeader::new(MessageType::HandshakeRequest, 7, 3, 5, 4);
+        write_command(&header, &EncodedCommand::new(&[]), &mut buf).unwrap();
+
+        for size in [PackedHeader::SIZE, PackedHeader::SIZE + 1] {
+            assert!(matches!(
+                PacketView::parse(&mut buf[..size]),
+                Err(SerializeError::NotEnough)
+            ));
+        }
+        let packet = PacketView::parse(&mut buf[..PacketView::PAYLOAD_OFFSET]).unwrap();
+        assert!(packet.payload().is_empty());
+    }
+}
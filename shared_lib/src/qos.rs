@@ -0,0 +1,105 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::command::Payload;
+use crate::error::SerializeError;
+use crate::serialize::parse_payload_body;
+
+/// Delivery guarantee of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Qos {
+    /// Fire-and-forget: the message is sent once and the server does not acknowledge it.
+    AtMostOnce,
+    /// The message is retransmitted until it is acknowledged, it might be delivered twice.
+    AtLeastOnce,
+    /// The message is retransmitted until it is acknowledged, the server drops duplicates
+    /// by the message id, even if they are received in another session.
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = SerializeError;
+
+    fn try_from(value: u8) -> Result<Self, SerializeError> {
+        match value {
+            0 => Ok(Self::AtMostOnce),
+            1 => Ok(Self::AtLeastOnce),
+            2 => Ok(Self::ExactlyOnce),
+            _ => Err(SerializeError::UnknownQos),
+        }
+    }
+}
+
+impl From<Qos> for u8 {
+    fn from(val: Qos) -> Self {
+        match val {
+            Qos::AtMostOnce => 0,
+            Qos::AtLeastOnce => 1,
+            Qos::ExactlyOnce => 2,
+        }
+    }
+}
+
+/// Payload with a delivery guarantee.
+///
+/// Encoded as `[qos u8][message_id u32][kind u8][body]`, where `kind` and `body` are
+/// the wrapped payload. Message ids are chosen by the device and must not repeat,
+/// the session sequence cannot be used as it restarts with every handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QosMessage<'a> {
+    pub qos: Qos,
+    pub message_id: u32,
+    pub kind: u8,
+    pub body: &'a [u8],
+}
+
+impl<'a> QosMessage<'a> {
+    pub const HEADER_SIZE: usize = 6;
+
+    /// Serialize the message into the buffer. Returns the number of bytes written.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = Self::HEADER_SIZE + self.body.len();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        Self::write_header(self.qos, self.message_id, buf)?;
+        buf[5] = self.kind;
+        buf[Self::HEADER_SIZE..size].copy_from_slice(self.body);
+
+        Ok(size)
+    }
+
+    /// Parse a message, the body is borrowed from the buffer.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(SerializeError::NotEnough);
+        }
+
+        Ok(QosMessage {
+            qos: Qos::try_from(buf[0])?,
+            message_id: NetworkEndian::read_u32(&buf[1..5]),
+            kind: buf[5],
+            body: &buf[Self::HEADER_SIZE..],
+        })
+    }
+
+    /// Parse the wrapped payload.
+    pub fn payload(&self) -> Result<Payload<'a>, SerializeError> {
+        parse_payload_body(self.kind, self.body)
+    }
+
+    /// Write `[qos][message_id]`, the wrapped payload follows it.
+    pub(crate) fn write_header(
+        qos: Qos,
+        message_id: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, SerializeError> {
+        if buf.len() < 5 {
+            return Err(SerializeError::TooBig);
+        }
+
+        buf[0] = qos.into();
+        NetworkEndian::write_u32(&mut buf[1..5], message_id);
+        Ok(5)
+    }
+}
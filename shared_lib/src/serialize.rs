@@ -1,109 +1,322 @@
+use crate::close::CloseReason;
 use crate::command::EncodedCommand;
-use crate::command::Information;
+use crate::command::Payload;
+use crate::command::COMMAND_SIZE;
 use crate::error::SerializeError;
+use crate::firmware::{FirmwareInfo, FirmwareQuery, FirmwareRequest};
+use crate::fragment::Fragment;
 use crate::network::PackedHeader;
+use crate::qos::{Qos, QosMessage};
+use crate::telemetry::{self, Reading, Telemetry};
+use crate::transfer::{Block, BlockAck, UploadQuery};
 use byteorder::ByteOrder;
 use byteorder::NetworkEndian;
-use libc_print::libc_eprintln as eprintln;
-use musli::alloc::{ArrayBuffer, Slice};
-use musli::{context, packed::Encoding};
 
 const BUF_SIZE: usize = size_of::<u16>();
 
-const OPTIONS: musli::Options = musli::options::new().fixed().native_byte_order().build();
-const ENCODING: Encoding<OPTIONS> = Encoding::new().with_options();
-
 /// Make a new message and serialize it into the buffer.
-/// Buffer must be enough to fit at least header_size + message_size + payload_size.
-/// The payload_size is the size of the payload in bytes. The function returns the number of bytes written to the buffer.
-/// If the buffer is too small, function panic. Buffer recommended size is 1400.
+/// Buffer must be enough to fit at least header_size + length_size + command_size.
+/// The function returns the number of bytes written to the buffer.
+/// If the buffer is too small, function returns an error. Buffer recommended size is [crate::command::PACKET_SIZE].
 pub fn write_command(
     header: &PackedHeader,
     command: &EncodedCommand,
     buf: &mut [u8],
 ) -> Result<usize, SerializeError> {
+    if buf.len() < PackedHeader::SIZE {
+        return Err(SerializeError::TooBig);
+    }
     // serialize header
     header.serialize_info(&mut buf[0..PackedHeader::SIZE])?;
     // serialize payload
     let payload_size = write(command, &mut buf[PackedHeader::SIZE..])?;
     // return size of header + payload
-    Ok(PackedHeader::SIZE + usize::from(payload_size))
+    Ok(PackedHeader::SIZE + payload_size)
 }
 
-/// Parse a command from the buffer. Buffer must start with u64 representing the payload size.
-pub fn parse_command(buf: &[u8]) -> Result<EncodedCommand, SerializeError> {
-    let payload_size: u16 = NetworkEndian::read_u16(buf);
-    if buf.len() >= usize::from(payload_size) + BUF_SIZE {
-        // parse payload
-        let mut alloc_buf = ArrayBuffer::<256>::with_size();
-        let alloc = Slice::new(&mut alloc_buf);
-        let cx = context::new_in(&alloc);
-
-        ENCODING
-            .from_slice_with(&cx, &buf[BUF_SIZE..])
-            .map_err(|err| {
-                eprintln!("Error: {}", err);
-                SerializeError::NotParsed
-            })
-    } else {
-        Err(SerializeError::NotEnough)
+/// Serialize the header and the command length prefix in front of a command which is
+/// already placed in the buffer at [crate::packet::PacketView::PAYLOAD_OFFSET].
+/// Used to frame messages encrypted in place. Returns the total packet size.
+pub fn write_command_header(
+    header: &PackedHeader,
+    command_size: usize,
+    buf: &mut [u8],
+) -> Result<usize, SerializeError> {
+    let packet_size = PackedHeader::SIZE + BUF_SIZE + command_size;
+    if command_size > COMMAND_SIZE || buf.len() < packet_size {
+        return Err(SerializeError::TooBig);
     }
+
+    header.serialize_info(&mut buf[0..PackedHeader::SIZE])?;
+    // size fits into u16 as it is limited by COMMAND_SIZE
+    NetworkEndian::write_u16(
+        &mut buf[PackedHeader::SIZE..PackedHeader::SIZE + BUF_SIZE],
+        command_size as u16,
+    );
+    Ok(packet_size)
 }
 
-pub fn parse_non_encrypted(buf: &[u8]) -> Result<Information, SerializeError> {
-    let payload_size: u16 = NetworkEndian::read_u16(buf);
-    if buf.len() >= usize::from(payload_size) + BUF_SIZE {
-        // parse payload
-        let mut alloc_buf = ArrayBuffer::<256>::with_size();
-        let alloc = Slice::new(&mut alloc_buf);
-        let cx = context::new_in(&alloc);
-
-        ENCODING
-            .from_slice_with(&cx, &buf[BUF_SIZE..])
-            .map_err(|err| {
-                eprintln!("Error: {}", err);
-                SerializeError::NotParsed
-            })
+/// Parse a command from the buffer. Buffer must start with u16 representing the payload size.
+/// The returned command borrows the payload from the buffer.
+pub fn parse_command(buf: &[u8]) -> Result<EncodedCommand<'_>, SerializeError> {
+    if buf.len() < BUF_SIZE {
+        return Err(SerializeError::NotEnough);
+    }
+
+    let payload_size = usize::from(NetworkEndian::read_u16(buf));
+    if payload_size > COMMAND_SIZE {
+        Err(SerializeError::TooBig)
+    } else if buf.len() >= payload_size + BUF_SIZE {
+        Ok(EncodedCommand::new(&buf[BUF_SIZE..BUF_SIZE + payload_size]))
     } else {
         Err(SerializeError::NotEnough)
     }
 }
 
-pub fn write_non_encrypted(
-    informatiin: &Information,
-    buf: &mut [u8],
-) -> Result<u16, SerializeError> {
-    let mut alloc_buf = ArrayBuffer::<256>::with_size();
-    let alloc = Slice::new(&mut alloc_buf);
-    let cx = context::new_in(&alloc);
-
-    match ENCODING.to_slice_with(&cx, &mut buf[BUF_SIZE..], informatiin) {
-        Ok(w) => {
-            NetworkEndian::write_u16(&mut buf[0..BUF_SIZE], w.try_into().unwrap());
-            Ok(w + BUF_SIZE).map(|w| w.try_into().unwrap())
+/// Serialize the payload kind and body into the buffer. Returns the number of bytes written.
+pub fn write_payload(payload: &Payload, buf: &mut [u8]) -> Result<usize, SerializeError> {
+    let Some((kind, body)) = buf.split_first_mut() else {
+        return Err(SerializeError::TooBig);
+    };
+
+    match payload {
+        Payload::Telemetry(telemetry) => {
+            let data = telemetry.as_bytes();
+            if body.len() < Telemetry::HEADER_SIZE + data.len() {
+                return Err(SerializeError::TooBig);
+            }
+            *kind = Payload::TELEMETRY;
+            body[0] = telemetry::SCHEMA_VERSION;
+            body[Telemetry::HEADER_SIZE..Telemetry::HEADER_SIZE + data.len()].copy_from_slice(data);
+            Ok(1 + Telemetry::HEADER_SIZE + data.len())
+        }
+        Payload::Application(data) => {
+            if body.len() < data.len() {
+                return Err(SerializeError::TooBig);
+            }
+            *kind = Payload::APPLICATION;
+            body[..data.len()].copy_from_slice(data);
+            Ok(1 + data.len())
+        }
+        Payload::Fragment(fragment) => {
+            *kind = Payload::FRAGMENT;
+            Ok(1 + fragment.serialize(body)?)
+        }
+        Payload::Block(block) => {
+            *kind = Payload::BLOCK;
+            Ok(1 + block.serialize(body)?)
         }
-        Err(error) => {
-            // report error
-            eprintln!("Error: {}", error);
-            Err(SerializeError::TooBig)
+        Payload::UploadQuery(query) => {
+            *kind = Payload::UPLOAD_QUERY;
+            Ok(1 + query.serialize(body)?)
+        }
+        Payload::BlockAck(ack) => {
+            *kind = Payload::BLOCK_ACK;
+            Ok(1 + ack.serialize(body)?)
+        }
+        Payload::FirmwareQuery(query) => {
+            *kind = Payload::FIRMWARE_QUERY;
+            Ok(1 + query.serialize(body)?)
+        }
+        Payload::FirmwareInfo(info) => {
+            *kind = Payload::FIRMWARE_INFO;
+            Ok(1 + info.serialize(body)?)
+        }
+        Payload::FirmwareRequest(request) => {
+            *kind = Payload::FIRMWARE_REQUEST;
+            Ok(1 + request.serialize(body)?)
+        }
+        Payload::Qos(message) => {
+            *kind = Payload::QOS;
+            Ok(1 + message.serialize(body)?)
+        }
+        Payload::Ping(token) => {
+            *kind = Payload::PING;
+            Ok(1 + write_token(*token, body)?)
+        }
+        Payload::Pong(token) => {
+            *kind = Payload::PONG;
+            Ok(1 + write_token(*token, body)?)
+        }
+        Payload::Close(reason) => {
+            let Some(code) = body.first_mut() else {
+                return Err(SerializeError::TooBig);
+            };
+            *kind = Payload::CLOSE;
+            *code = (*reason).into();
+            Ok(2)
         }
     }
 }
 
-fn write(payload: &EncodedCommand, buf: &mut [u8]) -> Result<u16, SerializeError> {
-    let mut alloc_buf = ArrayBuffer::<256>::with_size();
-    let alloc = Slice::new(&mut alloc_buf);
-    let cx = context::new_in(&alloc);
+fn write_token(token: u32, buf: &mut [u8]) -> Result<usize, SerializeError> {
+    if buf.len() < size_of::<u32>() {
+        return Err(SerializeError::TooBig);
+    }
+    NetworkEndian::write_u32(&mut buf[..size_of::<u32>()], token);
+    Ok(size_of::<u32>())
+}
 
-    match ENCODING.to_slice_with(&cx, &mut buf[BUF_SIZE..], payload) {
-        Ok(w) => {
-            NetworkEndian::write_u16(&mut buf[0..BUF_SIZE], w.try_into().unwrap());
-            Ok(w + BUF_SIZE).map(|w| w.try_into().unwrap())
-        }
-        Err(error) => {
-            // report error
-            eprintln!("Error: {}", error);
-            Err(SerializeError::TooBig)
-        }
+fn parse_token(buf: &[u8]) -> Result<u32, SerializeError> {
+    if buf.len() < size_of::<u32>() {
+        return Err(SerializeError::NotEnough);
+    }
+    Ok(NetworkEndian::read_u32(buf))
+}
+
+/// Serialize the payload wrapped with the delivery guarantee. Returns the number of bytes written.
+pub fn write_qos_payload(
+    qos: Qos,
+    message_id: u32,
+    payload: &Payload,
+    buf: &mut [u8],
+) -> Result<usize, SerializeError> {
+    let Some((kind, body)) = buf.split_first_mut() else {
+        return Err(SerializeError::TooBig);
+    };
+    *kind = Payload::QOS;
+
+    // the wrapped payload is written with its kind right after the header
+    let header_size = QosMessage::write_header(qos, message_id, body)?;
+    Ok(1 + header_size + write_payload(payload, &mut body[header_size..])?)
+}
+
+/// Serialize readings as a telemetry payload. Returns the number of bytes written.
+pub fn write_readings_payload(
+    readings: &[Reading],
+    buf: &mut [u8],
+) -> Result<usize, SerializeError> {
+    let Some((kind, body)) = buf.split_first_mut() else {
+        return Err(SerializeError::TooBig);
+    };
+    *kind = Payload::TELEMETRY;
+    Ok(1 + telemetry::write_readings(readings, body)?)
+}
+
+/// Parse a decrypted message body. Application data is borrowed from the buffer.
+pub fn parse_payload(buf: &[u8]) -> Result<Payload<'_>, SerializeError> {
+    let Some((kind, body)) = buf.split_first() else {
+        return Err(SerializeError::BufferEmpty);
+    };
+
+    parse_payload_body(*kind, body)
+}
+
+/// Parse a payload body of the known kind, e.g. a reassembled message.
+pub fn parse_payload_body(kind: u8, body: &[u8]) -> Result<Payload<'_>, SerializeError> {
+    match kind {
+        Payload::TELEMETRY => Telemetry::parse(body).map(Payload::Telemetry),
+        Payload::APPLICATION => Ok(Payload::Application(body)),
+        Payload::FRAGMENT => Fragment::parse(body).map(Payload::Fragment),
+        Payload::BLOCK => Block::parse(body).map(Payload::Block),
+        Payload::UPLOAD_QUERY => UploadQuery::parse(body).map(Payload::UploadQuery),
+        Payload::BLOCK_ACK => BlockAck::parse(body).map(Payload::BlockAck),
+        Payload::FIRMWARE_QUERY => FirmwareQuery::parse(body).map(Payload::FirmwareQuery),
+        Payload::FIRMWARE_INFO => FirmwareInfo::parse(body).map(Payload::FirmwareInfo),
+        Payload::FIRMWARE_REQUEST => FirmwareRequest::parse(body).map(Payload::FirmwareRequest),
+        Payload::QOS => QosMessage::parse(body).map(Payload::Qos),
+        Payload::PING => parse_token(body).map(Payload::Ping),
+        Payload::PONG => parse_token(body).map(Payload::Pong),
+        Payload::CLOSE => body
+            .first()
+            .map(|code| Payload::Close(CloseReason::from(*code)))
+            .ok_or(SerializeError::NotEnough),
+        _ => Err(SerializeError::UnknownPayload),
+    }
+}
+
+fn write(command: &EncodedCommand, buf: &mut [u8]) -> Result<usize, SerializeError> {
+    let size = command.size();
+    if size > COMMAND_SIZE || buf.len() < command.encoded_size() {
+        return Err(SerializeError::TooBig);
+    }
+
+    // size fits into u16 as it is limited by COMMAND_SIZE
+    NetworkEndian::write_u16(&mut buf[0..BUF_SIZE], size as u16);
+    buf[BUF_SIZE..BUF_SIZE + size].copy_from_slice(command.buf);
+    Ok(command.encoded_size())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::MessageType;
+    use crate::telemetry::Information;
+
+    #[test]
+    fn write_parse_command() {
+        let mut buf = [0u8; 64];
+        let payload = [1u8, 2, 3, 4];
+
+        let header = PackedHeader::new(MessageType::EncryptedMessage, 42, 1, 2, 1);
+        let written = write_command(&header, &EncodedCommand::new(&payload), &mut buf)
+            .expect("Failed to write command");
+
+        // wire size tracks the content: header + u16 length + payload
+        assert_eq!(written, PackedHeader::SIZE + BUF_SIZE + payload.len());
+
+        let command = parse_command(&buf[PackedHeader::SIZE..written]).unwrap();
+        assert_eq!(command.buf, &payload);
+    }
+
+    #[test]
+    fn write_parse_payload() {
+        let mut buf = [0u8; 64];
+
+        let reading = Reading::new(Information::AirPressure(1013.25));
+        let size = write_readings_payload(&[reading], &mut buf).unwrap();
+        let Payload::Telemetry(telemetry) = parse_payload(&buf[..size]).unwrap() else {
+            panic!("Expected telemetry payload");
+        };
+        assert_eq!(telemetry.readings().next().unwrap().unwrap(), reading);
+
+        let application = Payload::Application(b"custom");
+        let size = write_payload(&application, &mut buf).unwrap();
+        assert_eq!(parse_payload(&buf[..size]).unwrap(), application);
+
+        let block = Payload::Block(Block {
+            name: "device.log",
+            index: 3,
+            block_size: 8,
+            last: true,
+            data: b"tail",
+        });
+        let size = write_payload(&block, &mut buf).unwrap();
+        assert_eq!(parse_payload(&buf[..size]).unwrap(), block);
+
+        let request = Payload::FirmwareRequest(FirmwareRequest {
+            name: "sensor",
+            version: 2,
+            index: 5,
+            block_size: 512,
+        });
+        let size = write_payload(&request, &mut buf).unwrap();
+        assert_eq!(parse_payload(&buf[..size]).unwrap(), request);
+
+        let size = write_qos_payload(Qos::ExactlyOnce, 7, &application, &mut buf).unwrap();
+        let Payload::Qos(message) = parse_payload(&buf[..size]).unwrap() else {
+            panic!("Expected QoS payload");
+        };
+        assert_eq!((message.qos, message.message_id), (Qos::ExactlyOnce, 7));
+        assert_eq!(message.payload().unwrap(), application);
+        let mut copy = [0u8; 64];
+        let copy_size = write_payload(&Payload::Qos(message), &mut copy).unwrap();
+        assert_eq!(&copy[..copy_size], &buf[..size]);
+
+        let size = write_payload(&Payload::Ping(0xDEAD_BEEF), &mut buf).unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(
+            parse_payload(&buf[..size]).unwrap(),
+            Payload::Ping(0xDEAD_BEEF)
+        );
+
+        let close = Payload::Close(CloseReason::Shutdown);
+        let size = write_payload(&close, &mut buf).unwrap();
+        assert_eq!(&buf[..size], &[Payload::CLOSE, 1]);
+        assert_eq!(parse_payload(&buf[..size]).unwrap(), close);
+        assert_eq!(
+            parse_payload(&[Payload::CLOSE, 42]).unwrap(),
+            Payload::Close(CloseReason::Other(42))
+        );
     }
 }
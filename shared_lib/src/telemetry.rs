@@ -0,0 +1,339 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::error::SerializeError;
+
+/// Telemetry schema version.
+/// Changes only when the envelope or the reading header changes, new sensor kinds
+/// are added without a version bump because decoders skip unknown kinds.
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// Sensor value with its unit.
+///
+/// Wire kind codes:
+/// 1 - Temperature, 2 - AirPressure, 3 - Humidity, 4 - Voltage, 5 - Current,
+/// 6 - Illuminance, 7 - Co2, 8 - Battery, 9 - Counter, 10 - State, 11 - Location
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Information {
+    /// Degrees Celsius.
+    Temperature(f32),
+    /// Hectopascal.
+    AirPressure(f32),
+    /// Relative humidity, percent.
+    Humidity(f32),
+    /// Volts.
+    Voltage(f32),
+    /// Amperes.
+    Current(f32),
+    /// Lux.
+    Illuminance(f32),
+    /// CO2 concentration, ppm.
+    Co2(f32),
+    /// Battery charge, percent.
+    Battery(u8),
+    /// Monotonic counter (pulses, events).
+    Counter(u32),
+    /// Binary state (switch, door, motion).
+    State(bool),
+    /// WGS84 position in degrees.
+    Location { latitude: f32, longitude: f32 },
+}
+
+/// Reading quality flags. Empty set means the value is good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quality(u8);
+
+impl Quality {
+    pub const GOOD: Quality = Quality(0);
+    /// Value was not refreshed during the last sampling period.
+    pub const STALE: Quality = Quality(1);
+    /// Value is outside the sensor range and was clamped.
+    pub const OUT_OF_RANGE: Quality = Quality(1 << 1);
+    /// Value is interpolated or estimated.
+    pub const ESTIMATED: Quality = Quality(1 << 2);
+    /// Sensor reported a fault, value must not be trusted.
+    pub const SENSOR_FAULT: Quality = Quality(1 << 3);
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Quality(bits)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: Quality) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Quality {
+    type Output = Quality;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Quality(self.0 | rhs.0)
+    }
+}
+
+/// Single sensor reading.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    /// Sensor index on the device.
+    pub sensor_id: u16,
+    /// Seconds since Unix epoch, 0 if the device has no clock.
+    pub timestamp: u32,
+    pub quality: Quality,
+    pub value: Information,
+}
+
+impl Reading {
+    /// Reading header size: kind 1 byte, length 1 byte, sensor id 2 bytes,
+    /// timestamp 4 bytes, quality 1 byte.
+    pub const HEADER_SIZE: usize = 9;
+    /// The biggest reading on the wire.
+    pub const MAX_SIZE: usize = Self::HEADER_SIZE + 8;
+
+    /// Reading from the default sensor without timestamp.
+    pub fn new(value: Information) -> Self {
+        Reading {
+            sensor_id: 0,
+            timestamp: 0,
+            quality: Quality::GOOD,
+            value,
+        }
+    }
+
+    /// Size of the reading on the wire.
+    pub fn encoded_size(&self) -> usize {
+        Self::HEADER_SIZE + self.value.value_size()
+    }
+
+    /// Serialize the reading into the buffer. Returns the number of bytes written.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = self.encoded_size();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        buf[0] = self.value.kind();
+        // length covers everything after the length byte
+        buf[1] = (size - 2) as u8;
+        NetworkEndian::write_u16(&mut buf[2..4], self.sensor_id);
+        NetworkEndian::write_u32(&mut buf[4..8], self.timestamp);
+        buf[8] = self.quality.bits();
+        self.value.write_value(&mut buf[Self::HEADER_SIZE..size]);
+
+        Ok(size)
+    }
+}
+
+impl Information {
+    fn kind(&self) -> u8 {
+        match self {
+            Information::Temperature(_) => 1,
+            Information::AirPressure(_) => 2,
+            Information::Humidity(_) => 3,
+            Information::Voltage(_) => 4,
+            Information::Current(_) => 5,
+            Information::Illuminance(_) => 6,
+            Information::Co2(_) => 7,
+            Information::Battery(_) => 8,
+            Information::Counter(_) => 9,
+            Information::State(_) => 10,
+            Information::Location { .. } => 11,
+        }
+    }
+
+    fn value_size(&self) -> usize {
+        match self {
+            Information::Battery(_) | Information::State(_) => 1,
+            Information::Location { .. } => 8,
+            _ => 4,
+        }
+    }
+
+    fn write_value(&self, buf: &mut [u8]) {
+        match *self {
+            Information::Temperature(value)
+            | Information::AirPressure(value)
+            | Information::Humidity(value)
+            | Information::Voltage(value)
+            | Information::Current(value)
+            | Information::Illuminance(value)
+            | Information::Co2(value) => NetworkEndian::write_f32(buf, value),
+            Information::Battery(value) => buf[0] = value,
+            Information::Counter(value) => NetworkEndian::write_u32(buf, value),
+            Information::State(value) => buf[0] = u8::from(value),
+            Information::Location {
+                latitude,
+                longitude,
+            } => {
+                NetworkEndian::write_f32(&mut buf[0..4], latitude);
+                NetworkEndian::write_f32(&mut buf[4..8], longitude);
+            }
+        }
+    }
+
+    /// Decode a value of the known kind. Returns `None` for unknown kinds.
+    /// Extra bytes after the value are ignored, newer peers may append fields.
+    fn read_value(kind: u8, buf: &[u8]) -> Option<Result<Self, SerializeError>> {
+        let size = match kind {
+            8 | 10 => 1,
+            11 => 8,
+            1..=9 => 4,
+            _ => return None,
+        };
+        if buf.len() < size {
+            return Some(Err(SerializeError::NotParsed));
+        }
+
+        let value = match kind {
+            1 => Information::Temperature(NetworkEndian::read_f32(buf)),
+            2 => Information::AirPressure(NetworkEndian::read_f32(buf)),
+            3 => Information::Humidity(NetworkEndian::read_f32(buf)),
+            4 => Information::Voltage(NetworkEndian::read_f32(buf)),
+            5 => Information::Current(NetworkEndian::read_f32(buf)),
+            6 => Information::Illuminance(NetworkEndian::read_f32(buf)),
+            7 => Information::Co2(NetworkEndian::read_f32(buf)),
+            8 => Information::Battery(buf[0]),
+            9 => Information::Counter(NetworkEndian::read_u32(buf)),
+            10 => Information::State(buf[0] != 0),
+            _ => Information::Location {
+                latitude: NetworkEndian::read_f32(&buf[0..4]),
+                longitude: NetworkEndian::read_f32(&buf[4..8]),
+            },
+        };
+        Some(Ok(value))
+    }
+}
+
+/// Encoded telemetry: schema version followed by readings.
+/// Each reading is `[kind u8][length u8][sensor_id u16][timestamp u32][quality u8][value]`,
+/// the length allows to skip kinds unknown to this peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Telemetry<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Telemetry<'a> {
+    /// Envelope size before the readings.
+    pub const HEADER_SIZE: usize = 1;
+
+    /// Validate the schema version, readings are decoded lazily.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        match buf.first() {
+            Some(&SCHEMA_VERSION) => Ok(Telemetry {
+                buf: &buf[Self::HEADER_SIZE..],
+            }),
+            Some(_) => Err(SerializeError::UnsupportedVersion),
+            None => Err(SerializeError::BufferEmpty),
+        }
+    }
+
+    /// Iterate over readings, skipping kinds unknown to this peer.
+    pub fn readings(&self) -> Readings<'a> {
+        Readings { buf: self.buf }
+    }
+
+    /// Encoded readings without the envelope.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+/// Iterator over encoded readings.
+pub struct Readings<'a> {
+    buf: &'a [u8],
+}
+
+impl Iterator for Readings<'_> {
+    type Item = Result<Reading, SerializeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.buf.is_empty() {
+                return None;
+            }
+
+            let size = match self.buf.get(1) {
+                Some(&length) if usize::from(length) + 2 <= self.buf.len() => {
+                    usize::from(length) + 2
+                }
+                _ => {
+                    // truncated reading, stop here
+                    self.buf = &[];
+                    return Some(Err(SerializeError::NotEnough));
+                }
+            };
+            let (reading, rest) = self.buf.split_at(size);
+            self.buf = rest;
+
+            if reading.len() < Reading::HEADER_SIZE {
+                return Some(Err(SerializeError::NotParsed));
+            }
+
+            let Some(value) = Information::read_value(reading[0], &reading[Reading::HEADER_SIZE..])
+            else {
+                // unknown kind, skip it
+                continue;
+            };
+
+            return Some(value.map(|value| Reading {
+                sensor_id: NetworkEndian::read_u16(&reading[2..4]),
+                timestamp: NetworkEndian::read_u32(&reading[4..8]),
+                quality: Quality::from_bits(reading[8]),
+                value,
+            }));
+        }
+    }
+}
+
+/// Serialize readings with the telemetry envelope. Returns the number of bytes written.
+pub fn write_readings(readings: &[Reading], buf: &mut [u8]) -> Result<usize, SerializeError> {
+    let Some((version, mut body)) = buf.split_first_mut() else {
+        return Err(SerializeError::TooBig);
+    };
+    *version = SCHEMA_VERSION;
+
+    let mut size = Telemetry::HEADER_SIZE;
+    for reading in readings {
+        let written = reading.serialize(body)?;
+        body = &mut body[written..];
+        size += written;
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_unknown_readings() {
+        let readings = [
+            Reading::new(Information::Temperature(21.5)),
+            Reading {
+                sensor_id: 3,
+                timestamp: 1_700_000_000,
+                quality: Quality::STALE | Quality::ESTIMATED,
+                value: Information::Location {
+                    latitude: 52.52,
+                    longitude: 13.405,
+                },
+            },
+        ];
+
+        let mut buf = [0u8; 64];
+        let size = write_readings(&readings, &mut buf).unwrap();
+
+        // append a reading of the kind from the future with a 3 bytes value
+        buf[size..size + 12].copy_from_slice(&[200, 10, 0, 1, 0, 0, 0, 0, 0, 1, 2, 3]);
+        let size = size + 12;
+
+        let telemetry = Telemetry::parse(&buf[..size]).unwrap();
+        let mut decoded = telemetry.readings();
+        assert_eq!(decoded.next().unwrap().unwrap(), readings[0]);
+        assert_eq!(decoded.next().unwrap().unwrap(), readings[1]);
+        assert!(decoded.next().is_none());
+    }
+}
@@ -0,0 +1,173 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::error::SerializeError;
+
+/// Maximal length of a transfer name in bytes.
+pub const MAX_NAME_SIZE: usize = 64;
+
+/// Block of a named blob, similar to CoAP Block1/Block2.
+/// Sent by the device for uploads and by the server for firmware downloads.
+///
+/// Encoded as `[name_len u8][name][index u32][block_size u16][flags u8][data]`,
+/// flag bit 0 marks the last block. The block offset is `index * block_size`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block<'a> {
+    pub name: &'a str,
+    pub index: u32,
+    pub block_size: u16,
+    pub last: bool,
+    pub data: &'a [u8],
+}
+
+/// Request for the upload state, sent before the first block to resume an interrupted upload.
+///
+/// Encoded as `[name_len u8][name][block_size u16]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UploadQuery<'a> {
+    pub name: &'a str,
+    pub block_size: u16,
+}
+
+/// Server answer to [Block] and [UploadQuery]: the next block expected by the server.
+///
+/// Encoded as `[next_block u32][flags u8]`, flag bit 0 marks a completed upload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockAck {
+    pub next_block: u32,
+    pub complete: bool,
+}
+
+impl<'a> Block<'a> {
+    const FIXED_SIZE: usize = 1 + 4 + 2 + 1;
+
+    pub fn encoded_size(&self) -> usize {
+        Self::FIXED_SIZE + self.name.len() + self.data.len()
+    }
+
+    /// Byte offset of the block in the blob.
+    pub fn offset(&self) -> u64 {
+        u64::from(self.index) * u64::from(self.block_size)
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = self.encoded_size();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        let mut offset = write_name(self.name, buf)?;
+        NetworkEndian::write_u32(&mut buf[offset..offset + 4], self.index);
+        NetworkEndian::write_u16(&mut buf[offset + 4..offset + 6], self.block_size);
+        buf[offset + 6] = u8::from(self.last);
+        offset += 7;
+        buf[offset..size].copy_from_slice(self.data);
+
+        Ok(size)
+    }
+
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        let (name, offset) = read_name(buf)?;
+        if buf.len() < offset + 7 {
+            return Err(SerializeError::NotEnough);
+        }
+
+        let block = Block {
+            name,
+            index: NetworkEndian::read_u32(&buf[offset..offset + 4]),
+            block_size: NetworkEndian::read_u16(&buf[offset + 4..offset + 6]),
+            last: buf[offset + 6] & 1 != 0,
+            data: &buf[offset + 7..],
+        };
+
+        // only the last block can be shorter
+        let size = block.data.len();
+        if block.block_size == 0
+            || size > usize::from(block.block_size)
+            || (!block.last && size != usize::from(block.block_size))
+        {
+            return Err(SerializeError::NotParsed);
+        }
+        Ok(block)
+    }
+}
+
+impl<'a> UploadQuery<'a> {
+    pub fn encoded_size(&self) -> usize {
+        1 + self.name.len() + 2
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        let size = self.encoded_size();
+        if buf.len() < size {
+            return Err(SerializeError::TooBig);
+        }
+
+        let offset = write_name(self.name, buf)?;
+        NetworkEndian::write_u16(&mut buf[offset..offset + 2], self.block_size);
+        Ok(size)
+    }
+
+    pub fn parse(buf: &'a [u8]) -> Result<Self, SerializeError> {
+        let (name, offset) = read_name(buf)?;
+        if buf.len() < offset + 2 {
+            return Err(SerializeError::NotEnough);
+        }
+
+        let block_size = NetworkEndian::read_u16(&buf[offset..offset + 2]);
+        if block_size == 0 {
+            return Err(SerializeError::NotParsed);
+        }
+        Ok(UploadQuery { name, block_size })
+    }
+}
+
+impl BlockAck {
+    pub const SIZE: usize = 5;
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, SerializeError> {
+        if buf.len() < Self::SIZE {
+            return Err(SerializeError::TooBig);
+        }
+
+        NetworkEndian::write_u32(&mut buf[0..4], self.next_block);
+        buf[4] = u8::from(self.complete);
+        Ok(Self::SIZE)
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, SerializeError> {
+        if buf.len() < Self::SIZE {
+            return Err(SerializeError::NotEnough);
+        }
+
+        Ok(BlockAck {
+            next_block: NetworkEndian::read_u32(&buf[0..4]),
+            complete: buf[4] & 1 != 0,
+        })
+    }
+}
+
+/// Write a length-prefixed name. Returns the number of bytes written.
+pub(crate) fn write_name(name: &str, buf: &mut [u8]) -> Result<usize, SerializeError> {
+    if name.is_empty() || name.len() > MAX_NAME_SIZE || buf.len() < 1 + name.len() {
+        return Err(SerializeError::TooBig);
+    }
+
+    buf[0] = name.len() as u8;
+    buf[1..1 + name.len()].copy_from_slice(name.as_bytes());
+    Ok(1 + name.len())
+}
+
+/// Read a length-prefixed name. Returns the name and the number of bytes read.
+pub(crate) fn read_name(buf: &[u8]) -> Result<(&str, usize), SerializeError> {
+    let Some(&size) = buf.first() else {
+        return Err(SerializeError::NotEnough);
+    };
+
+    let size = usize::from(size);
+    if size == 0 || size > MAX_NAME_SIZE {
+        return Err(SerializeError::NotParsed);
+    }
+    let name = buf.get(1..1 + size).ok_or(SerializeError::NotEnough)?;
+    let name = core::str::from_utf8(name).map_err(|_| SerializeError::NotParsed)?;
+    Ok((name, 1 + size))
+}